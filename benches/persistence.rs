@@ -0,0 +1,85 @@
+//! Compares serial saves with group commit for a burst of concurrent
+//! RequestAddress calls, the pattern `docker compose up` produces.
+//!
+//! Run with `cargo bench --bench persistence`.
+
+use docker_ipam_plugin::ipam::IpamPlugin;
+use docker_ipam_plugin::storage::{Storage, StorageOptions};
+use docker_ipam_plugin::types::*;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const REQUESTS: u32 = 200;
+
+async fn run(commit_window: Option<Duration>) -> Duration {
+    let temp_dir = tempfile::TempDir::new().unwrap();
+    let options = StorageOptions {
+        commit_window,
+        ..Default::default()
+    };
+    let storage = Arc::new(
+        Storage::with_options(temp_dir.path().join("state.yaml"), options)
+            .await
+            .unwrap(),
+    );
+    let plugin = Arc::new(IpamPlugin::new(storage, "10.0.0.0/16".to_string()));
+
+    let pool = plugin
+        .request_pool(RequestPoolRequest {
+            pool: Some("10.99.0.0/16".to_string()),
+            sub_pool: None,
+            options: None,
+            v6: None,
+            address_space: None,
+        })
+        .await
+        .unwrap();
+
+    let start = Instant::now();
+    let mut handles = Vec::new();
+    for i in 0..REQUESTS {
+        let plugin = plugin.clone();
+        let pool_id = pool.pool_id.clone();
+        handles.push(tokio::spawn(async move {
+            let mut options = HashMap::new();
+            options.insert("container_name".to_string(), format!("service-{}", i));
+            plugin
+                .request_address(RequestAddressRequest {
+                    pool_id,
+                    address: Some(format!("10.99.{}.{}", i / 250, i % 250 + 1)),
+                    options: Some(options),
+                })
+                .await
+                .unwrap();
+        }));
+    }
+    for handle in handles {
+        handle.await.unwrap();
+    }
+    start.elapsed()
+}
+
+#[tokio::main]
+async fn main() {
+    let serial = run(None).await;
+    let grouped = run(Some(Duration::from_millis(2))).await;
+
+    let rate = |d: Duration| REQUESTS as f64 / d.as_secs_f64();
+    println!(
+        "serial save:  {:>8.1?} for {} requests ({:.0} req/s)",
+        serial,
+        REQUESTS,
+        rate(serial)
+    );
+    println!(
+        "group commit: {:>8.1?} for {} requests ({:.0} req/s)",
+        grouped,
+        REQUESTS,
+        rate(grouped)
+    );
+    println!(
+        "speedup:      {:.1}x",
+        serial.as_secs_f64() / grouped.as_secs_f64()
+    );
+}
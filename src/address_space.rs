@@ -0,0 +1,180 @@
+use anyhow::{anyhow, bail, Context, Result};
+use ipnetwork::IpNetwork;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// Address space for networks that only exist on this host
+pub const LOCAL: &str = "local";
+
+/// Address space whose pools are shared between hosts
+pub const GLOBAL: &str = "global";
+
+/// The address spaces a RequestPool call can name
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressSpace {
+    Local,
+    Global,
+}
+
+impl AddressSpace {
+    /// Parse the `AddressSpace` field of a request; Docker omits it for the
+    /// local default
+    pub fn from_request(name: Option<&str>) -> Result<Self> {
+        match name {
+            None | Some("") | Some(LOCAL) => Ok(Self::Local),
+            Some(GLOBAL) => Ok(Self::Global),
+            Some(other) => bail!(
+                "Unknown address space {:?} (expected {:?} or {:?})",
+                other,
+                LOCAL,
+                GLOBAL
+            ),
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Local => LOCAL,
+            Self::Global => GLOBAL,
+        }
+    }
+
+    /// Whether a new pool in this space may overlap an existing pool in
+    /// `other`
+    ///
+    /// Local pools belong to separate bridges on one host, so they may
+    /// overlap each other as before. Global pools are routed across hosts
+    /// and must not overlap anything.
+    pub fn allows_overlap_with(self, other: AddressSpace) -> bool {
+        self == Self::Local && other == Self::Local
+    }
+}
+
+/// A range that subnets are carved from when a request does not name one,
+/// like Docker's `default-address-pools` (`base` split into `/size` subnets)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DefaultPool {
+    pub base: IpNetwork,
+    pub size: u8,
+}
+
+impl DefaultPool {
+    pub fn new(base: IpNetwork, size: u8) -> Result<Self> {
+        let max = if base.is_ipv4() { 32 } else { 128 };
+        if size < base.prefix() || size > max {
+            bail!(
+                "Default pool size /{} must be between /{} and /{}",
+                size,
+                base.prefix(),
+                max
+            );
+        }
+        Ok(Self { base, size })
+    }
+
+    /// The subnets of this pool, in address order
+    pub fn subnets(&self) -> impl Iterator<Item = IpNetwork> + '_ {
+        let bits: u32 = if self.base.is_ipv4() { 32 } else { 128 };
+        let start = ip_to_u128(self.base.network());
+        let step = 1u128.checked_shl(bits - self.size as u32).unwrap_or(0);
+        let count = 1u128
+            .checked_shl((self.size - self.base.prefix()) as u32)
+            .unwrap_or(u128::MAX);
+        (0..count).map_while(move |i| {
+            let ip = u128_to_ip(start + i * step, self.base.is_ipv4());
+            IpNetwork::new(ip, self.size).ok()
+        })
+    }
+}
+
+/// Parses `<base>:<size>`, e.g. `10.0.0.0/8:24` or `fd00::/48:64`
+impl FromStr for DefaultPool {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (base, size) = s
+            .trim()
+            .rsplit_once(':')
+            .ok_or_else(|| anyhow!("Default pool {:?} must be <base>:<size>", s))?;
+        let base = base
+            .parse()
+            .with_context(|| format!("Invalid default pool base {:?}", base))?;
+        let size = size
+            .parse()
+            .with_context(|| format!("Invalid default pool size {:?}", size))?;
+        Self::new(base, size)
+    }
+}
+
+/// Whether two subnets share any address
+pub fn overlaps(a: &IpNetwork, b: &IpNetwork) -> bool {
+    a.contains(b.network()) || b.contains(a.network())
+}
+
+fn ip_to_u128(ip: IpAddr) -> u128 {
+    match ip {
+        IpAddr::V4(ip) => u32::from(ip) as u128,
+        IpAddr::V6(ip) => u128::from(ip),
+    }
+}
+
+fn u128_to_ip(n: u128, v4: bool) -> IpAddr {
+    if v4 {
+        IpAddr::from((n as u32).to_be_bytes())
+    } else {
+        IpAddr::from(n.to_be_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn net(s: &str) -> IpNetwork {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse_address_space() {
+        assert_eq!(
+            AddressSpace::from_request(None).unwrap(),
+            AddressSpace::Local
+        );
+        assert_eq!(
+            AddressSpace::from_request(Some("global")).unwrap(),
+            AddressSpace::Global
+        );
+        assert!(AddressSpace::from_request(Some("cluster")).is_err());
+    }
+
+    #[test]
+    fn test_default_pool_subnets() {
+        let pool: DefaultPool = "10.1.0.0/16:24".parse().unwrap();
+        let subnets: Vec<_> = pool.subnets().take(3).collect();
+        assert_eq!(
+            subnets,
+            vec![net("10.1.0.0/24"), net("10.1.1.0/24"), net("10.1.2.0/24")]
+        );
+        assert_eq!(pool.subnets().count(), 256);
+
+        let v6: DefaultPool = "fd00::/48:64".parse().unwrap();
+        assert_eq!(v6.subnets().nth(1), Some(net("fd00:0:0:1::/64")));
+
+        let whole: DefaultPool = "172.18.0.0/16:16".parse().unwrap();
+        assert_eq!(
+            whole.subnets().collect::<Vec<_>>(),
+            vec![net("172.18.0.0/16")]
+        );
+
+        assert!("10.1.0.0/16:8".parse::<DefaultPool>().is_err());
+        assert!("10.1.0.0/16".parse::<DefaultPool>().is_err());
+    }
+
+    #[test]
+    fn test_overlaps() {
+        assert!(overlaps(&net("10.0.0.0/8"), &net("10.5.0.0/16")));
+        assert!(overlaps(&net("10.5.0.0/16"), &net("10.0.0.0/8")));
+        assert!(!overlaps(&net("10.0.0.0/24"), &net("10.0.1.0/24")));
+        assert!(!overlaps(&net("10.0.0.0/8"), &net("fd00::/8")));
+    }
+}
@@ -0,0 +1,1107 @@
+use crate::address_space::AddressSpace;
+use crate::diff::StateDiff;
+use crate::events::{Event, EventBus};
+use crate::global::{AddressInUseError, OverlapError};
+use crate::ipam::{AmbiguousLeaseError, IpamPlugin, NotFoundError};
+use crate::metrics::metrics_response;
+use crate::server::{
+    bind_unix, json_response, parse_body, serve_until, SocketPermissions, DEFAULT_DRAIN_TIMEOUT,
+};
+use crate::storage::SnapshotError;
+use crate::types::{IpLease, PoolInfo};
+use crate::utilization::{ExhaustedError, Utilization};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use ipnetwork::IpNetwork;
+use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+
+/// How often an idle event stream checks that its client is still there,
+/// sending a comment to SSE clients so that proxies keep the connection
+const KEEPALIVE: Duration = Duration::from_secs(15);
+
+/// Management API for operators, served on its own socket so that nothing
+/// reaching the Docker-facing socket can use it
+///
+/// Changes go through [`IpamPlugin`] like Docker's requests do, so they take
+/// the same locks, are saved the same way and are audited with the reason
+/// given.
+pub struct AdminServer {
+    plugin: Arc<IpamPlugin>,
+    read_only: bool,
+    drain_timeout: Duration,
+    socket_permissions: SocketPermissions,
+}
+
+impl AdminServer {
+    pub fn new(plugin: Arc<IpamPlugin>) -> Self {
+        Self {
+            plugin,
+            read_only: false,
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+            socket_permissions: SocketPermissions::new(0o660),
+        }
+    }
+
+    /// Only serve `GET` requests, for monitoring tools that must not change
+    /// anything
+    pub fn read_only(mut self) -> Self {
+        self.read_only = true;
+        self
+    }
+
+    /// Set how long requests may take to finish once shutdown starts
+    pub fn with_drain_timeout(mut self, timeout: Duration) -> Self {
+        self.drain_timeout = timeout;
+        self
+    }
+
+    /// Set the mode and ownership of the socket; by default only its owner
+    /// and group can use it
+    pub fn with_socket_permissions(mut self, permissions: SocketPermissions) -> Self {
+        self.socket_permissions = permissions;
+        self
+    }
+
+    /// Start the server on a Unix socket, until `shutdown` resolves
+    ///
+    /// Stops like the plugin server: running requests get the drain timeout
+    /// to finish, then the socket is removed.
+    pub async fn serve_unix(
+        self,
+        socket_path: &str,
+        shutdown: impl Future<Output = ()>,
+    ) -> anyhow::Result<()> {
+        let listener = bind_unix(socket_path, &self.socket_permissions)?;
+        tracing::info!(
+            "{} listening on {}",
+            if self.read_only {
+                "Read-only admin API"
+            } else {
+                "Admin API"
+            },
+            socket_path
+        );
+
+        let plugin = self.plugin.clone();
+        if self.read_only {
+            serve_until(
+                listener,
+                move |req| handle_read_only_request(req, plugin.clone()),
+                shutdown,
+                self.drain_timeout,
+            )
+            .await?;
+        } else {
+            serve_until(
+                listener,
+                move |req| handle_admin_request(req, plugin.clone()),
+                shutdown,
+                self.drain_timeout,
+            )
+            .await?;
+        }
+
+        if let Err(e) = std::fs::remove_file(socket_path) {
+            tracing::warn!("Failed to remove socket {}: {}", socket_path, e);
+        }
+        Ok(())
+    }
+}
+
+/// A pool as shown by the admin API
+#[derive(Debug, Serialize)]
+pub struct PoolView {
+    pub pool_id: String,
+    pub address_space: &'static str,
+    pub subnet: IpNetwork,
+    pub gateway: Option<IpAddr>,
+    pub utilization: Utilization,
+}
+
+/// `POST /v1/reservations` body
+#[derive(Debug, Deserialize)]
+pub struct ReservationRequest {
+    pub pool_id: String,
+    /// The next free address is reserved when unset
+    #[serde(default)]
+    pub address: Option<IpAddr>,
+    #[serde(default)]
+    pub container_name: Option<String>,
+    pub reason: String,
+}
+
+/// `POST /v1/snapshots` body
+#[derive(Debug, Deserialize)]
+pub struct SnapshotRequest {
+    pub name: String,
+}
+
+/// `POST /v1/snapshots/{name}/restore` body
+#[derive(Debug, Deserialize)]
+pub struct RestoreRequest {
+    pub reason: String,
+}
+
+/// `POST /v1/leases/{ip}/move` body
+#[derive(Debug, Deserialize)]
+pub struct MoveRequest {
+    #[serde(default)]
+    pub pool_id: Option<String>,
+    pub container_name: String,
+    pub reason: String,
+}
+
+/// An admin API failure, sent as `{"error": ...}` with its status
+#[derive(Debug)]
+struct ApiError {
+    status: StatusCode,
+    message: String,
+}
+
+impl ApiError {
+    fn new(status: StatusCode, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+        }
+    }
+
+    fn not_found(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::NOT_FOUND, message)
+    }
+
+    fn bad_request(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, message)
+    }
+
+    fn into_response(self) -> Response<Body> {
+        let mut response = json_response(serde_json::json!({ "error": self.message }));
+        *response.status_mut() = self.status;
+        response
+    }
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(e: anyhow::Error) -> Self {
+        let status = if e.is::<NotFoundError>() {
+            StatusCode::NOT_FOUND
+        } else if let Some(e) = e.downcast_ref::<SnapshotError>() {
+            match e {
+                SnapshotError::InvalidName(_) => StatusCode::BAD_REQUEST,
+                SnapshotError::Exists(_) => StatusCode::CONFLICT,
+            }
+        } else if e.is::<AddressInUseError>()
+            || e.is::<OverlapError>()
+            || e.is::<ExhaustedError>()
+            || e.is::<AmbiguousLeaseError>()
+        {
+            StatusCode::CONFLICT
+        } else {
+            StatusCode::INTERNAL_SERVER_ERROR
+        };
+        Self::new(status, format!("{:#}", e))
+    }
+}
+
+/// Handle an admin API request
+pub async fn handle_admin_request(
+    req: Request<Body>,
+    plugin: Arc<IpamPlugin>,
+) -> Result<Response<Body>, Infallible> {
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    tracing::debug!("admin {} {}", method, path);
+
+    let result = route(req, &plugin).await;
+    Ok(match result {
+        Ok(response) => response,
+        Err(e) => {
+            if e.status.is_server_error() {
+                tracing::error!("Admin request {} {} failed: {}", method, path, e.message);
+            }
+            e.into_response()
+        }
+    })
+}
+
+/// Handle a request on a read-only admin socket, refusing anything that is
+/// not a `GET`
+pub async fn handle_read_only_request(
+    req: Request<Body>,
+    plugin: Arc<IpamPlugin>,
+) -> Result<Response<Body>, Infallible> {
+    if req.method() != Method::GET {
+        return Ok(ApiError::new(
+            StatusCode::FORBIDDEN,
+            format!(
+                "{} {} is not allowed on the read-only admin socket",
+                req.method(),
+                req.uri().path()
+            ),
+        )
+        .into_response());
+    }
+    handle_admin_request(req, plugin).await
+}
+
+async fn route(req: Request<Body>, plugin: &IpamPlugin) -> Result<Response<Body>, ApiError> {
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    let query = req.uri().query().map(str::to_string);
+    let query = query.as_deref();
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    match (&method, segments.as_slice()) {
+        (&Method::GET, ["metrics"]) => {
+            no_params(query)?;
+            Ok(metrics_response(plugin).await)
+        }
+        (&Method::GET, ["v1", "pools"]) => {
+            no_params(query)?;
+            let mut pools = Vec::new();
+            for (space, pool) in plugin.pools().await? {
+                pools.push(pool_view(plugin, space, pool).await?);
+            }
+            pools.sort_by(|a, b| a.pool_id.cmp(&b.pool_id));
+            Ok(json_response(pools))
+        }
+        (&Method::GET, ["v1", "pools", pool_id]) => {
+            no_params(query)?;
+            let (space, pool) = find_pool(plugin, pool_id).await?;
+            Ok(json_response(pool_view(plugin, space, pool).await?))
+        }
+        (&Method::GET, ["v1", "pools", pool_id, "leases"]) => {
+            no_params(query)?;
+            let (_, pool) = find_pool(plugin, pool_id).await?;
+            Ok(json_response(sorted(plugin.pool_leases(&pool).await?)))
+        }
+        (&Method::GET, ["v1", "leases"]) => {
+            let filter = LeaseFilter::parse(query)?;
+            let leases = plugin
+                .leases()
+                .await?
+                .into_iter()
+                .filter(|l| filter.matches(l))
+                .collect();
+            Ok(json_response(sorted(leases)))
+        }
+        (&Method::GET, ["v1", "events"]) => event_stream(&req, query, plugin.events()),
+        (&Method::POST, ["v1", "reservations"]) => {
+            no_params(query)?;
+            let body: ReservationRequest = parse_body(req).await.map_err(ApiError::bad_request)?;
+            let reason = require_reason(&body.reason)?;
+            let lease = plugin
+                .reserve_address(&body.pool_id, body.address, body.container_name, reason)
+                .await?;
+            let mut response = json_response(lease);
+            *response.status_mut() = StatusCode::CREATED;
+            Ok(response)
+        }
+        (&Method::DELETE, ["v1", "leases", ip]) => {
+            let ip = path_ip(ip)?;
+            let mut reason = String::new();
+            let mut pool_id = None;
+            for (key, value) in query_params(query)? {
+                match key.as_str() {
+                    "reason" => reason = value,
+                    "pool_id" => pool_id = Some(value),
+                    _ => {
+                        return Err(ApiError::bad_request(format!(
+                            "Unknown parameter {:?}",
+                            key
+                        )))
+                    }
+                }
+            }
+            let reason = require_reason(&reason)?;
+            Ok(json_response(
+                plugin.force_release(ip, pool_id.as_deref(), reason).await?,
+            ))
+        }
+        (&Method::POST, ["v1", "leases", ip, "move"]) => {
+            no_params(query)?;
+            let ip = path_ip(ip)?;
+            let body: MoveRequest = parse_body(req).await.map_err(ApiError::bad_request)?;
+            let reason = require_reason(&body.reason)?;
+            if body.container_name.trim().is_empty() {
+                return Err(ApiError::bad_request("container_name must not be empty"));
+            }
+            Ok(json_response(
+                plugin
+                    .move_lease(ip, body.pool_id.as_deref(), &body.container_name, reason)
+                    .await?,
+            ))
+        }
+        (&Method::GET, ["v1", "snapshots"]) => {
+            no_params(query)?;
+            Ok(json_response(plugin.storage().snapshots().await?))
+        }
+        (&Method::POST, ["v1", "snapshots"]) => {
+            no_params(query)?;
+            let body: SnapshotRequest = parse_body(req).await.map_err(ApiError::bad_request)?;
+            let snapshot = plugin.storage().create_snapshot(&body.name).await?;
+            let mut response = json_response(snapshot);
+            *response.status_mut() = StatusCode::CREATED;
+            Ok(response)
+        }
+        (&Method::GET, ["v1", "snapshots", name, "diff"]) => {
+            let mut against = None;
+            for (key, value) in query_params(query)? {
+                match key.as_str() {
+                    "against" => against = Some(value),
+                    _ => {
+                        return Err(ApiError::bad_request(format!(
+                            "Unknown parameter {:?}",
+                            key
+                        )))
+                    }
+                }
+            }
+            let storage = plugin.storage();
+            let diff = match against {
+                Some(other) => StateDiff::between(
+                    &storage.load_snapshot(name).await?,
+                    &storage.load_snapshot(&other).await?,
+                ),
+                None => storage.diff_snapshot(name).await?,
+            };
+            Ok(json_response(diff))
+        }
+        (&Method::POST, ["v1", "snapshots", name, "restore"]) => {
+            no_params(query)?;
+            let body: RestoreRequest = parse_body(req).await.map_err(ApiError::bad_request)?;
+            let reason = require_reason(&body.reason)?;
+            Ok(json_response(plugin.restore_snapshot(name, reason).await?))
+        }
+        _ => Err(ApiError::not_found(format!(
+            "No such endpoint: {} {}",
+            method, path
+        ))),
+    }
+}
+
+/// How `GET /v1/events` writes events
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum StreamFormat {
+    /// Server-sent events, with the sequence number as the event ID
+    Sse,
+    /// One JSON event per line
+    Ndjson,
+}
+
+impl StreamFormat {
+    fn content_type(self) -> &'static str {
+        match self {
+            Self::Sse => "text/event-stream",
+            Self::Ndjson => "application/x-ndjson",
+        }
+    }
+
+    fn frame(self, event: &Event) -> String {
+        let json = serde_json::to_value(event).unwrap();
+        match self {
+            Self::Sse => format!(
+                "id: {}\nevent: {}\ndata: {}\n\n",
+                event.seq,
+                json["type"].as_str().unwrap_or_default(),
+                json
+            ),
+            Self::Ndjson => format!("{}\n", json),
+        }
+    }
+}
+
+/// Stream events until the client goes away, starting after `since` (or
+/// SSE's `Last-Event-ID`) when given and with new events otherwise
+///
+/// A client that falls too far behind is disconnected; it can reconnect and
+/// resume from the last event it got.
+fn event_stream(
+    req: &Request<Body>,
+    query: Option<&str>,
+    events: &EventBus,
+) -> Result<Response<Body>, ApiError> {
+    let accepts_sse = req
+        .headers()
+        .get(hyper::header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.contains("text/event-stream"));
+    let mut format = if accepts_sse {
+        StreamFormat::Sse
+    } else {
+        StreamFormat::Ndjson
+    };
+    let parse_seq = |value: &str| {
+        value
+            .trim()
+            .parse::<u64>()
+            .map_err(|_| ApiError::bad_request(format!("Invalid sequence number {:?}", value)))
+    };
+    let mut since = match req.headers().get("Last-Event-ID") {
+        Some(id) => Some(parse_seq(id.to_str().unwrap_or_default())?),
+        None => None,
+    };
+    for (key, value) in query_params(query)? {
+        match key.as_str() {
+            "since" => since = Some(parse_seq(&value)?),
+            "format" => {
+                format = match value.as_str() {
+                    "sse" => StreamFormat::Sse,
+                    "ndjson" => StreamFormat::Ndjson,
+                    _ => return Err(ApiError::bad_request(format!("Unknown format {:?}", value))),
+                }
+            }
+            _ => {
+                return Err(ApiError::bad_request(format!(
+                    "Unknown parameter {:?}",
+                    key
+                )))
+            }
+        }
+    }
+
+    let (missed, mut rx) = match since {
+        Some(seq) => events.subscribe_after(seq).ok_or_else(|| {
+            ApiError::new(
+                StatusCode::GONE,
+                format!(
+                    "Cannot resume after event {}: the last event is {} and older ones may be gone; re-read the state instead",
+                    seq,
+                    events.last_seq()
+                ),
+            )
+        })?,
+        None => (Vec::new(), events.subscribe()),
+    };
+
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        for event in &missed {
+            if sender.send_data(format.frame(event).into()).await.is_err() {
+                return;
+            }
+        }
+        loop {
+            let frame = match tokio::time::timeout(KEEPALIVE, rx.recv()).await {
+                Ok(Ok(event)) => format.frame(&event),
+                Ok(Err(RecvError::Lagged(missed))) => {
+                    tracing::warn!("Event stream client missed {} events, closing it", missed);
+                    return;
+                }
+                Ok(Err(RecvError::Closed)) => return,
+                Err(_) if format == StreamFormat::Sse => ": keep-alive\n\n".to_string(),
+                Err(_) => {
+                    if std::future::poll_fn(|cx| sender.poll_ready(cx))
+                        .await
+                        .is_err()
+                    {
+                        return;
+                    }
+                    continue;
+                }
+            };
+            if sender.send_data(frame.into()).await.is_err() {
+                return;
+            }
+        }
+    });
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", format.content_type())
+        .header("Cache-Control", "no-cache")
+        .body(body)
+        .unwrap())
+}
+
+async fn find_pool(
+    plugin: &IpamPlugin,
+    pool_id: &str,
+) -> Result<(AddressSpace, PoolInfo), ApiError> {
+    plugin
+        .pool(pool_id)
+        .await?
+        .ok_or_else(|| ApiError::not_found(format!("Pool not found: {}", pool_id)))
+}
+
+async fn pool_view(
+    plugin: &IpamPlugin,
+    space: AddressSpace,
+    pool: PoolInfo,
+) -> Result<PoolView, ApiError> {
+    let leases = plugin.pool_leases(&pool).await?;
+    Ok(PoolView {
+        utilization: Utilization::of(&pool.subnet, &leases),
+        address_space: space.as_str(),
+        pool_id: pool.pool_id,
+        subnet: pool.subnet,
+        gateway: pool.gateway,
+    })
+}
+
+fn sorted(mut leases: Vec<IpLease>) -> Vec<IpLease> {
+    leases.sort_by_key(|l| l.ip_address);
+    leases
+}
+
+/// `GET /v1/leases` query; unset fields match everything
+#[derive(Debug, Default)]
+struct LeaseFilter {
+    ip: Option<IpAddr>,
+    /// Container name or ID
+    container: Option<String>,
+}
+
+impl LeaseFilter {
+    fn parse(query: Option<&str>) -> Result<Self, ApiError> {
+        let mut filter = Self::default();
+        for (key, value) in query_params(query)? {
+            match key.as_str() {
+                "ip" => {
+                    filter.ip =
+                        Some(value.parse().map_err(|_| {
+                            ApiError::bad_request(format!("Invalid ip {:?}", value))
+                        })?)
+                }
+                "container" => filter.container = Some(value),
+                _ => {
+                    return Err(ApiError::bad_request(format!(
+                        "Unknown parameter {:?}",
+                        key
+                    )))
+                }
+            }
+        }
+        Ok(filter)
+    }
+
+    fn matches(&self, lease: &IpLease) -> bool {
+        self.ip.is_none_or(|ip| lease.ip_address == ip)
+            && self.container.as_ref().is_none_or(|c| {
+                lease.container_name == *c || lease.container_id.as_ref() == Some(c)
+            })
+    }
+}
+
+/// Every change needs a reason, which goes into the audit log
+fn require_reason(reason: &str) -> Result<&str, ApiError> {
+    let reason = reason.trim();
+    if reason.is_empty() {
+        return Err(ApiError::bad_request("A reason is required"));
+    }
+    Ok(reason)
+}
+
+fn path_ip(segment: &str) -> Result<IpAddr, ApiError> {
+    let decoded = percent_decode(segment)?;
+    decoded
+        .parse()
+        .map_err(|_| ApiError::bad_request(format!("Invalid IP address {:?}", decoded)))
+}
+
+fn no_params(query: Option<&str>) -> Result<(), ApiError> {
+    match query_params(query)?.first() {
+        Some((key, _)) => Err(ApiError::bad_request(format!(
+            "Unknown parameter {:?}",
+            key
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Decode `a=1&b=x%20y` into pairs
+fn query_params(query: Option<&str>) -> Result<Vec<(String, String)>, ApiError> {
+    query
+        .unwrap_or_default()
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            Ok((percent_decode(key)?, percent_decode(value)?))
+        })
+        .collect()
+}
+
+fn percent_decode(s: &str) -> Result<String, ApiError> {
+    let invalid = || ApiError::bad_request(format!("Invalid query encoding {:?}", s));
+    let mut out = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        match b {
+            b'%' => {
+                let hex = [
+                    bytes.next().ok_or_else(invalid)?,
+                    bytes.next().ok_or_else(invalid)?,
+                ];
+                let hex = std::str::from_utf8(&hex).map_err(|_| invalid())?;
+                out.push(u8::from_str_radix(hex, 16).map_err(|_| invalid())?);
+            }
+            b'+' => out.push(b' '),
+            b => out.push(b),
+        }
+    }
+    String::from_utf8(out).map_err(|_| invalid())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::Storage;
+    use crate::types::{RequestAddressRequest, RequestPoolRequest};
+    use hyper::body::to_bytes;
+    use std::collections::HashMap;
+    use tempfile::TempDir;
+
+    async fn create_test_plugin() -> (Arc<IpamPlugin>, TempDir) {
+        let temp_dir = TempDir::new().unwrap();
+        let state_file = temp_dir.path().join("state.yaml");
+        let storage = Arc::new(Storage::new(&state_file).await.unwrap());
+        let plugin = Arc::new(IpamPlugin::new(storage, "10.0.0.0/24".to_string()));
+        (plugin, temp_dir)
+    }
+
+    async fn get(plugin: &Arc<IpamPlugin>, uri: &str) -> (StatusCode, serde_json::Value) {
+        send(plugin, Method::GET, uri, None).await
+    }
+
+    async fn send(
+        plugin: &Arc<IpamPlugin>,
+        method: Method,
+        uri: &str,
+        body: Option<serde_json::Value>,
+    ) -> (StatusCode, serde_json::Value) {
+        let body = body.map_or_else(Body::empty, |b| Body::from(b.to_string()));
+        let req = Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(body)
+            .unwrap();
+        let response = handle_admin_request(req, plugin.clone()).await.unwrap();
+        let status = response.status();
+        let body = to_bytes(response.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    /// A /29 pool with two containers in it
+    async fn populate(plugin: &IpamPlugin) -> String {
+        let pool_id = plugin
+            .request_pool(RequestPoolRequest {
+                pool: Some("192.168.50.0/29".to_string()),
+                sub_pool: None,
+                options: None,
+                v6: None,
+                address_space: None,
+            })
+            .await
+            .unwrap()
+            .pool_id;
+        for (name, id) in [("web", "c0ffee"), ("db", "d00d")] {
+            let options = HashMap::from([
+                ("container_name".to_string(), name.to_string()),
+                ("container_id".to_string(), id.to_string()),
+            ]);
+            plugin
+                .request_address(RequestAddressRequest {
+                    pool_id: pool_id.clone(),
+                    address: None,
+                    options: Some(options),
+                })
+                .await
+                .unwrap();
+        }
+        pool_id
+    }
+
+    #[tokio::test]
+    async fn test_pools_with_utilization() {
+        let (plugin, _temp) = create_test_plugin().await;
+        let pool_id = populate(&plugin).await;
+
+        let (status, pools) = get(&plugin, "/v1/pools").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(pools.as_array().unwrap().len(), 1);
+        assert_eq!(pools[0]["pool_id"], pool_id.as_str());
+        assert_eq!(pools[0]["address_space"], "local");
+        assert_eq!(pools[0]["subnet"], "192.168.50.0/29");
+
+        let (status, pool) = get(&plugin, &format!("/v1/pools/{}", pool_id)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(pool["utilization"]["total"], 6);
+        assert_eq!(pool["utilization"]["used"], 2);
+        assert_eq!(pool["utilization"]["free"], 4);
+
+        let (status, body) = get(&plugin, "/v1/pools/pool-missing").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert!(body["error"].as_str().unwrap().contains("pool-missing"));
+    }
+
+    #[tokio::test]
+    async fn test_lease_queries() {
+        let (plugin, _temp) = create_test_plugin().await;
+        let pool_id = populate(&plugin).await;
+
+        let (_, leases) = get(&plugin, &format!("/v1/pools/{}/leases", pool_id)).await;
+        let names: Vec<_> = leases
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|l| l["container_name"].as_str().unwrap())
+            .collect();
+        assert_eq!(names, vec!["web", "db"]);
+
+        let (_, by_name) = get(&plugin, "/v1/leases?container=db").await;
+        assert_eq!(by_name[0]["ip_address"], "192.168.50.2");
+        let (_, by_id) = get(&plugin, "/v1/leases?container=c0ffee").await;
+        assert_eq!(by_id[0]["container_name"], "web");
+        let (_, by_ip) = get(&plugin, "/v1/leases?ip=192.168.50.2&container=web").await;
+        assert!(by_ip.as_array().unwrap().is_empty());
+
+        let (status, _) = get(&plugin, "/v1/leases?ip=nope").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = get(&plugin, "/v1/leases?pool=x").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_admin_routes() {
+        let (plugin, _temp) = create_test_plugin().await;
+        let (status, _) = get(&plugin, "/IpamDriver.GetCapabilities").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let req = Request::builder()
+            .method(Method::DELETE)
+            .uri("/v1/pools")
+            .body(Body::empty())
+            .unwrap();
+        let response = handle_admin_request(req, plugin).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_read_only_requests() {
+        let (plugin, _temp) = create_test_plugin().await;
+        let pool_id = populate(&plugin).await;
+        let request = |method: Method, uri: &str, body: Option<serde_json::Value>| {
+            Request::builder()
+                .method(method)
+                .uri(uri)
+                .body(body.map_or_else(Body::empty, |b| Body::from(b.to_string())))
+                .unwrap()
+        };
+
+        let req = request(Method::GET, "/v1/pools", None);
+        let response = handle_read_only_request(req, plugin.clone()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let (_, before) = get(&plugin, "/v1/leases").await;
+        let req = request(
+            Method::POST,
+            "/v1/reservations",
+            Some(serde_json::json!({ "pool_id": pool_id, "reason": "test" })),
+        );
+        let response = handle_read_only_request(req, plugin.clone()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let body = to_bytes(response.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body["error"],
+            "POST /v1/reservations is not allowed on the read-only admin socket"
+        );
+        let (_, after) = get(&plugin, "/v1/leases").await;
+        assert_eq!(before, after);
+    }
+
+    #[tokio::test]
+    async fn test_reserve_move_and_release() {
+        let (plugin, _temp) = create_test_plugin().await;
+        let pool_id = populate(&plugin).await;
+
+        let reserve = |address: &str, reason: &str| {
+            serde_json::json!({
+                "pool_id": pool_id,
+                "address": address,
+                "container_name": "gateway-vm",
+                "reason": reason,
+            })
+        };
+        let (status, lease) = send(
+            &plugin,
+            Method::POST,
+            "/v1/reservations",
+            Some(reserve("192.168.50.5", "router uplink")),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(lease["reserved"], true);
+
+        let (status, _) = send(
+            &plugin,
+            Method::POST,
+            "/v1/reservations",
+            Some(reserve("192.168.50.5", "again")),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        let (status, body) = send(
+            &plugin,
+            Method::POST,
+            "/v1/reservations",
+            Some(reserve("192.168.50.6", " ")),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body["error"].as_str().unwrap().contains("reason"));
+
+        let (status, moved) = send(
+            &plugin,
+            Method::POST,
+            "/v1/leases/192.168.50.1/move",
+            Some(serde_json::json!({"container_name": "web-v2", "reason": "blue/green"})),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(moved["container_name"], "web-v2");
+
+        let (status, released) = send(
+            &plugin,
+            Method::DELETE,
+            "/v1/leases/192.168.50.5?reason=decommissioned",
+            None,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(released["container_name"], "gateway-vm");
+
+        let (status, _) = send(
+            &plugin,
+            Method::DELETE,
+            "/v1/leases/192.168.50.5?reason=twice",
+            None,
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = send(&plugin, Method::DELETE, "/v1/leases/192.168.50.1", None).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    /// Open `GET uri` as a stream
+    async fn stream(plugin: &Arc<IpamPlugin>, uri: &str, sse: bool) -> Response<Body> {
+        let mut req = Request::builder().method(Method::GET).uri(uri);
+        if sse {
+            req = req.header("Accept", "text/event-stream");
+        }
+        handle_admin_request(req.body(Body::empty()).unwrap(), plugin.clone())
+            .await
+            .unwrap()
+    }
+
+    async fn next_frame(body: &mut Body) -> String {
+        use hyper::body::HttpBody;
+        let chunk = tokio::time::timeout(Duration::from_secs(5), body.data())
+            .await
+            .expect("no event within 5s")
+            .unwrap()
+            .unwrap();
+        String::from_utf8(chunk.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_snapshots() {
+        let (plugin, temp) = create_test_plugin().await;
+        populate(&plugin).await;
+
+        let (status, created) = send(
+            &plugin,
+            Method::POST,
+            "/v1/snapshots",
+            Some(serde_json::json!({ "name": "before-cleanup" })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(created["leases"], 2);
+        let (status, _) = send(
+            &plugin,
+            Method::POST,
+            "/v1/snapshots",
+            Some(serde_json::json!({ "name": "before-cleanup" })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        let (status, _) = send(
+            &plugin,
+            Method::POST,
+            "/v1/snapshots",
+            Some(serde_json::json!({ "name": "../state" })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (_, list) = get(&plugin, "/v1/snapshots").await;
+        assert_eq!(list[0]["name"], "before-cleanup");
+
+        let (status, _) = send(
+            &plugin,
+            Method::DELETE,
+            "/v1/leases/192.168.50.1?reason=cleanup",
+            None,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let (_, diff) = get(&plugin, "/v1/snapshots/before-cleanup/diff").await;
+        assert_eq!(diff["leases_removed"][0]["container_name"], "web");
+        let (status, _) = get(&plugin, "/v1/snapshots/missing/diff").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        // Read-only sockets can look but not restore
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri("/v1/snapshots/before-cleanup/restore")
+            .body(Body::from(r#"{"reason": "undo"}"#))
+            .unwrap();
+        let response = handle_read_only_request(req, plugin.clone()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let restore = |reason: &str| Some(serde_json::json!({ "reason": reason }));
+        let (status, _) = send(
+            &plugin,
+            Method::POST,
+            "/v1/snapshots/before-cleanup/restore",
+            restore(" "),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, restored) = send(
+            &plugin,
+            Method::POST,
+            "/v1/snapshots/before-cleanup/restore",
+            restore("undo cleanup"),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(restored["leases_added"][0]["container_name"], "web");
+        let (_, leases) = get(&plugin, "/v1/leases").await;
+        assert_eq!(leases.as_array().unwrap().len(), 2);
+
+        // Saved through the running plugin's storage
+        let on_disk = Storage::open_read_only(temp.path().join("state.yaml"))
+            .await
+            .unwrap();
+        assert_eq!(on_disk.read().await.leases.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_event_stream() {
+        let (plugin, _temp) = create_test_plugin().await;
+        // Pool created (1), web (2) and db (3) leased
+        let pool_id = populate(&plugin).await;
+
+        let response = stream(&plugin, "/v1/events?since=1", false).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["Content-Type"], "application/x-ndjson");
+        let mut body = response.into_body();
+        for (seq, name) in [(2, "web"), (3, "db")] {
+            let line = next_frame(&mut body).await;
+            assert!(line.ends_with('\n'));
+            let event: serde_json::Value = serde_json::from_str(&line).unwrap();
+            assert_eq!(event["seq"], seq);
+            assert_eq!(event["type"], "lease_created");
+            assert_eq!(event["lease"]["container_name"], name);
+        }
+
+        // Live events follow the missed ones
+        plugin
+            .reserve_address(&pool_id, None, None, "spare")
+            .await
+            .unwrap();
+        let event: serde_json::Value = serde_json::from_str(&next_frame(&mut body).await).unwrap();
+        assert_eq!(event["seq"], 4);
+        assert_eq!(event["lease"]["reserved"], true);
+
+        // SSE clients resume with Last-Event-ID
+        let req = Request::builder()
+            .method(Method::GET)
+            .uri("/v1/events")
+            .header("Accept", "text/event-stream")
+            .header("Last-Event-ID", "3")
+            .body(Body::empty())
+            .unwrap();
+        let response = handle_admin_request(req, plugin.clone()).await.unwrap();
+        assert_eq!(response.headers()["Content-Type"], "text/event-stream");
+        let frame = next_frame(&mut response.into_body()).await;
+        assert!(frame.starts_with("id: 4\nevent: lease_created\ndata: {"));
+        assert!(frame.ends_with("}\n\n"));
+
+        // Without a starting point only new events are sent
+        let mut body = stream(&plugin, "/v1/events?format=sse", false)
+            .await
+            .into_body();
+        plugin
+            .force_release("192.168.50.1".parse().unwrap(), None, "gone")
+            .await
+            .unwrap();
+        assert!(next_frame(&mut body)
+            .await
+            .starts_with("id: 5\nevent: lease_released\n"));
+
+        let response = stream(&plugin, "/v1/events?since=99", true).await;
+        assert_eq!(response.status(), StatusCode::GONE);
+        let (status, _) = get(&plugin, "/v1/events?format=xml").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = get(&plugin, "/v1/events?since=-1").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_servers_stop_on_shared_shutdown() {
+        let (plugin, temp) = create_test_plugin().await;
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let shutdown = crate::server::Shutdown::new(async {
+            let _ = stopped.await;
+        });
+        let mut serving = Vec::new();
+        let mut sockets = Vec::new();
+        for (name, server) in [
+            ("admin.sock", AdminServer::new(plugin.clone())),
+            ("monitor.sock", AdminServer::new(plugin.clone()).read_only()),
+        ] {
+            let socket = temp.path().join(name);
+            let socket_path = socket.to_str().unwrap().to_string();
+            let shutdown = shutdown.clone();
+            serving.push(tokio::spawn(async move {
+                server.serve_unix(&socket_path, shutdown.wait()).await
+            }));
+            sockets.push(socket);
+        }
+        for socket in &sockets {
+            while !socket.exists() {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        }
+
+        stop.send(()).unwrap();
+        for server in serving {
+            tokio::time::timeout(Duration::from_secs(5), server)
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+        }
+        assert!(sockets.iter().all(|s| !s.exists()));
+    }
+
+    #[test]
+    fn test_query_params() {
+        assert_eq!(
+            query_params(Some("ip=fd00%3A%3A2&container=my+app")).unwrap(),
+            vec![
+                ("ip".to_string(), "fd00::2".to_string()),
+                ("container".to_string(), "my app".to_string())
+            ]
+        );
+        assert!(query_params(Some("ip=%zz")).is_err());
+        assert!(query_params(None).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,412 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Sender;
+use std::thread::JoinHandle;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Operation {
+    RequestPool,
+    ReleasePool,
+    RequestAddress,
+    ReleaseAddress,
+    ReserveAddress,
+    ForceReleaseAddress,
+    MoveAddress,
+    RestoreSnapshot,
+}
+
+/// One line of the audit log
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEvent {
+    pub timestamp: DateTime<Utc>,
+    pub operation: Operation,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pool_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subnet: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip_address: Option<IpAddr>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub container: Option<String>,
+    /// Options passed by Docker with the request
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub options: BTreeMap<String, String>,
+    /// Why an operator made the change, for admin API calls
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    pub success: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl AuditEvent {
+    pub fn new(operation: Operation) -> Self {
+        Self {
+            timestamp: Utc::now(),
+            operation,
+            pool_id: None,
+            subnet: None,
+            ip_address: None,
+            container: None,
+            options: BTreeMap::new(),
+            reason: None,
+            success: true,
+            error: None,
+        }
+    }
+
+    pub fn options(mut self, options: Option<&HashMap<String, String>>) -> Self {
+        if let Some(options) = options {
+            self.options = options
+                .iter()
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect();
+        }
+        self
+    }
+
+    /// Record the outcome of the operation
+    pub fn result<T>(mut self, result: &Result<T>) -> Self {
+        if let Err(e) = result {
+            self.success = false;
+            self.error = Some(format!("{:#}", e));
+        }
+        self
+    }
+}
+
+/// Selects audit events; unset fields match everything
+#[derive(Debug, Default)]
+pub struct AuditFilter {
+    pub ip_address: Option<IpAddr>,
+    pub container: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+}
+
+impl AuditFilter {
+    pub fn matches(&self, event: &AuditEvent) -> bool {
+        self.ip_address
+            .is_none_or(|ip| event.ip_address == Some(ip))
+            && self
+                .container
+                .as_ref()
+                .is_none_or(|c| event.container.as_ref() == Some(c))
+            && self.since.is_none_or(|t| event.timestamp >= t)
+            && self.until.is_none_or(|t| event.timestamp <= t)
+    }
+}
+
+/// Append-only JSONL log of pool and lease operations
+///
+/// When the file would grow past `max_bytes` it is rotated to `<path>.1`,
+/// shifting older files up to `<path>.<files>`; the oldest is dropped.
+///
+/// Events are written in order by a background thread, so recording one
+/// never blocks the request being audited on file I/O. Dropping the log
+/// waits for the events already recorded to be written.
+pub struct AuditLog {
+    path: PathBuf,
+    sender: Option<Sender<Message>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+/// What the writer thread is asked to do
+enum Message {
+    Append(AuditEvent),
+    /// Answer once everything sent before has been written
+    Flush(Sender<()>),
+}
+
+impl AuditLog {
+    pub fn new(path: impl Into<PathBuf>, max_bytes: u64, files: usize) -> Self {
+        let path = path.into();
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let mut writer = Writer {
+            path: path.clone(),
+            max_bytes,
+            files,
+            file: None,
+        };
+        let writer = std::thread::Builder::new()
+            .name("audit-log".to_string())
+            .spawn(move || {
+                for message in receiver {
+                    match message {
+                        Message::Append(event) => writer.record(&event),
+                        Message::Flush(done) => {
+                            let _ = done.send(());
+                        }
+                    }
+                }
+            })
+            .expect("failed to start the audit log writer");
+        Self {
+            path,
+            sender: Some(sender),
+            writer: Some(writer),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Queue an event to be appended
+    ///
+    /// Failing to audit never fails the operation being audited; the error is
+    /// logged instead.
+    pub fn record(&self, event: &AuditEvent) {
+        self.send(Message::Append(event.clone()));
+    }
+
+    fn send(&self, message: Message) {
+        let sent = self
+            .sender
+            .as_ref()
+            .is_some_and(|s| s.send(message).is_ok());
+        if !sent {
+            tracing::error!("Audit log writer for {:?} has stopped", self.path);
+        }
+    }
+
+    /// Wait until every event recorded so far is written
+    pub fn flush(&self) {
+        let (done, written) = std::sync::mpsc::channel();
+        self.send(Message::Flush(done));
+        let _ = written.recv();
+    }
+
+    /// Events matching `filter` from the current and rotated files, oldest
+    /// first, including every event recorded so far
+    pub fn query(&self, filter: &AuditFilter) -> Result<Vec<AuditEvent>> {
+        self.flush();
+        query(&self.path, filter)
+    }
+}
+
+impl Drop for AuditLog {
+    fn drop(&mut self) {
+        // Closing the channel stops the writer once it is drained
+        self.sender.take();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+/// The file end of [`AuditLog`], owned by its writer thread
+struct Writer {
+    path: PathBuf,
+    max_bytes: u64,
+    files: usize,
+    file: Option<(File, u64)>,
+}
+
+impl Writer {
+    fn record(&mut self, event: &AuditEvent) {
+        if let Err(e) = self.append(event) {
+            tracing::error!("Failed to write audit log {:?}: {:#}", self.path, e);
+        }
+    }
+
+    fn append(&mut self, event: &AuditEvent) -> Result<()> {
+        let mut line = serde_json::to_string(event)?;
+        line.push('\n');
+
+        if self.file.is_none() {
+            self.file = Some(self.open()?);
+        }
+        let (_, size) = self.file.as_ref().expect("audit log is open");
+        if *size > 0 && size + line.len() as u64 > self.max_bytes {
+            self.file = None;
+            self.rotate()?;
+            self.file = Some(self.open()?);
+        }
+
+        let (file, size) = self.file.as_mut().expect("audit log is open");
+        file.write_all(line.as_bytes())?;
+        *size += line.len() as u64;
+        Ok(())
+    }
+
+    /// Open the current file for appending, with its size
+    fn open(&self) -> Result<(File, u64)> {
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir).context("Failed to create audit log directory")?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .context("Failed to open audit log")?;
+        let size = file.metadata()?.len();
+        Ok((file, size))
+    }
+
+    fn rotate(&self) -> Result<()> {
+        if self.files == 0 {
+            std::fs::remove_file(&self.path).context("Failed to truncate audit log")?;
+            return Ok(());
+        }
+        for n in (1..self.files).rev() {
+            let from = rotated_path(&self.path, n);
+            if from.exists() {
+                std::fs::rename(&from, rotated_path(&self.path, n + 1))
+                    .context("Failed to rotate audit log")?;
+            }
+        }
+        std::fs::rename(&self.path, rotated_path(&self.path, 1))
+            .context("Failed to rotate audit log")
+    }
+}
+
+/// Read matching events from the audit log at `path` and its rotated files,
+/// oldest first, without opening it for writing
+pub fn query(path: &Path, filter: &AuditFilter) -> Result<Vec<AuditEvent>> {
+    let mut files = Vec::new();
+    let mut n = 1;
+    while rotated_path(path, n).exists() {
+        files.push(rotated_path(path, n));
+        n += 1;
+    }
+    files.reverse();
+    files.push(path.to_path_buf());
+
+    let mut events = Vec::new();
+    for file in files {
+        let reader = match File::open(&file) {
+            Ok(f) => BufReader::new(f),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {:?}", file)),
+        };
+        for (i, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str::<AuditEvent>(&line) {
+                Ok(event) if filter.matches(&event) => events.push(event),
+                Ok(_) => {}
+                // A torn last line from a crash should not hide everything else
+                Err(e) => tracing::warn!("{:?} line {}: {}", file, i + 1, e),
+            }
+        }
+    }
+    Ok(events)
+}
+
+fn rotated_path(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn event(ip: &str, container: &str) -> AuditEvent {
+        let mut event = AuditEvent::new(Operation::RequestAddress);
+        event.ip_address = Some(ip.parse().unwrap());
+        event.container = Some(container.to_string());
+        event
+    }
+
+    #[test]
+    fn test_record_and_query() {
+        let dir = TempDir::new().unwrap();
+        let log = AuditLog::new(dir.path().join("audit.jsonl"), 1 << 20, 3);
+        log.record(&event("10.0.0.2", "web"));
+        log.record(&event("10.0.0.3", "db"));
+        log.record(
+            &AuditEvent::new(Operation::ReleaseAddress).result::<()>(&Err(anyhow::anyhow!("boom"))),
+        );
+
+        let by_ip = log
+            .query(&AuditFilter {
+                ip_address: Some("10.0.0.3".parse().unwrap()),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(by_ip.len(), 1);
+        assert_eq!(by_ip[0].container.as_deref(), Some("db"));
+
+        let all = log.query(&AuditFilter::default()).unwrap();
+        assert_eq!(all.len(), 3);
+        assert!(!all[2].success);
+        assert_eq!(all[2].error.as_deref(), Some("boom"));
+
+        let future = log
+            .query(&AuditFilter {
+                since: Some(Utc::now() + chrono::Duration::hours(1)),
+                ..Default::default()
+            })
+            .unwrap();
+        assert!(future.is_empty());
+    }
+
+    #[test]
+    fn test_drop_writes_queued_events() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("audit.jsonl");
+        let log = AuditLog::new(&path, 1 << 20, 1);
+        for i in 0..20 {
+            log.record(&event("10.0.0.2", &format!("c{}", i)));
+        }
+        drop(log);
+        let events = query(&path, &AuditFilter::default()).unwrap();
+        assert_eq!(events.len(), 20);
+        assert_eq!(events[19].container.as_deref(), Some("c19"));
+    }
+
+    #[test]
+    fn test_rotation_keeps_history_queryable() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("audit.jsonl");
+        let line_len = serde_json::to_string(&event("10.0.0.2", "c0"))
+            .unwrap()
+            .len() as u64
+            + 1;
+        // Two events per file, three files in total
+        let log = AuditLog::new(&path, line_len * 2, 2);
+        for i in 0..7 {
+            log.record(&event("10.0.0.2", &format!("c{}", i)));
+        }
+        log.flush();
+
+        assert!(rotated_path(&path, 2).exists());
+        assert!(!rotated_path(&path, 3).exists());
+        let names: Vec<_> = log
+            .query(&AuditFilter::default())
+            .unwrap()
+            .into_iter()
+            .map(|e| e.container.unwrap())
+            .collect();
+        // The oldest file was dropped; the rest is in order
+        assert_eq!(names, vec!["c2", "c3", "c4", "c5", "c6"]);
+
+        // Reopening continues the current file rather than starting over
+        drop(log);
+        let reopened = AuditLog::new(&path, line_len * 2, 2);
+        reopened.record(&event("10.0.0.2", "c7"));
+        reopened.flush();
+        assert_eq!(query(&path, &AuditFilter::default()).unwrap().len(), 6);
+        reopened.record(&event("10.0.0.2", "c8"));
+        reopened.flush();
+        assert_eq!(
+            query(&path, &AuditFilter::default()).unwrap()[0]
+                .container
+                .as_deref(),
+            Some("c4")
+        );
+    }
+}
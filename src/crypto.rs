@@ -0,0 +1,242 @@
+use anyhow::{anyhow, bail, Context, Result};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::path::Path;
+
+/// Algorithm name recorded in the envelope
+const ALGORITHM: &str = "xchacha20poly1305";
+
+/// On-disk form of an encrypted state file
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+    encryption: String,
+    /// Identifies which key sealed the file, so rotated keys can still open it
+    key_id: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+/// The state file could not be decrypted with the configured keys
+///
+/// Kept distinct from parse errors so that a missing or wrong key is never
+/// mistaken for a corrupt file and "recovered" from an older backup.
+#[derive(Debug)]
+pub struct DecryptError(pub String);
+
+impl fmt::Display for DecryptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for DecryptError {}
+
+/// A 256-bit state encryption key
+#[derive(Clone)]
+pub struct StateKey {
+    id: String,
+    cipher: XChaCha20Poly1305,
+}
+
+impl StateKey {
+    /// Load a key file holding 32 bytes, base64-encoded
+    /// (e.g. `head -c 32 /dev/urandom | base64`)
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read key file {:?}", path))?;
+        let bytes = BASE64
+            .decode(text.trim())
+            .with_context(|| format!("Key file {:?} is not valid base64", path))?;
+        Self::from_bytes(&bytes).with_context(|| format!("Invalid key in {:?}", path))
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() != 32 {
+            bail!("Expected a 32-byte key, got {} bytes", bytes.len());
+        }
+        // The id is derived from the key so it never has to be configured
+        let digest = Sha256::digest([b"docker-ipam-key-id:".as_slice(), bytes].concat());
+        let id = digest[..8].iter().map(|b| format!("{:02x}", b)).collect();
+        Ok(Self {
+            id,
+            cipher: XChaCha20Poly1305::new_from_slice(bytes)
+                .map_err(|_| anyhow!("Invalid key length"))?,
+        })
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+impl fmt::Debug for StateKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StateKey").field("id", &self.id).finish()
+    }
+}
+
+/// Keys used to encrypt the state file at rest
+///
+/// Files are always sealed with the primary key. Retired keys are kept only
+/// to open files written before a rotation; the next save re-encrypts them
+/// with the primary key. With no primary key the file is stored in plain
+/// text.
+#[derive(Debug, Clone, Default)]
+pub struct Keyring {
+    pub primary: Option<StateKey>,
+    pub retired: Vec<StateKey>,
+}
+
+impl Keyring {
+    /// Encrypt serialized state with the primary key, if there is one
+    pub fn seal(&self, plaintext: &str) -> Result<String> {
+        let Some(key) = &self.primary else {
+            return Ok(plaintext.to_string());
+        };
+
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = key
+            .cipher
+            .encrypt(&nonce, plaintext.as_bytes())
+            .map_err(|_| anyhow!("Failed to encrypt state"))?;
+
+        let envelope = Envelope {
+            encryption: ALGORITHM.to_string(),
+            key_id: key.id.clone(),
+            nonce: BASE64.encode(nonce),
+            ciphertext: BASE64.encode(ciphertext),
+        };
+        serde_yaml::to_string(&envelope).context("Failed to serialize encrypted state")
+    }
+
+    /// Decrypt a state file if it is encrypted, or pass plain text through
+    pub fn open(&self, contents: &str) -> Result<String> {
+        let Some(envelope) = parse_envelope(contents) else {
+            return Ok(contents.to_string());
+        };
+        if envelope.encryption != ALGORITHM {
+            bail!("Unsupported state encryption {:?}", envelope.encryption);
+        }
+
+        let key = self
+            .primary
+            .iter()
+            .chain(&self.retired)
+            .find(|k| k.id == envelope.key_id)
+            .ok_or_else(|| {
+                DecryptError(format!(
+                    "State file is encrypted with key {} which is not configured",
+                    envelope.key_id
+                ))
+            })?;
+        if self.primary.as_ref().map(|k| k.id.as_str()) != Some(envelope.key_id.as_str()) {
+            tracing::info!(
+                "State file is encrypted with retired key {}; it will be re-encrypted on the next save",
+                envelope.key_id
+            );
+        }
+
+        let nonce = BASE64
+            .decode(&envelope.nonce)
+            .context("Invalid nonce in encrypted state")?;
+        if nonce.len() != 24 {
+            bail!("Invalid nonce length in encrypted state");
+        }
+        let ciphertext = BASE64
+            .decode(&envelope.ciphertext)
+            .context("Invalid ciphertext in encrypted state")?;
+        let plaintext = key
+            .cipher
+            .decrypt(XNonce::from_slice(&nonce), ciphertext.as_slice())
+            .map_err(|_| {
+                DecryptError("Failed to decrypt state file: wrong key or tampered content".into())
+            })?;
+        String::from_utf8(plaintext).context("Decrypted state is not valid UTF-8")
+    }
+}
+
+/// Recognise an encrypted state file by its envelope keys
+fn parse_envelope(contents: &str) -> Option<Envelope> {
+    let value: serde_yaml::Value = serde_yaml::from_str(contents).ok()?;
+    value.get("ciphertext")?;
+    serde_yaml::from_value(value).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(byte: u8) -> StateKey {
+        StateKey::from_bytes(&[byte; 32]).unwrap()
+    }
+
+    #[test]
+    fn test_seal_and_open() {
+        let keyring = Keyring {
+            primary: Some(key(1)),
+            retired: vec![],
+        };
+        let sealed = keyring.seal("pools: {}\nleases: []\n").unwrap();
+        assert!(!sealed.contains("leases"));
+        assert_eq!(keyring.open(&sealed).unwrap(), "pools: {}\nleases: []\n");
+    }
+
+    #[test]
+    fn test_plain_text_passes_through() {
+        let keyring = Keyring::default();
+        assert_eq!(keyring.seal("a: 1\n").unwrap(), "a: 1\n");
+        assert_eq!(keyring.open("a: 1\n").unwrap(), "a: 1\n");
+    }
+
+    #[test]
+    fn test_retired_key_still_opens() {
+        let old = Keyring {
+            primary: Some(key(1)),
+            retired: vec![],
+        };
+        let sealed = old.seal("leases: []\n").unwrap();
+
+        let rotated = Keyring {
+            primary: Some(key(2)),
+            retired: vec![key(1)],
+        };
+        assert_eq!(rotated.open(&sealed).unwrap(), "leases: []\n");
+
+        let without_old = Keyring {
+            primary: Some(key(2)),
+            retired: vec![],
+        };
+        assert!(without_old.open(&sealed).is_err());
+    }
+
+    #[test]
+    fn test_tampered_ciphertext_is_rejected() {
+        let keyring = Keyring {
+            primary: Some(key(3)),
+            retired: vec![],
+        };
+        let sealed = keyring.seal("leases: []\n").unwrap();
+        let mut envelope: Envelope = serde_yaml::from_str(&sealed).unwrap();
+        let mut bytes = BASE64.decode(&envelope.ciphertext).unwrap();
+        bytes[0] ^= 1;
+        envelope.ciphertext = BASE64.encode(bytes);
+        let tampered = serde_yaml::to_string(&envelope).unwrap();
+        assert!(keyring.open(&tampered).is_err());
+    }
+
+    #[test]
+    fn test_key_file_must_be_32_bytes() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("key");
+        std::fs::write(&path, BASE64.encode([7u8; 16])).unwrap();
+        assert!(StateKey::from_file(&path).is_err());
+        std::fs::write(&path, BASE64.encode([7u8; 32])).unwrap();
+        assert_eq!(StateKey::from_file(&path).unwrap().id(), key(7).id());
+    }
+}
@@ -0,0 +1,171 @@
+use crate::types::{IpLease, IpamState, PoolInfo};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::net::IpAddr;
+
+/// Pool and lease differences between two states
+#[derive(Debug, Default, Serialize)]
+pub struct StateDiff {
+    pub pools_added: Vec<PoolInfo>,
+    pub pools_removed: Vec<PoolInfo>,
+    /// Pools whose subnet or gateway changed, as `(old, new)`
+    pub pools_changed: Vec<(PoolInfo, PoolInfo)>,
+    pub leases_added: Vec<IpLease>,
+    pub leases_removed: Vec<IpLease>,
+    /// Leases for the same IP that now belong to a different container
+    pub leases_changed: Vec<(IpLease, IpLease)>,
+}
+
+impl StateDiff {
+    /// Compute what changed going from `old` to `new`
+    pub fn between(old: &IpamState, new: &IpamState) -> Self {
+        let mut diff = Self::default();
+
+        let old_pools: BTreeMap<_, _> = old.pools.iter().collect();
+        let new_pools: BTreeMap<_, _> = new.pools.iter().collect();
+        for (id, pool) in &old_pools {
+            match new_pools.get(id) {
+                None => diff.pools_removed.push((*pool).clone()),
+                Some(new_pool) if !same_pool(pool, new_pool) => diff
+                    .pools_changed
+                    .push(((*pool).clone(), (*new_pool).clone())),
+                Some(_) => {}
+            }
+        }
+        for (id, pool) in &new_pools {
+            if !old_pools.contains_key(id) {
+                diff.pools_added.push((*pool).clone());
+            }
+        }
+
+        let old_leases = leases_by_ip(&old.leases);
+        let new_leases = leases_by_ip(&new.leases);
+        for (ip, lease) in &old_leases {
+            match new_leases.get(ip) {
+                None => diff.leases_removed.push((*lease).clone()),
+                Some(new_lease) if new_lease.container_name != lease.container_name => diff
+                    .leases_changed
+                    .push(((*lease).clone(), (*new_lease).clone())),
+                Some(_) => {}
+            }
+        }
+        for (ip, lease) in &new_leases {
+            if !old_leases.contains_key(ip) {
+                diff.leases_added.push((*lease).clone());
+            }
+        }
+        diff.leases_added.sort_by_key(|l| l.ip_address);
+        diff.leases_removed.sort_by_key(|l| l.ip_address);
+        diff.leases_changed.sort_by_key(|(l, _)| l.ip_address);
+
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pools_added.is_empty()
+            && self.pools_removed.is_empty()
+            && self.pools_changed.is_empty()
+            && self.leases_added.is_empty()
+            && self.leases_removed.is_empty()
+            && self.leases_changed.is_empty()
+    }
+}
+
+fn same_pool(a: &PoolInfo, b: &PoolInfo) -> bool {
+    a.subnet == b.subnet && a.gateway == b.gateway
+}
+
+fn leases_by_ip(leases: &[IpLease]) -> HashMap<IpAddr, &IpLease> {
+    leases.iter().map(|l| (l.ip_address, l)).collect()
+}
+
+impl fmt::Display for StateDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return write!(f, "no changes");
+        }
+
+        let mut lines = Vec::new();
+        for p in &self.pools_added {
+            lines.push(format!("+ pool {} {}", p.pool_id, p.subnet));
+        }
+        for p in &self.pools_removed {
+            lines.push(format!("- pool {} {}", p.pool_id, p.subnet));
+        }
+        for (old, new) in &self.pools_changed {
+            lines.push(format!(
+                "~ pool {} {} (gateway {:?}) -> {} (gateway {:?})",
+                old.pool_id, old.subnet, old.gateway, new.subnet, new.gateway
+            ));
+        }
+        for l in &self.leases_added {
+            lines.push(format!("+ lease {} {}", l.ip_address, l.container_name));
+        }
+        for l in &self.leases_removed {
+            lines.push(format!("- lease {} {}", l.ip_address, l.container_name));
+        }
+        for (old, new) in &self.leases_changed {
+            lines.push(format!(
+                "~ lease {} {} -> {}",
+                old.ip_address, old.container_name, new.container_name
+            ));
+        }
+        write!(f, "{}", lines.join("; "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    fn lease(ip: &str, name: &str) -> IpLease {
+        IpLease::new(ip.parse().unwrap(), name.to_string(), Utc::now())
+    }
+
+    fn pool(id: &str, subnet: &str) -> PoolInfo {
+        PoolInfo {
+            pool_id: id.to_string(),
+            subnet: subnet.parse().unwrap(),
+            gateway: None,
+            thresholds: None,
+        }
+    }
+
+    #[test]
+    fn test_diff_detects_pool_and_lease_changes() {
+        let mut old = IpamState::default();
+        old.pools.insert("p1".into(), pool("p1", "10.0.0.0/24"));
+        old.pools.insert("p2".into(), pool("p2", "10.0.1.0/24"));
+        old.leases.push(lease("10.0.0.2", "a"));
+        old.leases.push(lease("10.0.0.3", "b"));
+
+        let mut new = IpamState::default();
+        new.pools.insert("p1".into(), pool("p1", "10.0.0.0/23"));
+        new.pools.insert("p3".into(), pool("p3", "10.0.2.0/24"));
+        new.leases.push(lease("10.0.0.2", "a"));
+        new.leases.push(lease("10.0.0.3", "c"));
+        new.leases.push(lease("10.0.0.4", "d"));
+
+        let diff = StateDiff::between(&old, &new);
+        assert_eq!(diff.pools_added[0].pool_id, "p3");
+        assert_eq!(diff.pools_removed[0].pool_id, "p2");
+        assert_eq!(diff.pools_changed[0].1.subnet.to_string(), "10.0.0.0/23");
+        assert_eq!(diff.leases_added[0].container_name, "d");
+        assert!(diff.leases_removed.is_empty());
+        assert_eq!(diff.leases_changed[0].1.container_name, "c");
+        assert!(!diff.is_empty());
+    }
+
+    #[test]
+    fn test_diff_of_identical_states_is_empty() {
+        let mut state = IpamState::default();
+        state.pools.insert("p1".into(), pool("p1", "10.0.0.0/24"));
+        state.leases.push(lease("10.0.0.2", "a"));
+
+        let diff = StateDiff::between(&state, &state.clone());
+        assert!(diff.is_empty());
+        assert_eq!(diff.to_string(), "no changes");
+    }
+}
@@ -0,0 +1,206 @@
+use crate::types::IpLease;
+use chrono::{DateTime, Utc};
+use ipnetwork::IpNetwork;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use tokio::sync::broadcast;
+
+/// Events a subscriber may fall behind by before it misses the oldest ones,
+/// and events kept for subscribers resuming after a disconnect
+const DEFAULT_CAPACITY: usize = 1024;
+
+/// Which way utilization moved across a threshold
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    Rising,
+    Falling,
+}
+
+impl std::fmt::Display for Direction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Rising => "rose above",
+            Self::Falling => "fell below",
+        })
+    }
+}
+
+/// What happened, tagged with `type` when serialized
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EventKind {
+    PoolCreated {
+        pool_id: String,
+        subnet: IpNetwork,
+        address_space: String,
+    },
+    /// Also ends every lease of the pool
+    PoolReleased {
+        pool_id: String,
+    },
+    /// Docker or an operator leased an address
+    LeaseCreated {
+        lease: IpLease,
+    },
+    LeaseReleased {
+        lease: IpLease,
+    },
+    /// An operator gave a leased address to another container
+    LeaseMoved {
+        lease: IpLease,
+        previous_container: String,
+    },
+    /// A pool's utilization crossed one of its thresholds
+    ThresholdCrossed {
+        pool_id: String,
+        subnet: IpNetwork,
+        threshold: f64,
+        percent: f64,
+        direction: Direction,
+    },
+}
+
+/// Something that happened to a pool or lease
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Event {
+    /// Numbered from 1 since the plugin started
+    pub seq: u64,
+    pub timestamp: DateTime<Utc>,
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+/// Fans events out to in-process subscribers
+///
+/// Publishing never waits for subscribers; one that falls more than
+/// `capacity` events behind misses the oldest ones. The last `capacity`
+/// events are kept so that a subscriber can resume where it left off.
+pub struct EventBus {
+    sender: broadcast::Sender<Event>,
+    capacity: usize,
+    /// Recent events, oldest first, and the last sequence number
+    recent: Mutex<(VecDeque<Event>, u64)>,
+}
+
+impl EventBus {
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity);
+        Self {
+            sender,
+            capacity,
+            recent: Mutex::new((VecDeque::with_capacity(capacity), 0)),
+        }
+    }
+
+    pub fn publish(&self, kind: EventKind) {
+        let mut recent = self.recent.lock().unwrap();
+        let (events, last) = &mut *recent;
+        *last += 1;
+        let event = Event {
+            seq: *last,
+            timestamp: Utc::now(),
+            kind,
+        };
+        if events.len() == self.capacity {
+            events.pop_front();
+        }
+        events.push_back(event.clone());
+        // Nobody listening is not an error
+        let _ = self.sender.send(event);
+    }
+
+    /// Events published from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+
+    /// The kept events after `seq`, and a receiver for the ones published
+    /// after them
+    ///
+    /// `None` when some events after `seq` are no longer kept, or when `seq`
+    /// is ahead of the last event, as happens after the plugin restarted.
+    pub fn subscribe_after(&self, seq: u64) -> Option<(Vec<Event>, broadcast::Receiver<Event>)> {
+        let recent = self.recent.lock().unwrap();
+        let (events, last) = &*recent;
+        let oldest = events.front().map_or(last + 1, |e| e.seq);
+        if seq > *last || seq + 1 < oldest {
+            return None;
+        }
+        let missed = events.iter().filter(|e| e.seq > seq).cloned().collect();
+        // Subscribing under the lock, so nothing is published in between
+        Some((missed, self.sender.subscribe()))
+    }
+
+    /// The sequence number of the last event, 0 before the first
+    pub fn last_seq(&self) -> u64 {
+        self.recent.lock().unwrap().1
+    }
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new(DEFAULT_CAPACITY)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_subscribers_get_published_events() {
+        let bus = EventBus::default();
+        // Published before anyone listens: dropped
+        bus.publish(EventKind::ThresholdCrossed {
+            pool_id: "pool-0".into(),
+            subnet: "10.0.0.0/24".parse().unwrap(),
+            threshold: 80.0,
+            percent: 80.0,
+            direction: Direction::Rising,
+        });
+
+        let mut rx = bus.subscribe();
+        bus.publish(EventKind::ThresholdCrossed {
+            pool_id: "pool-1".into(),
+            subnet: "10.0.0.0/24".parse().unwrap(),
+            threshold: 95.0,
+            percent: 94.5,
+            direction: Direction::Falling,
+        });
+        let event = rx.recv().await.unwrap();
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(json["type"], "threshold_crossed");
+        assert_eq!(json["pool_id"], "pool-1");
+        assert_eq!(json["subnet"], "10.0.0.0/24");
+        assert_eq!(json["direction"], "falling");
+        assert_eq!(serde_json::from_value::<Event>(json).unwrap(), event);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_subscribers_resume_after_a_sequence_number() {
+        let bus = EventBus::new(2);
+        let released = |id: &str| EventKind::PoolReleased {
+            pool_id: id.to_string(),
+        };
+        assert!(bus.subscribe_after(0).unwrap().0.is_empty());
+        for id in ["a", "b", "c"] {
+            bus.publish(released(id));
+        }
+        assert_eq!(bus.last_seq(), 3);
+
+        // Event 1 is no longer kept, and there is no event 4 yet
+        assert!(bus.subscribe_after(0).is_none());
+        assert!(bus.subscribe_after(4).is_none());
+
+        let (missed, mut rx) = bus.subscribe_after(1).unwrap();
+        let seqs: Vec<_> = missed.iter().map(|e| e.seq).collect();
+        assert_eq!(seqs, vec![2, 3]);
+        assert_eq!(missed[1].kind, released("c"));
+        bus.publish(released("d"));
+        assert_eq!(rx.recv().await.unwrap().seq, 4);
+        assert!(bus.subscribe_after(4).unwrap().0.is_empty());
+    }
+}
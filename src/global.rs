@@ -0,0 +1,534 @@
+use crate::address_space::overlaps;
+use crate::ipam::NotFoundError;
+use crate::kv::KvStore;
+use crate::types::{IpLease, PoolInfo};
+use crate::utilization::ExhaustedError;
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use ipnetwork::IpNetwork;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+/// A new pool overlaps an existing one it is not allowed to share addresses
+/// with
+#[derive(Debug)]
+pub struct OverlapError {
+    pub subnet: IpNetwork,
+    pub existing_pool: String,
+    pub existing_subnet: IpNetwork,
+}
+
+impl fmt::Display for OverlapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Subnet {} overlaps {} of pool {}",
+            self.subnet, self.existing_subnet, self.existing_pool
+        )
+    }
+}
+
+impl std::error::Error for OverlapError {}
+
+/// An address that is already leased was asked for
+#[derive(Debug)]
+pub struct AddressInUseError {
+    pub ip: IpAddr,
+    pub pool_id: String,
+}
+
+impl fmt::Display for AddressInUseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "IP address {} is already allocated in pool {}",
+            self.ip, self.pool_id
+        )
+    }
+}
+
+impl std::error::Error for AddressInUseError {}
+
+/// Coordinates pools and leases of the `global` address space between hosts
+///
+/// The plugin only talks to the global space through this trait, so the
+/// backing store can be swapped without touching the local state file.
+/// Implementations must make `create_pool` and the address methods atomic
+/// across hosts.
+#[async_trait]
+pub trait Coordinator: Send + Sync {
+    /// Record a new pool, failing with [`OverlapError`] if its subnet
+    /// overlaps another global pool
+    async fn create_pool(&self, pool: &PoolInfo) -> Result<()>;
+
+    async fn get_pool(&self, pool_id: &str) -> Result<Option<PoolInfo>>;
+
+    /// Every global pool
+    async fn pools(&self) -> Result<Vec<PoolInfo>>;
+
+    /// Every lease in a pool
+    async fn leases(&self, pool_id: &str) -> Result<Vec<IpLease>>;
+
+    async fn get_lease(&self, pool_id: &str, ip: IpAddr) -> Result<Option<IpLease>>;
+
+    /// Overwrite the existing lease of `lease.ip_address`; returns false if
+    /// there is none
+    async fn replace_lease(&self, pool_id: &str, lease: &IpLease) -> Result<bool>;
+
+    /// Remove a pool and every lease in it
+    async fn release_pool(&self, pool_id: &str) -> Result<()>;
+
+    /// Record `lease` if its address is free in the pool, failing with
+    /// [`AddressInUseError`] otherwise, or with [`NotFoundError`] once the
+    /// pool is gone
+    async fn claim_address(&self, pool_id: &str, lease: &IpLease) -> Result<()>;
+
+    /// Claim the first free address in `network` for `lease`, skipping the
+    /// network and broadcast addresses like local allocation does
+    async fn allocate_address(
+        &self,
+        pool_id: &str,
+        network: &IpNetwork,
+        lease: IpLease,
+    ) -> Result<IpLease>;
+
+    /// Returns whether a lease was removed
+    async fn release_address(&self, pool_id: &str, ip: IpAddr) -> Result<bool>;
+}
+
+/// [`Coordinator`] backed by an etcd-style KV store
+///
+/// Each pool is stored under `<prefix>pools/<pool_id>` and each lease under
+/// `<prefix>leases/<pool_id>/<ip>`. Leases are created with a
+/// create-if-absent compare-and-swap, so when two hosts race for the same
+/// address exactly one of them gets it. The subnets of all pools are also
+/// kept in a single `<prefix>subnets` index key that is updated with
+/// compare-and-swap, which serializes pool creation so overlapping pools
+/// cannot be created concurrently. A new pool is written in the same
+/// transaction as its index entry. Leases are only written while their pool
+/// key is unchanged, and a released pool's key is deleted before its leases,
+/// so no lease can outlive its pool.
+pub struct KvCoordinator {
+    kv: Arc<dyn KvStore>,
+    prefix: String,
+}
+
+impl KvCoordinator {
+    pub fn new(kv: Arc<dyn KvStore>, prefix: impl Into<String>) -> Self {
+        let mut prefix = prefix.into();
+        if !prefix.ends_with('/') {
+            prefix.push('/');
+        }
+        Self { kv, prefix }
+    }
+
+    fn pool_key(&self, pool_id: &str) -> String {
+        format!("{}pools/{}", self.prefix, pool_id)
+    }
+
+    fn leases_prefix(&self, pool_id: &str) -> String {
+        format!("{}leases/{}/", self.prefix, pool_id)
+    }
+
+    fn lease_key(&self, pool_id: &str, ip: IpAddr) -> String {
+        format!("{}{}", self.leases_prefix(pool_id), ip)
+    }
+
+    fn index_key(&self) -> String {
+        format!("{}subnets", self.prefix)
+    }
+
+    /// Revision of the pool's key, failing with [`NotFoundError`] if the
+    /// pool does not exist
+    async fn pool_revision(&self, pool_id: &str) -> Result<u64> {
+        match self.kv.get(&self.pool_key(pool_id)).await? {
+            Some(entry) => Ok(entry.revision),
+            None => Err(NotFoundError(format!("Global pool not found: {}", pool_id)).into()),
+        }
+    }
+
+    /// Create the lease key of `lease` if it is absent and the pool is still
+    /// at `pool_revision`
+    async fn create_lease(
+        &self,
+        pool_id: &str,
+        pool_revision: u64,
+        lease: &IpLease,
+    ) -> Result<bool> {
+        let value = serde_json::to_string(lease)?;
+        self.kv
+            .compare_and_swap_all(
+                &[(&self.pool_key(pool_id), pool_revision)],
+                &[(&self.lease_key(pool_id, lease.ip_address), None, &value)],
+            )
+            .await
+    }
+
+    /// The pool_id -> subnet index and its revision, `None` before the
+    /// first pool
+    async fn read_index(&self) -> Result<(Option<u64>, BTreeMap<String, IpNetwork>)> {
+        match self.kv.get(&self.index_key()).await? {
+            Some(entry) => Ok((
+                Some(entry.revision),
+                serde_json::from_str(&entry.value).context("Invalid global subnet index")?,
+            )),
+            None => Ok((None, BTreeMap::new())),
+        }
+    }
+
+    /// Apply `update` to the pool_id -> subnet index, retrying when another
+    /// host changes it concurrently
+    async fn update_index<F>(&self, mut update: F) -> Result<()>
+    where
+        F: FnMut(&mut BTreeMap<String, IpNetwork>) -> Result<()> + Send,
+    {
+        let key = self.index_key();
+        loop {
+            let (revision, mut index) = self.read_index().await?;
+            update(&mut index)?;
+            let value = serde_json::to_string(&index)?;
+            if self.kv.compare_and_swap(&key, revision, &value).await? {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Coordinator for KvCoordinator {
+    async fn create_pool(&self, pool: &PoolInfo) -> Result<()> {
+        let index_key = self.index_key();
+        let pool_key = self.pool_key(&pool.pool_id);
+        let value = serde_json::to_string(pool)?;
+        loop {
+            let (revision, mut index) = self.read_index().await?;
+            for (existing_pool, existing_subnet) in index.iter() {
+                if overlaps(existing_subnet, &pool.subnet) {
+                    return Err(OverlapError {
+                        subnet: pool.subnet,
+                        existing_pool: existing_pool.clone(),
+                        existing_subnet: *existing_subnet,
+                    }
+                    .into());
+                }
+            }
+            index.insert(pool.pool_id.clone(), pool.subnet);
+            let index_value = serde_json::to_string(&index)?;
+
+            // The index and the pool are written together, so a pool that
+            // already exists leaves the index as it was
+            if self
+                .kv
+                .compare_and_swap_all(
+                    &[],
+                    &[
+                        (&index_key, revision, &index_value),
+                        (&pool_key, None, &value),
+                    ],
+                )
+                .await?
+            {
+                return Ok(());
+            }
+            if self.kv.get(&pool_key).await?.is_some() {
+                bail!("Global pool {} already exists", pool.pool_id);
+            }
+        }
+    }
+
+    async fn get_pool(&self, pool_id: &str) -> Result<Option<PoolInfo>> {
+        match self.kv.get(&self.pool_key(pool_id)).await? {
+            Some(entry) => Ok(Some(
+                serde_json::from_str(&entry.value).context("Invalid global pool record")?,
+            )),
+            None => Ok(None),
+        }
+    }
+
+    async fn pools(&self) -> Result<Vec<PoolInfo>> {
+        self.kv
+            .list(&format!("{}pools/", self.prefix))
+            .await?
+            .into_iter()
+            .map(|(_, entry)| {
+                serde_json::from_str(&entry.value).context("Invalid global pool record")
+            })
+            .collect()
+    }
+
+    async fn leases(&self, pool_id: &str) -> Result<Vec<IpLease>> {
+        self.kv
+            .list(&self.leases_prefix(pool_id))
+            .await?
+            .into_iter()
+            .map(|(_, entry)| {
+                serde_json::from_str(&entry.value).context("Invalid global lease record")
+            })
+            .collect()
+    }
+
+    async fn get_lease(&self, pool_id: &str, ip: IpAddr) -> Result<Option<IpLease>> {
+        match self.kv.get(&self.lease_key(pool_id, ip)).await? {
+            Some(entry) => Ok(Some(
+                serde_json::from_str(&entry.value).context("Invalid global lease record")?,
+            )),
+            None => Ok(None),
+        }
+    }
+
+    async fn replace_lease(&self, pool_id: &str, lease: &IpLease) -> Result<bool> {
+        let key = self.lease_key(pool_id, lease.ip_address);
+        let value = serde_json::to_string(lease)?;
+        loop {
+            let Some(entry) = self.kv.get(&key).await? else {
+                return Ok(false);
+            };
+            if self
+                .kv
+                .compare_and_swap(&key, Some(entry.revision), &value)
+                .await?
+            {
+                return Ok(true);
+            }
+        }
+    }
+
+    async fn release_pool(&self, pool_id: &str) -> Result<()> {
+        // The pool goes first: claims check it in the same transaction, so
+        // none can add a lease once the leases below are deleted
+        let key = self.pool_key(pool_id);
+        while let Some(entry) = self.kv.get(&key).await? {
+            if self.kv.compare_and_delete(&key, entry.revision).await? {
+                break;
+            }
+        }
+        self.kv.delete_prefix(&self.leases_prefix(pool_id)).await?;
+        // Dropped last so the subnet stays reserved until the pool is gone
+        self.update_index(|index| {
+            index.remove(pool_id);
+            Ok(())
+        })
+        .await
+    }
+
+    async fn claim_address(&self, pool_id: &str, lease: &IpLease) -> Result<()> {
+        loop {
+            let pool_revision = self.pool_revision(pool_id).await?;
+            if self.create_lease(pool_id, pool_revision, lease).await? {
+                return Ok(());
+            }
+            if self.get_lease(pool_id, lease.ip_address).await?.is_some() {
+                return Err(AddressInUseError {
+                    ip: lease.ip_address,
+                    pool_id: pool_id.to_string(),
+                }
+                .into());
+            }
+        }
+    }
+
+    /// Candidates that another host claims first are skipped.
+    async fn allocate_address(
+        &self,
+        pool_id: &str,
+        network: &IpNetwork,
+        mut lease: IpLease,
+    ) -> Result<IpLease> {
+        let mut pool_revision = self.pool_revision(pool_id).await?;
+        let leases = self.leases(pool_id).await?;
+        let taken: HashSet<IpAddr> = leases.iter().map(|l| l.ip_address).collect();
+
+        for ip in network.iter().skip(1) {
+            if (ip.is_ipv4() && ip == network.broadcast()) || taken.contains(&ip) {
+                continue;
+            }
+            lease.ip_address = ip;
+            let claimed = loop {
+                if self.create_lease(pool_id, pool_revision, &lease).await? {
+                    break true;
+                }
+                // Another host took the address, unless the pool changed
+                let current = self.pool_revision(pool_id).await?;
+                if current == pool_revision {
+                    break false;
+                }
+                pool_revision = current;
+            };
+            if claimed {
+                return Ok(lease);
+            }
+        }
+
+        Err(ExhaustedError::new(network, &leases).into())
+    }
+
+    async fn release_address(&self, pool_id: &str, ip: IpAddr) -> Result<bool> {
+        let key = self.lease_key(pool_id, ip);
+        match self.kv.get(&key).await? {
+            Some(entry) => self.kv.compare_and_delete(&key, entry.revision).await,
+            None => Ok(false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kv::MemoryKv;
+    use chrono::Utc;
+
+    fn lease(name: &str) -> IpLease {
+        IpLease::new("0.0.0.0".parse().unwrap(), name.to_string(), Utc::now())
+    }
+
+    #[tokio::test]
+    async fn test_two_hosts_never_share_an_address() {
+        let kv: Arc<dyn KvStore> = Arc::new(MemoryKv::new());
+        let host_a = Arc::new(KvCoordinator::new(kv.clone(), "/ipam"));
+        let host_b = Arc::new(KvCoordinator::new(kv, "/ipam"));
+        let network: IpNetwork = "10.200.0.0/24".parse().unwrap();
+        host_a
+            .create_pool(&PoolInfo {
+                pool_id: "global-pool-1".into(),
+                subnet: network,
+                gateway: None,
+                thresholds: None,
+            })
+            .await
+            .unwrap();
+
+        let mut handles = vec![];
+        for i in 0..40 {
+            let store = if i % 2 == 0 {
+                host_a.clone()
+            } else {
+                host_b.clone()
+            };
+            handles.push(tokio::spawn(async move {
+                store
+                    .allocate_address("global-pool-1", &network, lease(&format!("c{}", i)))
+                    .await
+                    .unwrap()
+                    .ip_address
+            }));
+        }
+        let mut ips = HashSet::new();
+        for handle in handles {
+            assert!(ips.insert(handle.await.unwrap()));
+        }
+        assert_eq!(host_b.leases("global-pool-1").await.unwrap().len(), 40);
+    }
+
+    #[tokio::test]
+    async fn test_claim_and_release() {
+        let store = KvCoordinator::new(Arc::new(MemoryKv::new()), "/ipam/");
+        let mut l = lease("web");
+        l.ip_address = "10.200.0.9".parse().unwrap();
+        let err = store.claim_address("p", &l).await.unwrap_err();
+        assert!(err.is::<NotFoundError>());
+        store
+            .create_pool(&PoolInfo {
+                pool_id: "p".into(),
+                subnet: "10.200.0.0/24".parse().unwrap(),
+                gateway: None,
+                thresholds: None,
+            })
+            .await
+            .unwrap();
+
+        store.claim_address("p", &l).await.unwrap();
+        let err = store.claim_address("p", &l).await.unwrap_err();
+        assert!(err.is::<AddressInUseError>());
+
+        let moved = IpLease {
+            container_name: "api".into(),
+            ..l.clone()
+        };
+        assert!(store.replace_lease("p", &moved).await.unwrap());
+        let current = store.get_lease("p", l.ip_address).await.unwrap().unwrap();
+        assert_eq!(current.container_name, "api");
+
+        assert!(store.release_address("p", l.ip_address).await.unwrap());
+        assert!(!store.replace_lease("p", &moved).await.unwrap());
+        assert!(!store.release_address("p", l.ip_address).await.unwrap());
+        store.claim_address("p", &l).await.unwrap();
+
+        store.release_pool("p").await.unwrap();
+        assert!(store.leases("p").await.unwrap().is_empty());
+        assert!(store.get_pool("p").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_no_lease_outlives_its_pool() {
+        let store = KvCoordinator::new(Arc::new(MemoryKv::new()), "/ipam/");
+        let network: IpNetwork = "10.200.0.0/24".parse().unwrap();
+        store
+            .create_pool(&PoolInfo {
+                pool_id: "p".into(),
+                subnet: network,
+                gateway: None,
+                thresholds: None,
+            })
+            .await
+            .unwrap();
+        let mut l = lease("web");
+        l.ip_address = "10.200.0.9".parse().unwrap();
+
+        // A claim that looked the pool up just before it was released
+        let revision = store.pool_revision("p").await.unwrap();
+        store.release_pool("p").await.unwrap();
+        assert!(!store.create_lease("p", revision, &l).await.unwrap());
+        assert!(store.leases("p").await.unwrap().is_empty());
+
+        let err = store.claim_address("p", &l).await.unwrap_err();
+        assert!(err.is::<NotFoundError>());
+        let err = store
+            .allocate_address("p", &network, lease("api"))
+            .await
+            .unwrap_err();
+        assert!(err.is::<NotFoundError>());
+    }
+
+    #[tokio::test]
+    async fn test_overlapping_pools_are_rejected() {
+        let kv: Arc<dyn KvStore> = Arc::new(MemoryKv::new());
+        let host_a = KvCoordinator::new(kv.clone(), "/ipam");
+        let host_b = KvCoordinator::new(kv, "/ipam");
+        let pool = |id: &str, subnet: &str| PoolInfo {
+            pool_id: id.into(),
+            subnet: subnet.parse().unwrap(),
+            gateway: None,
+            thresholds: None,
+        };
+
+        host_a.create_pool(&pool("a", "10.0.0.0/16")).await.unwrap();
+        let err = host_b
+            .create_pool(&pool("b", "10.0.5.0/24"))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<OverlapError>().unwrap().existing_pool,
+            "a"
+        );
+        host_b.create_pool(&pool("b", "10.1.0.0/24")).await.unwrap();
+        assert_eq!(host_a.pools().await.unwrap().len(), 2);
+        // A pool ID that is taken does not change the subnet it holds
+        let err = host_b
+            .create_pool(&pool("a", "10.9.0.0/16"))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("already exists"));
+        host_b.create_pool(&pool("d", "10.9.0.0/24")).await.unwrap();
+        assert!(host_b
+            .create_pool(&pool("e", "10.0.6.0/24"))
+            .await
+            .unwrap_err()
+            .is::<OverlapError>());
+
+        // Releasing a pool frees its subnet
+        host_a.release_pool("a").await.unwrap();
+        host_b.create_pool(&pool("c", "10.0.5.0/24")).await.unwrap();
+    }
+}
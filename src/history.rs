@@ -0,0 +1,186 @@
+use crate::types::{IpLease, IpamState, LeaseRecord};
+use chrono::{DateTime, Utc};
+use std::net::IpAddr;
+
+/// Number of past holders kept per address by default
+pub const DEFAULT_LIMIT: usize = 10;
+
+/// Move an ended lease into the address's history, keeping at most `limit`
+/// past holders of the address in the lease's pool
+///
+/// Only local leases get here: global ones are released in the coordinator,
+/// which keeps no history.
+pub fn record_release(
+    state: &mut IpamState,
+    lease: IpLease,
+    released: DateTime<Utc>,
+    limit: usize,
+) {
+    if limit == 0 {
+        return;
+    }
+    let records = state.history.entry(lease.ip_address).or_default();
+    let pool_id = lease.pool_id;
+    records.push(LeaseRecord {
+        container_name: lease.container_name,
+        lease_time: lease.lease_time,
+        release_time: Some(released),
+        pool_id: pool_id.clone(),
+    });
+    // Overlapping pools share an address; each keeps its own holders
+    let mut excess = records.iter().filter(|r| r.pool_id == pool_id).count();
+    records.retain(|r| {
+        if excess > limit && r.pool_id == pool_id {
+            excess -= 1;
+            false
+        } else {
+            true
+        }
+    });
+}
+
+/// Every known holder of `ip`, newest first, starting with the current ones
+///
+/// With `pool_id`, only holders in that pool; records written before leases
+/// knew their pool match every pool.
+pub fn holders(state: &IpamState, ip: IpAddr, pool_id: Option<&str>) -> Vec<LeaseRecord> {
+    let in_pool = |record: &Option<String>| {
+        pool_id.is_none_or(|id| record.as_deref().is_none_or(|r| r == id))
+    };
+    let current = state
+        .leases
+        .iter()
+        .filter(|l| l.ip_address == ip && in_pool(&l.pool_id))
+        .map(|l| LeaseRecord {
+            container_name: l.container_name.clone(),
+            lease_time: l.lease_time,
+            release_time: None,
+            pool_id: l.pool_id.clone(),
+        });
+    let past = state
+        .history
+        .get(&ip)
+        .into_iter()
+        .flatten()
+        .rev()
+        .filter(|r| in_pool(&r.pool_id))
+        .cloned();
+    current.chain(past).collect()
+}
+
+/// Who held `ip` at time `at`, if it was leased then and is still within the
+/// kept history; one holder per pool when overlapping pools both leased it
+pub fn holders_at(
+    state: &IpamState,
+    ip: IpAddr,
+    pool_id: Option<&str>,
+    at: DateTime<Utc>,
+) -> Vec<LeaseRecord> {
+    holders(state, ip, pool_id)
+        .into_iter()
+        .filter(|r| r.lease_time <= at && r.release_time.is_none_or(|released| at < released))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(hour: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 5, 7, hour, 0, 0).unwrap()
+    }
+
+    fn lease(name: &str, hour: u32) -> IpLease {
+        IpLease::new("10.0.3.17".parse().unwrap(), name.to_string(), at(hour))
+    }
+
+    #[test]
+    fn test_holder_at() {
+        let ip: IpAddr = "10.0.3.17".parse().unwrap();
+        let mut state = IpamState::default();
+        record_release(&mut state, lease("first", 9), at(12), 10);
+        record_release(&mut state, lease("second", 13), at(15), 10);
+        state.leases.push(lease("third", 16));
+
+        let names = |hour| -> Vec<String> {
+            holders_at(&state, ip, None, at(hour))
+                .into_iter()
+                .map(|r| r.container_name)
+                .collect()
+        };
+        assert!(names(8).is_empty());
+        assert_eq!(names(10), vec!["first"]);
+        assert!(names(12).is_empty());
+        assert_eq!(names(14), vec!["second"]);
+        assert_eq!(names(20), vec!["third"]);
+
+        let all: Vec<_> = holders(&state, ip, None)
+            .into_iter()
+            .map(|r| r.container_name)
+            .collect();
+        assert_eq!(all, vec!["third", "second", "first"]);
+    }
+
+    #[test]
+    fn test_history_is_capped() {
+        let mut state = IpamState::default();
+        for hour in 0..5 {
+            record_release(
+                &mut state,
+                lease(&format!("c{}", hour), hour),
+                at(hour + 1),
+                3,
+            );
+        }
+        let records = &state.history[&"10.0.3.17".parse::<IpAddr>().unwrap()];
+        let names: Vec<_> = records.iter().map(|r| r.container_name.as_str()).collect();
+        assert_eq!(names, vec!["c2", "c3", "c4"]);
+
+        record_release(&mut state, lease("ignored", 9), at(10), 0);
+        assert_eq!(state.history.values().flatten().count(), 3);
+    }
+
+    #[test]
+    fn test_overlapping_pools_keep_their_own_holders() {
+        let ip: IpAddr = "10.0.3.17".parse().unwrap();
+        let in_pool = |name: &str, hour, pool_id: &str| IpLease {
+            pool_id: Some(pool_id.to_string()),
+            ..lease(name, hour)
+        };
+        let mut state = IpamState::default();
+        record_release(&mut state, lease("legacy", 1), at(2), 2);
+        for hour in 3..6 {
+            record_release(
+                &mut state,
+                in_pool(&format!("a{}", hour), hour, "a"),
+                at(hour + 1),
+                2,
+            );
+        }
+        record_release(&mut state, in_pool("b1", 9, "b"), at(12), 2);
+        state.leases.push(in_pool("b2", 13, "b"));
+        state.leases.push(in_pool("a9", 13, "a"));
+
+        let names = |pool_id| -> Vec<String> {
+            holders(&state, ip, pool_id)
+                .into_iter()
+                .map(|r| r.container_name)
+                .collect()
+        };
+        // The cap applies per pool, and records without a pool match any
+        assert_eq!(names(Some("a")), vec!["a9", "a5", "a4", "legacy"]);
+        assert_eq!(names(Some("b")), vec!["b2", "b1", "legacy"]);
+
+        let at_ten: Vec<_> = holders_at(&state, ip, Some("a"), at(10))
+            .into_iter()
+            .map(|r| r.container_name)
+            .collect();
+        assert!(at_ten.is_empty());
+        assert_eq!(holders_at(&state, ip, None, at(14)).len(), 2);
+        assert_eq!(
+            holders_at(&state, ip, Some("b"), at(10))[0].container_name,
+            "b1"
+        );
+    }
+}
@@ -1,16 +1,48 @@
+use crate::address_space::{self, AddressSpace, DefaultPool};
+use crate::audit::{AuditEvent, AuditLog, Operation};
+use crate::diff::StateDiff;
+use crate::events::{Direction, EventBus, EventKind};
+use crate::global::{AddressInUseError, Coordinator, OverlapError};
+use crate::history;
+use crate::metrics::Metrics;
 use crate::storage::Storage;
 use crate::types::*;
+use crate::utilization::{self, ExhaustedError, Utilization};
 use anyhow::{anyhow, Context, Result};
 use chrono::Utc;
 use ipnetwork::IpNetwork;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::net::IpAddr;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+
+/// Prefix of pool IDs in the local address space
+const LOCAL_POOL_PREFIX: &str = "pool-";
+
+/// Prefix of pool IDs in the global address space, which live in the
+/// coordinator rather than the state file
+const GLOBAL_POOL_PREFIX: &str = "global-pool-";
+
+/// Docker's default for swarm-scoped networks
+pub const DEFAULT_GLOBAL_POOL: &str = "10.0.0.0/8:24";
+
+/// RequestPool option overriding the utilization thresholds of the pool,
+/// e.g. `--ipam-opt thresholds=80,95`
+const THRESHOLDS_OPTION: &str = "thresholds";
 
 /// The IPAM Plugin implementation
 pub struct IpamPlugin {
     storage: Arc<Storage>,
     default_subnet: String,
+    global_default_pools: Vec<DefaultPool>,
+    coordinator: Option<Arc<dyn Coordinator>>,
+    audit: Option<Arc<AuditLog>>,
+    metrics: Option<Arc<Metrics>>,
+    events: EventBus,
+    thresholds: Vec<f64>,
+    /// How many of its thresholds each pool had reached when last checked,
+    /// and the thresholds that was against
+    threshold_levels: Mutex<HashMap<String, (Vec<f64>, usize)>>,
+    history_limit: usize,
 }
 
 impl IpamPlugin {
@@ -18,7 +50,224 @@ impl IpamPlugin {
         Self {
             storage,
             default_subnet,
+            global_default_pools: vec![DEFAULT_GLOBAL_POOL.parse().unwrap()],
+            coordinator: None,
+            audit: None,
+            metrics: None,
+            events: EventBus::default(),
+            thresholds: utilization::DEFAULT_THRESHOLDS.to_vec(),
+            threshold_levels: Mutex::new(HashMap::new()),
+            history_limit: history::DEFAULT_LIMIT,
+        }
+    }
+
+    /// Keep up to `limit` past holders of each local address in the state;
+    /// zero disables lease history
+    pub fn with_lease_history(mut self, limit: usize) -> Self {
+        self.history_limit = limit;
+        self
+    }
+
+    /// Record every pool and lease operation, including failed ones
+    pub fn with_audit_log(mut self, audit: Arc<AuditLog>) -> Self {
+        self.audit = Some(audit);
+        self
+    }
+
+    /// Collect request metrics for `/metrics`
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    pub fn metrics(&self) -> Option<&Metrics> {
+        self.metrics.as_deref()
+    }
+
+    pub fn storage(&self) -> &Storage {
+        &self.storage
+    }
+
+    /// Utilization percentages, ascending, at which pools without their own
+    /// warn; empty disables the warnings
+    pub fn with_thresholds(mut self, thresholds: Vec<f64>) -> Self {
+        self.thresholds = thresholds;
+        self
+    }
+
+    /// Pool and lease events, for subscribers
+    pub fn events(&self) -> &EventBus {
+        &self.events
+    }
+
+    fn audit<T>(&self, event: AuditEvent, result: &Result<T>) {
+        if let Some(audit) = &self.audit {
+            audit.record(&event.result(result));
+        }
+    }
+
+    /// Keep pools requested in the `global` address space in a coordinator
+    /// shared with other hosts. Without one, global pool requests fail.
+    pub fn with_coordinator(mut self, coordinator: Arc<dyn Coordinator>) -> Self {
+        self.coordinator = Some(coordinator);
+        self
+    }
+
+    /// Ranges that global subnets are carved from when a request does not
+    /// name one, tried in order
+    pub fn with_global_default_pools(mut self, pools: Vec<DefaultPool>) -> Self {
+        self.global_default_pools = pools;
+        self
+    }
+
+    /// The coordinator, if `pool_id` names a global pool
+    fn coordinator_for(&self, pool_id: &str) -> Option<&dyn Coordinator> {
+        self.coordinator
+            .as_deref()
+            .filter(|_| pool_id.starts_with(GLOBAL_POOL_PREFIX))
+    }
+
+    fn coordinator(&self) -> Result<&dyn Coordinator> {
+        self.coordinator.as_deref().ok_or_else(|| {
+            anyhow!(
+                "The {} address space needs a coordinator shared between hosts (set GLOBAL_KV_ENDPOINT)",
+                address_space::GLOBAL
+            )
+        })
+    }
+
+    async fn local_pools(&self) -> Vec<(AddressSpace, PoolInfo)> {
+        let state = self.storage.read().await;
+        state
+            .pools
+            .values()
+            .map(|p| (AddressSpace::Local, p.clone()))
+            .collect()
+    }
+
+    /// Every existing pool with the address space it belongs to
+    pub async fn pools(&self) -> Result<Vec<(AddressSpace, PoolInfo)>> {
+        let mut pools = self.local_pools().await;
+        if let Some(coordinator) = &self.coordinator {
+            pools.extend(
+                coordinator
+                    .pools()
+                    .await?
+                    .into_iter()
+                    .map(|p| (AddressSpace::Global, p)),
+            );
+        }
+        Ok(pools)
+    }
+
+    /// A pool by ID, local or global
+    pub async fn pool(&self, pool_id: &str) -> Result<Option<(AddressSpace, PoolInfo)>> {
+        if let Some(coordinator) = self.coordinator_for(pool_id) {
+            return Ok(coordinator
+                .get_pool(pool_id)
+                .await?
+                .map(|p| (AddressSpace::Global, p)));
+        }
+        let state = self.storage.read().await;
+        Ok(state
+            .pools
+            .get(pool_id)
+            .map(|p| (AddressSpace::Local, p.clone())))
+    }
+
+    /// The current leases of a pool
+    pub async fn pool_leases(&self, pool: &PoolInfo) -> Result<Vec<IpLease>> {
+        if let Some(coordinator) = self.coordinator_for(&pool.pool_id) {
+            return coordinator.leases(&pool.pool_id).await;
+        }
+        let state = self.storage.read().await;
+        Ok(state
+            .leases
+            .iter()
+            .filter(|l| in_pool(l, pool))
+            .cloned()
+            .collect())
+    }
+
+    /// Every current lease, local and global
+    pub async fn leases(&self) -> Result<Vec<IpLease>> {
+        let mut leases = self.storage.read().await.leases.clone();
+        if let Some(coordinator) = &self.coordinator {
+            for pool in coordinator.pools().await? {
+                leases.extend(coordinator.leases(&pool.pool_id).await?);
+            }
         }
+        Ok(leases)
+    }
+
+    /// Check a new subnet in `space` against the overlap rules of its space
+    async fn check_overlap(&self, space: AddressSpace, subnet: &IpNetwork) -> Result<()> {
+        let pools = match self.pools().await {
+            Ok(pools) => pools,
+            // Local networks must keep working while the coordinator is down
+            Err(e) if space == AddressSpace::Local => {
+                tracing::warn!("Cannot check global pools for overlap: {:#}", e);
+                self.local_pools().await
+            }
+            Err(e) => return Err(e),
+        };
+        for (other_space, pool) in pools {
+            if space.allows_overlap_with(other_space) {
+                continue;
+            }
+            if address_space::overlaps(subnet, &pool.subnet) {
+                return Err(OverlapError {
+                    subnet: *subnet,
+                    existing_pool: pool.pool_id,
+                    existing_subnet: pool.subnet,
+                }
+                .into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Create a global pool, carving the subnet out of the global default
+    /// pools when the request does not name one
+    async fn request_global_pool(
+        &self,
+        pool_id: &str,
+        pool: Option<String>,
+        thresholds: Option<Vec<f64>>,
+    ) -> Result<IpNetwork> {
+        let coordinator = self.coordinator()?;
+        let create = |subnet: IpNetwork| PoolInfo {
+            pool_id: pool_id.to_string(),
+            subnet,
+            gateway: None,
+            thresholds: thresholds.clone(),
+        };
+
+        if let Some(pool) = pool {
+            let subnet = parse_subnet(&pool)?;
+            self.check_overlap(AddressSpace::Global, &subnet).await?;
+            coordinator.create_pool(&create(subnet)).await?;
+            return Ok(subnet);
+        }
+
+        let taken: Vec<IpNetwork> = self.pools().await?.iter().map(|(_, p)| p.subnet).collect();
+        for default_pool in &self.global_default_pools {
+            for subnet in default_pool.subnets() {
+                if taken.iter().any(|t| address_space::overlaps(t, &subnet)) {
+                    continue;
+                }
+                match coordinator.create_pool(&create(subnet)).await {
+                    Ok(()) => return Ok(subnet),
+                    // Another host took it since we listed the pools
+                    Err(e) if e.is::<OverlapError>() => continue,
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        Err(anyhow!(
+            "No free subnet left in the {} default pools",
+            address_space::GLOBAL
+        ))
     }
 
     /// Handle GetCapabilities request
@@ -31,52 +280,115 @@ impl IpamPlugin {
 
     /// Handle RequestPool request
     pub async fn request_pool(&self, req: RequestPoolRequest) -> Result<RequestPoolResponse> {
+        let mut event = AuditEvent::new(Operation::RequestPool).options(req.options.as_ref());
+        event.subnet = req.pool.clone();
+        let space = req.address_space.clone();
+        let result = self.request_pool_inner(req).await;
+        if let Ok(resp) = &result {
+            event.pool_id = Some(resp.pool_id.clone());
+            event.subnet = Some(resp.pool.clone());
+            if let (Ok(subnet), Ok(space)) = (
+                parse_subnet(&resp.pool),
+                AddressSpace::from_request(space.as_deref()),
+            ) {
+                self.events.publish(EventKind::PoolCreated {
+                    pool_id: resp.pool_id.clone(),
+                    subnet,
+                    address_space: space.as_str().to_string(),
+                });
+            }
+        }
+        self.audit(event, &result);
+        result
+    }
+
+    async fn request_pool_inner(&self, req: RequestPoolRequest) -> Result<RequestPoolResponse> {
+        let space = AddressSpace::from_request(req.address_space.as_deref())?;
+        let thresholds = req
+            .options
+            .as_ref()
+            .and_then(|o| o.get(THRESHOLDS_OPTION))
+            .map(|t| utilization::parse_thresholds(t))
+            .transpose()
+            .with_context(|| format!("Invalid {} option", THRESHOLDS_OPTION))?;
+
+        if space == AddressSpace::Global {
+            let pool_id = format!("{}{}", GLOBAL_POOL_PREFIX, uuid::Uuid::new_v4());
+            let subnet = self
+                .request_global_pool(&pool_id, req.pool, thresholds)
+                .await?;
+            tracing::info!("Global pool requested: {} -> {}", pool_id, subnet);
+            return Ok(RequestPoolResponse {
+                pool_id,
+                pool: subnet.to_string(),
+                data: HashMap::new(),
+            });
+        }
+
         let pool = req.pool.unwrap_or_else(|| self.default_subnet.clone());
-        let pool_id = format!("pool-{}", uuid::Uuid::new_v4());
+        let pool_id = format!("{}{}", LOCAL_POOL_PREFIX, uuid::Uuid::new_v4());
 
         // Validate the pool is a valid CIDR
-        pool.parse::<IpNetwork>().context("Invalid subnet format")?;
+        let subnet = parse_subnet(&pool)?;
+        self.check_overlap(space, &subnet).await?;
 
         // Store pool info
         let pool_info = PoolInfo {
             pool_id: pool_id.clone(),
-            subnet: pool.clone(),
+            subnet,
             gateway: None,
+            thresholds,
         };
 
         {
             let mut state = self.storage.write().await;
             state.pools.insert(pool_id.clone(), pool_info);
         }
-        self.storage.save().await?;
+        self.storage.commit().await?;
 
-        tracing::info!("Pool requested: {} -> {}", pool_id, pool);
+        tracing::info!("Pool requested: {} -> {}", pool_id, subnet);
 
         Ok(RequestPoolResponse {
             pool_id,
-            pool,
+            pool: subnet.to_string(),
             data: HashMap::new(),
         })
     }
 
     /// Handle ReleasePool request
     pub async fn release_pool(&self, req: ReleasePoolRequest) -> Result<()> {
+        let mut event = AuditEvent::new(Operation::ReleasePool);
+        event.pool_id = Some(req.pool_id.clone());
+        self.threshold_levels.lock().unwrap().remove(&req.pool_id);
+        let result = self.release_pool_inner(req).await;
+        if result.is_ok() {
+            self.events.publish(EventKind::PoolReleased {
+                pool_id: event.pool_id.clone().unwrap_or_default(),
+            });
+        }
+        self.audit(event, &result);
+        result
+    }
+
+    async fn release_pool_inner(&self, req: ReleasePoolRequest) -> Result<()> {
+        if let Some(coordinator) = self.coordinator_for(&req.pool_id) {
+            coordinator.release_pool(&req.pool_id).await?;
+            tracing::info!("Global pool released: {}", req.pool_id);
+            return Ok(());
+        }
+
         {
             let mut state = self.storage.write().await;
-            // Get the pool info before removing it
-            let pool_subnet = state.pools.get(&req.pool_id).map(|p| p.subnet.clone());
-            state.pools.remove(&req.pool_id);
-
             // Also remove all leases from this pool
-            if let Some(subnet) = pool_subnet {
-                if let Ok(network) = subnet.parse::<IpNetwork>() {
-                    state
-                        .leases
-                        .retain(|lease| !network.contains(lease.ip_address));
-                }
+            if let Some(pool) = state.pools.remove(&req.pool_id) {
+                let (released, kept) = std::mem::take(&mut state.leases)
+                    .into_iter()
+                    .partition(|lease| in_pool(lease, &pool));
+                state.leases = kept;
+                self.record_releases(&mut state, released);
             }
         }
-        self.storage.save().await?;
+        self.storage.commit().await?;
 
         tracing::info!("Pool released: {}", req.pool_id);
         Ok(())
@@ -87,34 +399,83 @@ impl IpamPlugin {
         &self,
         req: RequestAddressRequest,
     ) -> Result<RequestAddressResponse> {
-        let pool_info = {
-            let state = self.storage.read().await;
-            state
-                .pools
-                .get(&req.pool_id)
-                .cloned()
-                .ok_or_else(|| anyhow!("Pool not found: {}", req.pool_id))?
-        };
+        let mut event = AuditEvent::new(Operation::RequestAddress).options(req.options.as_ref());
+        event.pool_id = Some(req.pool_id.clone());
+        event.container = Some(container_name(req.options.as_ref()));
+        event.ip_address = req.address.as_deref().and_then(|a| parse_address(a).ok());
+        let pool_id = req.pool_id.clone();
+        self.watch_thresholds(&pool_id).await;
+        let result = self.request_address_inner(req).await;
+        if let Ok((lease, _)) = &result {
+            event.ip_address = Some(lease.ip_address);
+            self.events.publish(EventKind::LeaseCreated {
+                lease: lease.clone(),
+            });
+            self.check_thresholds(&pool_id).await;
+        }
+        self.audit(event, &result);
+        result.map(|(_, resp)| resp)
+    }
 
-        let network: IpNetwork = pool_info.subnet.parse().context("Invalid subnet in pool")?;
+    async fn request_address_inner(
+        &self,
+        req: RequestAddressRequest,
+    ) -> Result<(IpLease, RequestAddressResponse)> {
+        let coordinator = self.coordinator_for(&req.pool_id);
+        let pool_info = match coordinator {
+            Some(coordinator) => coordinator.get_pool(&req.pool_id).await?,
+            None => {
+                let state = self.storage.read().await;
+                state.pools.get(&req.pool_id).cloned()
+            }
+        }
+        .ok_or_else(|| anyhow!("Pool not found: {}", req.pool_id))?;
+
+        let network = pool_info.subnet;
+
+        let lease = lease_from_options(&req.pool_id, req.options.as_ref());
+        let container_name = lease.container_name.clone();
+
+        if let Some(coordinator) = coordinator {
+            let lease = match req.address {
+                Some(requested_addr) => {
+                    let ip_addr = requested_addr
+                        .parse::<IpAddr>()
+                        .context("Invalid IP address format")?;
+                    if !network.contains(ip_addr) {
+                        return Err(anyhow!(
+                            "IP address {} is not in subnet {}",
+                            ip_addr,
+                            network
+                        ));
+                    }
+                    let lease = IpLease {
+                        ip_address: ip_addr,
+                        ..lease
+                    };
+                    coordinator.claim_address(&req.pool_id, &lease).await?;
+                    lease
+                }
+                None => {
+                    coordinator
+                        .allocate_address(&req.pool_id, &network, lease)
+                        .await?
+                }
+            };
 
-        // Extract container name from options
-        let container_name = req
-            .options
-            .as_ref()
-            .and_then(|opts| opts.get("com.docker.network.endpoint.name"))
-            .or_else(|| {
-                req.options
-                    .as_ref()
-                    .and_then(|opts| opts.get("container_name"))
-            })
-            .or_else(|| {
-                req.options
-                    .as_ref()
-                    .and_then(|opts| opts.get("com.docker.network.container.id"))
-            })
-            .cloned()
-            .unwrap_or_else(|| "unknown".to_string());
+            let address_with_cidr = format!("{}/{}", lease.ip_address, network.prefix());
+            tracing::info!(
+                "Global address allocated: {} to container '{}' (pool: {})",
+                address_with_cidr,
+                container_name,
+                req.pool_id
+            );
+            let resp = RequestAddressResponse {
+                address: address_with_cidr,
+                data: HashMap::new(),
+            };
+            return Ok((lease, resp));
+        }
 
         // If a specific address is requested, use it
         let ip_addr = if let Some(requested_addr) = req.address {
@@ -138,18 +499,38 @@ impl IpamPlugin {
         // Create the lease
         let lease = IpLease {
             ip_address: ip_addr,
-            container_name: container_name.clone(),
-            lease_time: Utc::now(),
+            ..lease
         };
 
         // Store the lease
-        {
+        let lease = {
             let mut state = self.storage.write().await;
-            // Remove any existing lease for this IP
-            state.leases.retain(|l| l.ip_address != ip_addr);
-            state.leases.push(lease);
-        }
-        self.storage.save().await?;
+            // Only the container it is reserved for may take a reserved
+            // address
+            if let Some(reservation) = state
+                .leases
+                .iter()
+                .find(|l| l.ip_address == ip_addr && l.reserved && in_pool(l, &pool_info))
+            {
+                if !reservation.held_by(&lease) {
+                    return Err(AddressInUseError {
+                        ip: ip_addr,
+                        pool_id: req.pool_id.clone(),
+                    }
+                    .into());
+                }
+            }
+            // Remove any existing lease for this IP in this pool; overlapping
+            // pools keep theirs
+            let replaced = take_leases(&mut state, ip_addr, Some(&req.pool_id))?;
+            // A container asking for its reserved address keeps it pinned
+            let reserved = replaced.iter().any(|l| l.reserved);
+            self.record_releases(&mut state, replaced);
+            let lease = IpLease { reserved, ..lease };
+            state.leases.push(lease.clone());
+            lease
+        };
+        self.storage.commit().await?;
 
         let cidr_prefix = network.prefix();
         let address_with_cidr = format!("{}/{}", ip_addr, cidr_prefix);
@@ -161,63 +542,638 @@ impl IpamPlugin {
             req.pool_id
         );
 
-        Ok(RequestAddressResponse {
+        let resp = RequestAddressResponse {
             address: address_with_cidr,
             data: HashMap::new(),
-        })
+        };
+        Ok((lease, resp))
     }
 
     /// Handle ReleaseAddress request
     pub async fn release_address(&self, req: ReleaseAddressRequest) -> Result<()> {
-        // Parse the address (might have CIDR notation)
-        let ip_str = req.address.split('/').next().unwrap_or(&req.address);
-        let ip_addr: IpAddr = ip_str.parse().context("Invalid IP address format")?;
+        let mut event = AuditEvent::new(Operation::ReleaseAddress);
+        event.pool_id = Some(req.pool_id.clone());
+        event.ip_address = parse_address(&req.address).ok();
+        if let Some(ip) = event.ip_address {
+            let state = self.storage.read().await;
+            event.container = state
+                .leases
+                .iter()
+                .find(|l| l.ip_address == ip && in_pool_id(&state.pools, l, &req.pool_id))
+                .map(|l| l.container_name.clone());
+        }
+        let pool_id = req.pool_id.clone();
+        self.watch_thresholds(&pool_id).await;
+        let result = self.release_address_inner(req).await;
+        if let Ok(released) = &result {
+            if let Some(lease) = released {
+                self.events.publish(EventKind::LeaseReleased {
+                    lease: lease.clone(),
+                });
+            }
+            self.check_thresholds(&pool_id).await;
+        }
+        self.audit(event, &result);
+        result.map(|_| ())
+    }
 
-        {
-            let mut state = self.storage.write().await;
-            let initial_len = state.leases.len();
-            state.leases.retain(|lease| lease.ip_address != ip_addr);
-            let removed = initial_len - state.leases.len();
+    /// The lease that was released, if any
+    async fn release_address_inner(&self, req: ReleaseAddressRequest) -> Result<Option<IpLease>> {
+        let ip_addr = parse_address(&req.address)?;
+
+        if let Some(coordinator) = self.coordinator_for(&req.pool_id) {
+            let lease = coordinator.get_lease(&req.pool_id, ip_addr).await?;
+            if lease.as_ref().is_some_and(|l| l.reserved) {
+                tracing::info!("Global address {} is reserved, keeping it", ip_addr);
+                return Ok(None);
+            }
+            if coordinator.release_address(&req.pool_id, ip_addr).await? {
+                tracing::info!(
+                    "Global address released: {} (pool: {})",
+                    ip_addr,
+                    req.pool_id
+                );
+                return Ok(lease);
+            }
+            tracing::warn!("Global address not found for release: {}", ip_addr);
+            return Ok(None);
+        }
 
-            if removed > 0 {
+        let released = {
+            let mut state = self.storage.write().await;
+            let held = take_leases(&mut state, ip_addr, Some(&req.pool_id))?;
+            let (pinned, released): (Vec<_>, _) = held.into_iter().partition(|l| l.reserved);
+            let is_pinned = !pinned.is_empty();
+            state.leases.extend(pinned);
+            let lease = released.first().cloned();
+            self.record_releases(&mut state, released);
+
+            if lease.is_some() {
                 tracing::info!("Address released: {} (pool: {})", ip_addr, req.pool_id);
+            } else if is_pinned {
+                tracing::info!("Address {} is reserved, keeping it", ip_addr);
             } else {
                 tracing::warn!("Address not found for release: {}", ip_addr);
             }
+            lease
+        };
+        self.storage.commit().await?;
+
+        Ok(released)
+    }
+
+    /// Keep ended leases in the per-address history
+    ///
+    /// Only local leases come through here; global ones end in the
+    /// coordinator and have no history.
+    fn record_releases(&self, state: &mut IpamState, leases: Vec<IpLease>) {
+        let now = Utc::now();
+        for lease in leases {
+            history::record_release(state, lease, now, self.history_limit);
         }
-        self.storage.save().await?;
+    }
 
-        Ok(())
+    /// Reserve an address on behalf of an operator
+    ///
+    /// Without `address` the next free one is used. The lease is marked
+    /// reserved, so Docker releasing it leaves it pinned to its container;
+    /// only [`Self::force_release`] frees it.
+    pub async fn reserve_address(
+        &self,
+        pool_id: &str,
+        address: Option<IpAddr>,
+        container_name: Option<String>,
+        reason: &str,
+    ) -> Result<IpLease> {
+        let mut event = AuditEvent::new(Operation::ReserveAddress);
+        event.pool_id = Some(pool_id.to_string());
+        event.ip_address = address;
+        event.container = container_name.clone();
+        event.reason = Some(reason.to_string());
+        self.watch_thresholds(pool_id).await;
+        let result = self
+            .reserve_address_inner(pool_id, address, container_name)
+            .await;
+        if let Ok(lease) = &result {
+            event.ip_address = Some(lease.ip_address);
+            event.container = Some(lease.container_name.clone());
+            self.events.publish(EventKind::LeaseCreated {
+                lease: lease.clone(),
+            });
+            self.check_thresholds(pool_id).await;
+        }
+        self.audit(event, &result);
+        result
+    }
+
+    async fn reserve_address_inner(
+        &self,
+        pool_id: &str,
+        address: Option<IpAddr>,
+        container_name: Option<String>,
+    ) -> Result<IpLease> {
+        let (_, pool) = self
+            .pool(pool_id)
+            .await?
+            .ok_or_else(|| NotFoundError(format!("Pool not found: {}", pool_id)))?;
+        if let Some(ip) = address {
+            if !pool.subnet.contains(ip) {
+                return Err(anyhow!(
+                    "IP address {} is not in subnet {}",
+                    ip,
+                    pool.subnet
+                ));
+            }
+        }
+        let lease = IpLease {
+            pool_id: Some(pool_id.to_string()),
+            reserved: true,
+            ..IpLease::new(
+                IpAddr::from([0, 0, 0, 0]),
+                container_name.unwrap_or_else(|| "reserved".to_string()),
+                Utc::now(),
+            )
+        };
+
+        let lease = if let Some(coordinator) = self.coordinator_for(pool_id) {
+            match address {
+                Some(ip) => {
+                    let lease = IpLease {
+                        ip_address: ip,
+                        ..lease
+                    };
+                    coordinator.claim_address(pool_id, &lease).await?;
+                    lease
+                }
+                None => {
+                    coordinator
+                        .allocate_address(pool_id, &pool.subnet, lease)
+                        .await?
+                }
+            }
+        } else {
+            let lease = {
+                let mut state = self.storage.write().await;
+                let ip = match address {
+                    Some(ip)
+                        if state
+                            .leases
+                            .iter()
+                            .any(|l| l.ip_address == ip && in_pool(l, &pool)) =>
+                    {
+                        return Err(AddressInUseError {
+                            ip,
+                            pool_id: pool_id.to_string(),
+                        }
+                        .into());
+                    }
+                    Some(ip) => ip,
+                    None => next_free_ip(&state, &pool.subnet)?,
+                };
+                let lease = IpLease {
+                    ip_address: ip,
+                    ..lease
+                };
+                state.leases.push(lease.clone());
+                lease
+            };
+            self.storage.commit().await?;
+            lease
+        };
+
+        tracing::info!(
+            "Address reserved: {} for '{}' (pool: {})",
+            lease.ip_address,
+            lease.container_name,
+            pool_id
+        );
+        Ok(lease)
+    }
+
+    /// Release an address whether or not it is reserved, returning the lease
+    /// that held it
+    ///
+    /// Local pools may overlap, so `pool_id` must be given when the address
+    /// is leased in more than one of them.
+    pub async fn force_release(
+        &self,
+        ip: IpAddr,
+        pool_id: Option<&str>,
+        reason: &str,
+    ) -> Result<IpLease> {
+        let mut event = AuditEvent::new(Operation::ForceReleaseAddress);
+        event.ip_address = Some(ip);
+        event.pool_id = pool_id.map(str::to_string);
+        event.reason = Some(reason.to_string());
+        let result = self.force_release_inner(ip, pool_id).await;
+        if let Ok(lease) = &result {
+            event.pool_id = lease.pool_id.clone();
+            event.container = Some(lease.container_name.clone());
+            self.events.publish(EventKind::LeaseReleased {
+                lease: lease.clone(),
+            });
+            if let Some(pool_id) = &lease.pool_id {
+                self.check_thresholds(pool_id).await;
+            }
+        }
+        self.audit(event, &result);
+        result
+    }
+
+    async fn force_release_inner(&self, ip: IpAddr, pool_id: Option<&str>) -> Result<IpLease> {
+        let released = {
+            let mut state = self.storage.write().await;
+            let released = take_leases(&mut state, ip, pool_id)?;
+            let lease = released.first().cloned();
+            self.record_releases(&mut state, released);
+            lease
+        };
+        if let Some(lease) = released {
+            self.storage.commit().await?;
+            tracing::info!("Address force-released: {}", ip);
+            return Ok(lease);
+        }
+
+        if let Some((global_pool, lease)) = self.global_lease(ip).await? {
+            if pool_id.is_none_or(|id| id == global_pool)
+                && self
+                    .coordinator()?
+                    .release_address(&global_pool, ip)
+                    .await?
+            {
+                tracing::info!("Global address force-released: {}", ip);
+                return Ok(lease);
+            }
+        }
+        Err(NotFoundError(format!("No lease for {}", ip)).into())
+    }
+
+    /// Reassign a leased address to another container
+    ///
+    /// The old holder goes into the address history and the lease is pinned
+    /// like a reservation: the container that had the address still thinks
+    /// so, and Docker releasing it for that container must not take it from
+    /// the new one.
+    pub async fn move_lease(
+        &self,
+        ip: IpAddr,
+        pool_id: Option<&str>,
+        container_name: &str,
+        reason: &str,
+    ) -> Result<IpLease> {
+        let mut event = AuditEvent::new(Operation::MoveAddress);
+        event.ip_address = Some(ip);
+        event.pool_id = pool_id.map(str::to_string);
+        event.container = Some(container_name.to_string());
+        event.reason = Some(reason.to_string());
+        let result = self.move_lease_inner(ip, pool_id, container_name).await;
+        if let Ok((previous, lease)) = &result {
+            event.pool_id = lease.pool_id.clone();
+            self.events.publish(EventKind::LeaseMoved {
+                lease: lease.clone(),
+                previous_container: previous.container_name.clone(),
+            });
+        }
+        self.audit(event, &result);
+        result.map(|(_, lease)| lease)
+    }
+
+    /// The previous and the new lease
+    async fn move_lease_inner(
+        &self,
+        ip: IpAddr,
+        pool_id: Option<&str>,
+        container_name: &str,
+    ) -> Result<(IpLease, IpLease)> {
+        let moved = |old: &IpLease| IpLease {
+            pool_id: old.pool_id.clone(),
+            network_id: old.network_id.clone(),
+            reserved: true,
+            ..IpLease::new(ip, container_name, Utc::now())
+        };
+
+        let lease = {
+            let mut state = self.storage.write().await;
+            let mut taken = take_leases(&mut state, ip, pool_id)?;
+            match taken.pop() {
+                Some(old) => {
+                    let new = moved(&old);
+                    state.leases.extend(taken);
+                    state.leases.push(new.clone());
+                    self.record_releases(&mut state, vec![old.clone()]);
+                    Some((old, new))
+                }
+                None => None,
+            }
+        };
+        if let Some(moved) = lease {
+            self.storage.commit().await?;
+            tracing::info!("Address {} moved to '{}'", ip, container_name);
+            return Ok(moved);
+        }
+
+        if let Some((global_pool, old)) = self.global_lease(ip).await? {
+            let new = IpLease {
+                pool_id: Some(global_pool.clone()),
+                ..moved(&old)
+            };
+            if pool_id.is_none_or(|id| id == global_pool)
+                && self
+                    .coordinator()?
+                    .replace_lease(&global_pool, &new)
+                    .await?
+            {
+                tracing::info!("Global address {} moved to '{}'", ip, container_name);
+                return Ok((old, new));
+            }
+        }
+        Err(NotFoundError(format!("No lease for {}", ip)).into())
+    }
+
+    /// Replace the local state with a snapshot on behalf of an operator,
+    /// returning what the restore changed
+    ///
+    /// Takes the state write lock of the running plugin, so requests in
+    /// flight finish first and none see a half-restored state. Pools of the
+    /// `global` address space are not part of snapshots and stay as they are.
+    pub async fn restore_snapshot(&self, name: &str, reason: &str) -> Result<StateDiff> {
+        let mut event = AuditEvent::new(Operation::RestoreSnapshot);
+        event
+            .options
+            .insert("snapshot".to_string(), name.to_string());
+        event.reason = Some(reason.to_string());
+        let result = self.storage.restore_snapshot(name).await;
+        if result.is_ok() {
+            // Levels were recorded against the replaced pools
+            self.threshold_levels.lock().unwrap().clear();
+        }
+        self.audit(event, &result);
+        result
+    }
+
+    /// A pool with the utilization of its leases
+    async fn pool_utilization(&self, pool_id: &str) -> Result<Option<(PoolInfo, Utilization)>> {
+        let Some((_, pool)) = self.pool(pool_id).await? else {
+            return Ok(None);
+        };
+        let leases = self.pool_leases(&pool).await?;
+        let utilization = Utilization::of(&pool.subnet, &leases);
+        Ok(Some((pool, utilization)))
+    }
+
+    fn thresholds_of<'a>(&'a self, pool: &'a PoolInfo) -> &'a [f64] {
+        pool.thresholds.as_deref().unwrap_or(&self.thresholds)
+    }
+
+    /// Record the threshold level of a pool before a change to it, unless
+    /// already known against its current thresholds, so that
+    /// [`Self::check_thresholds`] has something to compare with
+    ///
+    /// A pool's thresholds change when the state is reloaded, restored or
+    /// imported, which makes the level recorded before meaningless.
+    async fn watch_thresholds(&self, pool_id: &str) {
+        let Ok(Some((pool, u))) = self.pool_utilization(pool_id).await else {
+            return;
+        };
+        let thresholds = self.thresholds_of(&pool);
+        let mut levels = self.threshold_levels.lock().unwrap();
+        if levels
+            .get(pool_id)
+            .is_some_and(|(known, _)| known == thresholds)
+        {
+            return;
+        }
+        let level = utilization::level(u.percent, thresholds);
+        levels.insert(pool_id.to_string(), (thresholds.to_vec(), level));
+    }
+
+    /// Warn about and publish every threshold a pool crossed since its level
+    /// was last recorded
+    async fn check_thresholds(&self, pool_id: &str) {
+        let (pool, u) = match self.pool_utilization(pool_id).await {
+            Ok(Some(found)) => found,
+            Ok(None) => return,
+            Err(e) => {
+                tracing::warn!("Cannot check utilization of pool {}: {:#}", pool_id, e);
+                return;
+            }
+        };
+        let thresholds = self.thresholds_of(&pool);
+        let level = utilization::level(u.percent, thresholds);
+        let previous = match self
+            .threshold_levels
+            .lock()
+            .unwrap()
+            .insert(pool_id.to_string(), (thresholds.to_vec(), level))
+        {
+            Some((known, previous)) if known == thresholds => previous,
+            // Nothing to compare with
+            _ => level,
+        };
+        let (crossed, direction) = if level > previous {
+            (&thresholds[previous..level], Direction::Rising)
+        } else {
+            (&thresholds[level..previous], Direction::Falling)
+        };
+        for &threshold in crossed {
+            tracing::warn!(
+                "Utilization of pool {} ({}) {} {}%: {} of {} addresses used ({:.1}%)",
+                pool_id,
+                pool.subnet,
+                direction,
+                threshold,
+                u.used,
+                u.total,
+                u.percent
+            );
+            self.events.publish(EventKind::ThresholdCrossed {
+                pool_id: pool_id.to_string(),
+                subnet: pool.subnet,
+                threshold,
+                percent: u.percent,
+                direction,
+            });
+        }
+    }
+
+    /// The global pool and lease holding `ip`, if any
+    async fn global_lease(&self, ip: IpAddr) -> Result<Option<(String, IpLease)>> {
+        let Some(coordinator) = &self.coordinator else {
+            return Ok(None);
+        };
+        for pool in coordinator.pools().await? {
+            if !pool.subnet.contains(ip) {
+                continue;
+            }
+            if let Some(lease) = coordinator.get_lease(&pool.pool_id, ip).await? {
+                return Ok(Some((pool.pool_id, lease)));
+            }
+        }
+        Ok(None)
     }
 
     /// Allocate the next available IP in the network
     async fn allocate_next_ip(&self, network: &IpNetwork) -> Result<IpAddr> {
         let state = self.storage.read().await;
+        next_free_ip(&state, network)
+    }
+}
 
-        // Get all allocated IPs
-        let allocated: std::collections::HashSet<IpAddr> = state
-            .leases
-            .iter()
-            .filter(|lease| network.contains(lease.ip_address))
-            .map(|lease| lease.ip_address)
-            .collect();
+/// The pool or lease an operation names does not exist
+#[derive(Debug)]
+pub struct NotFoundError(pub String);
 
-        // Find first available IP (skip network address and broadcast)
-        for ip in network.iter().skip(1) {
-            // Skip the last IP if it's IPv4 (broadcast)
-            if ip.is_ipv4() && ip == network.broadcast() {
-                continue;
-            }
+impl std::fmt::Display for NotFoundError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
 
-            if !allocated.contains(&ip) {
-                return Ok(ip);
-            }
+impl std::error::Error for NotFoundError {}
+
+/// An address is leased in several overlapping pools and no pool was named
+#[derive(Debug)]
+pub struct AmbiguousLeaseError {
+    pub ip: IpAddr,
+}
+
+impl std::fmt::Display for AmbiguousLeaseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} is leased in more than one pool; name the pool with pool_id",
+            self.ip
+        )
+    }
+}
+
+impl std::error::Error for AmbiguousLeaseError {}
+
+/// The first address of `network` that is not leased
+fn next_free_ip(state: &IpamState, network: &IpNetwork) -> Result<IpAddr> {
+    // Get all allocated IPs
+    let allocated: std::collections::HashSet<IpAddr> = state
+        .leases
+        .iter()
+        .filter(|lease| network.contains(lease.ip_address))
+        .map(|lease| lease.ip_address)
+        .collect();
+
+    // Find first available IP (skip network address and broadcast)
+    for ip in network.iter().skip(1) {
+        // Skip the last IP if it's IPv4 (broadcast)
+        if ip.is_ipv4() && ip == network.broadcast() {
+            continue;
+        }
+
+        if !allocated.contains(&ip) {
+            return Ok(ip);
         }
+    }
+
+    Err(ExhaustedError::new(network, &state.leases).into())
+}
+
+/// Whether a local lease belongs to `pool`
+///
+/// Local pools may overlap, so leases that know their pool are matched by
+/// ID rather than by address.
+fn in_pool(lease: &IpLease, pool: &PoolInfo) -> bool {
+    match &lease.pool_id {
+        Some(pool_id) => *pool_id == pool.pool_id,
+        None => pool.subnet.contains(lease.ip_address),
+    }
+}
 
-        Err(anyhow!("No available IP addresses in subnet {}", network))
+/// Whether a local lease belongs to the pool `pool_id`, which may already
+/// have been released
+fn in_pool_id(pools: &HashMap<String, PoolInfo>, lease: &IpLease, pool_id: &str) -> bool {
+    match pools.get(pool_id) {
+        Some(pool) => in_pool(lease, pool),
+        None => lease.pool_id.as_deref() == Some(pool_id),
     }
 }
 
+/// Take the local leases of `ip` out of the state, only those in `pool_id`
+/// when given
+///
+/// Without a pool, an address leased in more than one overlapping pool is
+/// ambiguous and nothing is taken.
+fn take_leases(state: &mut IpamState, ip: IpAddr, pool_id: Option<&str>) -> Result<Vec<IpLease>> {
+    let (taken, kept): (Vec<_>, _) = std::mem::take(&mut state.leases)
+        .into_iter()
+        .partition(|l| {
+            l.ip_address == ip && pool_id.is_none_or(|id| in_pool_id(&state.pools, l, id))
+        });
+    state.leases = kept;
+    if pool_id.is_none() && taken.iter().any(|l| l.pool_id != taken[0].pool_id) {
+        state.leases.extend(taken);
+        return Err(AmbiguousLeaseError { ip }.into());
+    }
+    Ok(taken)
+}
+
+/// Request option keys for each lease field, in order of preference
+const CONTAINER_NAME_KEYS: &[&str] = &["com.docker.network.endpoint.name", "container_name"];
+const ENDPOINT_ID_KEYS: &[&str] = &["com.docker.network.endpoint.id", "endpoint_id"];
+const NETWORK_ID_KEYS: &[&str] = &["com.docker.network.id", "network_id"];
+const CONTAINER_ID_KEYS: &[&str] = &["com.docker.network.container.id", "container_id"];
+const HOSTNAME_KEYS: &[&str] = &["com.docker.network.endpoint.hostname", "hostname"];
+
+/// A lease in `pool_id` described by the request options, with its address
+/// still unset
+///
+/// Options that fill a field are consumed; everything else is kept as a
+/// label. Without a name the container is identified by its hostname or ID.
+fn lease_from_options(pool_id: &str, options: Option<&HashMap<String, String>>) -> IpLease {
+    let mut labels: BTreeMap<String, String> = options
+        .into_iter()
+        .flatten()
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect();
+    let mut take = |keys: &[&str]| {
+        let values: Vec<_> = keys.iter().filter_map(|key| labels.remove(*key)).collect();
+        values.into_iter().find(|v| !v.is_empty())
+    };
+
+    let name = take(CONTAINER_NAME_KEYS);
+    let endpoint_id = take(ENDPOINT_ID_KEYS);
+    let network_id = take(NETWORK_ID_KEYS);
+    let container_id = take(CONTAINER_ID_KEYS);
+    let hostname = take(HOSTNAME_KEYS);
+    let container_name = name
+        .or_else(|| hostname.clone())
+        .or_else(|| container_id.clone())
+        .unwrap_or_else(|| "unknown".to_string());
+
+    IpLease {
+        pool_id: Some(pool_id.to_string()),
+        endpoint_id,
+        network_id,
+        container_id,
+        hostname,
+        labels,
+        ..IpLease::new(IpAddr::from([0, 0, 0, 0]), container_name, Utc::now())
+    }
+}
+
+/// Name of the container an address is requested for
+fn container_name(options: Option<&HashMap<String, String>>) -> String {
+    lease_from_options("", options).container_name
+}
+
+/// Parse a requested subnet, normalized to its network address
+pub(crate) fn parse_subnet(subnet: &str) -> Result<IpNetwork> {
+    let network: IpNetwork = subnet.parse().context("Invalid subnet format")?;
+    Ok(IpNetwork::new(network.network(), network.prefix())?)
+}
+
+/// Parse an address that might have CIDR notation
+fn parse_address(address: &str) -> Result<IpAddr> {
+    let ip_str = address.split('/').next().unwrap_or(address);
+    ip_str.parse().context("Invalid IP address format")
+}
+
 // UUID generation helper (simple implementation)
 mod uuid {
     use std::fmt;
@@ -272,6 +1228,7 @@ mod tests {
             sub_pool: None,
             options: None,
             v6: None,
+            address_space: None,
         };
 
         let response = plugin.request_pool(req).await.unwrap();
@@ -287,6 +1244,7 @@ mod tests {
             sub_pool: None,
             options: None,
             v6: None,
+            address_space: None,
         };
 
         let response = plugin.request_pool(req).await.unwrap();
@@ -303,6 +1261,7 @@ mod tests {
             sub_pool: None,
             options: None,
             v6: None,
+            address_space: None,
         };
         let pool_response = plugin.request_pool(pool_req).await.unwrap();
 
@@ -338,6 +1297,7 @@ mod tests {
             sub_pool: None,
             options: None,
             v6: None,
+            address_space: None,
         };
         let pool_response = plugin.request_pool(pool_req).await.unwrap();
 
@@ -362,6 +1322,7 @@ mod tests {
             sub_pool: None,
             options: None,
             v6: None,
+            address_space: None,
         };
         let pool_response = plugin.request_pool(pool_req).await.unwrap();
 
@@ -398,6 +1359,7 @@ mod tests {
             sub_pool: None,
             options: None,
             v6: None,
+            address_space: None,
         };
         let pool_response = plugin.request_pool(pool_req).await.unwrap();
 
@@ -416,6 +1378,155 @@ mod tests {
         plugin.release_pool(release_pool_req).await.unwrap();
     }
 
+    #[tokio::test]
+    async fn test_lease_records_endpoint_metadata() {
+        let (plugin, _temp) = create_test_plugin().await;
+        let pool_id = plugin
+            .request_pool(pool_request(Some("10.45.0.0/24"), None))
+            .await
+            .unwrap()
+            .pool_id;
+
+        let options: HashMap<String, String> = [
+            ("com.docker.network.endpoint.id", "ep-123"),
+            ("com.docker.network.id", "net-456"),
+            ("com.docker.network.container.id", "3f4e9a"),
+            ("hostname", "web-1"),
+            ("com.example.team", "payments"),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+        plugin
+            .request_address(RequestAddressRequest {
+                pool_id: pool_id.clone(),
+                address: None,
+                options: Some(options),
+            })
+            .await
+            .unwrap();
+
+        let state = plugin.storage.read().await;
+        let lease = &state.leases[0];
+        // Without a name the hostname identifies the container
+        assert_eq!(lease.container_name, "web-1");
+        assert_eq!(lease.pool_id.as_deref(), Some(pool_id.as_str()));
+        assert_eq!(lease.endpoint_id.as_deref(), Some("ep-123"));
+        assert_eq!(lease.network_id.as_deref(), Some("net-456"));
+        assert_eq!(lease.container_id.as_deref(), Some("3f4e9a"));
+        assert_eq!(lease.hostname.as_deref(), Some("web-1"));
+        assert_eq!(
+            lease.labels.iter().collect::<Vec<_>>(),
+            vec![(&"com.example.team".to_string(), &"payments".to_string())]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_release_pool_keeps_overlapping_pool_leases() {
+        let (plugin, _temp) = create_test_plugin().await;
+        let outer = plugin
+            .request_pool(pool_request(Some("10.46.0.0/16"), None))
+            .await
+            .unwrap()
+            .pool_id;
+        let inner = plugin
+            .request_pool(pool_request(Some("10.46.1.0/24"), None))
+            .await
+            .unwrap()
+            .pool_id;
+        for (pool_id, address) in [(&outer, "10.46.1.10"), (&inner, "10.46.1.20")] {
+            plugin
+                .request_address(RequestAddressRequest {
+                    pool_id: pool_id.clone(),
+                    address: Some(address.to_string()),
+                    options: None,
+                })
+                .await
+                .unwrap();
+        }
+
+        plugin
+            .release_pool(ReleasePoolRequest { pool_id: outer })
+            .await
+            .unwrap();
+
+        let state = plugin.storage.read().await;
+        assert_eq!(state.leases.len(), 1);
+        assert_eq!(state.leases[0].ip_address.to_string(), "10.46.1.20");
+    }
+
+    #[tokio::test]
+    async fn test_release_address_keeps_overlapping_pool_lease() {
+        let (plugin, _temp) = create_test_plugin().await;
+        let outer = plugin
+            .request_pool(pool_request(Some("10.47.0.0/16"), None))
+            .await
+            .unwrap()
+            .pool_id;
+        let inner = plugin
+            .request_pool(pool_request(Some("10.47.1.0/24"), None))
+            .await
+            .unwrap()
+            .pool_id;
+        for (pool_id, container) in [(&outer, "outer"), (&inner, "inner")] {
+            plugin
+                .request_address(RequestAddressRequest {
+                    pool_id: pool_id.clone(),
+                    address: Some("10.47.1.10".to_string()),
+                    options: Some(HashMap::from([(
+                        "container_name".to_string(),
+                        container.to_string(),
+                    )])),
+                })
+                .await
+                .unwrap();
+        }
+        {
+            let state = plugin.storage.read().await;
+            assert_eq!(state.leases.len(), 2);
+            assert!(crate::validate::validate(&state).is_empty());
+        }
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+
+        // Without a pool the address is ambiguous
+        let e = plugin
+            .force_release(ip("10.47.1.10"), None, "cleanup")
+            .await
+            .unwrap_err();
+        assert!(e.is::<AmbiguousLeaseError>());
+        let e = plugin
+            .move_lease(ip("10.47.1.10"), None, "other", "rename")
+            .await
+            .unwrap_err();
+        assert!(e.is::<AmbiguousLeaseError>());
+        assert_eq!(plugin.storage.read().await.leases.len(), 2);
+
+        plugin
+            .release_address(ReleaseAddressRequest {
+                pool_id: outer.clone(),
+                address: "10.47.1.10".to_string(),
+            })
+            .await
+            .unwrap();
+        {
+            let state = plugin.storage.read().await;
+            assert_eq!(state.leases.len(), 1);
+            assert_eq!(state.leases[0].container_name, "inner");
+        }
+
+        let moved = plugin
+            .move_lease(ip("10.47.1.10"), Some(&outer), "other", "rename")
+            .await
+            .unwrap_err();
+        assert!(moved.is::<NotFoundError>());
+        let released = plugin
+            .force_release(ip("10.47.1.10"), Some(&inner), "cleanup")
+            .await
+            .unwrap();
+        assert_eq!(released.container_name, "inner");
+        assert!(plugin.storage.read().await.leases.is_empty());
+    }
+
     #[tokio::test]
     async fn test_allocate_next_ip() {
         let (plugin, _temp) = create_test_plugin().await;
@@ -428,11 +1539,7 @@ mod tests {
         // Manually add a lease to simulate allocation
         {
             let mut state = plugin.storage.write().await;
-            state.leases.push(IpLease {
-                ip_address: ip1,
-                container_name: "test".to_string(),
-                lease_time: Utc::now(),
-            });
+            state.leases.push(IpLease::new(ip1, "test", Utc::now()));
         }
 
         // Allocate second IP
@@ -442,11 +1549,7 @@ mod tests {
         // Manually add second lease
         {
             let mut state = plugin.storage.write().await;
-            state.leases.push(IpLease {
-                ip_address: ip2,
-                container_name: "test2".to_string(),
-                lease_time: Utc::now(),
-            });
+            state.leases.push(IpLease::new(ip2, "test2", Utc::now()));
         }
 
         // Try to allocate third IP (should fail - no more IPs)
@@ -463,6 +1566,7 @@ mod tests {
             sub_pool: None,
             options: None,
             v6: None,
+            address_space: None,
         };
 
         let result = plugin.request_pool(req).await;
@@ -493,6 +1597,7 @@ mod tests {
             sub_pool: None,
             options: None,
             v6: None,
+            address_space: None,
         };
         let pool_resp = plugin.request_pool(pool_req).await.unwrap();
 
@@ -524,6 +1629,635 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[tokio::test]
+    async fn test_global_pool_is_shared_between_hosts() {
+        use crate::global::KvCoordinator;
+        use crate::kv::{KvStore, MemoryKv};
+
+        let kv: Arc<dyn KvStore> = Arc::new(MemoryKv::new());
+        let (host_a, _temp_a) = create_test_plugin().await;
+        let (host_b, _temp_b) = create_test_plugin().await;
+        let host_a = host_a.with_coordinator(Arc::new(KvCoordinator::new(kv.clone(), "/ipam")));
+        let host_b = host_b.with_coordinator(Arc::new(KvCoordinator::new(kv, "/ipam")));
+
+        let pool = host_a
+            .request_pool(RequestPoolRequest {
+                pool: Some("10.60.0.0/24".to_string()),
+                sub_pool: None,
+                options: None,
+                v6: None,
+                address_space: Some("global".to_string()),
+            })
+            .await
+            .unwrap();
+        assert!(pool.pool_id.starts_with(GLOBAL_POOL_PREFIX));
+        // Global pools never touch the local state file
+        assert!(host_a.storage.read().await.pools.is_empty());
+
+        let request = |address: Option<&str>| RequestAddressRequest {
+            pool_id: pool.pool_id.clone(),
+            address: address.map(str::to_string),
+            options: None,
+        };
+        let a = host_a.request_address(request(None)).await.unwrap();
+        let b = host_b.request_address(request(None)).await.unwrap();
+        assert_eq!(a.address, "10.60.0.1/24");
+        assert_eq!(b.address, "10.60.0.2/24");
+
+        // A specific address held by another host is refused
+        assert!(host_b
+            .request_address(request(Some("10.60.0.1")))
+            .await
+            .is_err());
+
+        host_a
+            .release_address(ReleaseAddressRequest {
+                pool_id: pool.pool_id.clone(),
+                address: a.address.clone(),
+            })
+            .await
+            .unwrap();
+        host_b
+            .request_address(request(Some("10.60.0.1")))
+            .await
+            .unwrap();
+
+        host_b
+            .release_pool(ReleasePoolRequest {
+                pool_id: pool.pool_id.clone(),
+            })
+            .await
+            .unwrap();
+        assert!(host_a.request_address(request(None)).await.is_err());
+    }
+
+    fn pool_request(pool: Option<&str>, space: Option<&str>) -> RequestPoolRequest {
+        RequestPoolRequest {
+            pool: pool.map(str::to_string),
+            sub_pool: None,
+            options: None,
+            v6: None,
+            address_space: space.map(str::to_string),
+        }
+    }
+
+    async fn create_global_test_plugin() -> (IpamPlugin, TempDir) {
+        use crate::global::KvCoordinator;
+        use crate::kv::MemoryKv;
+
+        let (plugin, temp) = create_test_plugin().await;
+        let coordinator = KvCoordinator::new(Arc::new(MemoryKv::new()), "/ipam");
+        (plugin.with_coordinator(Arc::new(coordinator)), temp)
+    }
+
+    #[tokio::test]
+    async fn test_address_space_is_validated() {
+        let (plugin, _temp) = create_test_plugin().await;
+        assert!(plugin
+            .request_pool(pool_request(Some("10.61.0.0/24"), Some("local")))
+            .await
+            .is_ok());
+        assert!(plugin
+            .request_pool(pool_request(Some("10.62.0.0/24"), Some("elsewhere")))
+            .await
+            .is_err());
+        // The global space never silently falls back to the state file
+        let err = plugin
+            .request_pool(pool_request(Some("10.63.0.0/24"), Some("global")))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("coordinator"));
+        assert_eq!(plugin.storage.read().await.pools.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_overlap_rules_per_address_space() {
+        let (plugin, _temp) = create_global_test_plugin().await;
+
+        // Local pools may overlap each other
+        plugin
+            .request_pool(pool_request(Some("10.70.0.0/16"), None))
+            .await
+            .unwrap();
+        plugin
+            .request_pool(pool_request(Some("10.70.1.0/24"), None))
+            .await
+            .unwrap();
+
+        // Global pools may not overlap local ones...
+        let err = plugin
+            .request_pool(pool_request(Some("10.70.2.0/24"), Some("global")))
+            .await
+            .unwrap_err();
+        assert!(err.is::<OverlapError>());
+
+        // ...nor each other
+        plugin
+            .request_pool(pool_request(Some("10.80.0.0/16"), Some("global")))
+            .await
+            .unwrap();
+        assert!(plugin
+            .request_pool(pool_request(Some("10.80.3.0/24"), Some("global")))
+            .await
+            .is_err());
+
+        // and local pools may not overlap global ones
+        assert!(plugin
+            .request_pool(pool_request(Some("10.80.4.0/24"), None))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_default_pools_per_address_space() {
+        let (plugin, _temp) = create_global_test_plugin().await;
+        let plugin = plugin.with_global_default_pools(vec!["10.90.0.0/23:24".parse().unwrap()]);
+
+        // The local space hands out the default subnet every time
+        for _ in 0..2 {
+            let resp = plugin.request_pool(pool_request(None, None)).await.unwrap();
+            assert_eq!(resp.pool, "10.10.0.0/24");
+        }
+
+        // The global space carves a fresh subnet per request
+        let first = plugin
+            .request_pool(pool_request(None, Some("global")))
+            .await
+            .unwrap();
+        let second = plugin
+            .request_pool(pool_request(None, Some("global")))
+            .await
+            .unwrap();
+        assert_eq!(first.pool, "10.90.0.0/24");
+        assert_eq!(second.pool, "10.90.1.0/24");
+        assert!(plugin
+            .request_pool(pool_request(None, Some("global")))
+            .await
+            .is_err());
+
+        plugin
+            .release_pool(ReleasePoolRequest {
+                pool_id: first.pool_id,
+            })
+            .await
+            .unwrap();
+        let again = plugin
+            .request_pool(pool_request(None, Some("global")))
+            .await
+            .unwrap();
+        assert_eq!(again.pool, "10.90.0.0/24");
+    }
+
+    #[tokio::test]
+    async fn test_operations_are_audited() {
+        use crate::audit::{AuditFilter, AuditLog};
+
+        let (plugin, temp) = create_test_plugin().await;
+        let audit = Arc::new(AuditLog::new(temp.path().join("audit.jsonl"), 1 << 20, 1));
+        let plugin = plugin.with_audit_log(audit.clone());
+
+        let pool = plugin
+            .request_pool(pool_request(Some("10.65.0.0/24"), None))
+            .await
+            .unwrap();
+        let mut options = HashMap::new();
+        options.insert("container_name".to_string(), "web".to_string());
+        let request = |address: &str| RequestAddressRequest {
+            pool_id: pool.pool_id.clone(),
+            address: Some(address.to_string()),
+            options: Some(options.clone()),
+        };
+        plugin.request_address(request("10.65.0.7")).await.unwrap();
+        assert!(plugin.request_address(request("10.66.0.7")).await.is_err());
+        plugin
+            .release_address(ReleaseAddressRequest {
+                pool_id: pool.pool_id.clone(),
+                address: "10.65.0.7/24".to_string(),
+            })
+            .await
+            .unwrap();
+
+        let events = audit.query(&AuditFilter::default()).unwrap();
+        let operations: Vec<_> = events.iter().map(|e| (e.operation, e.success)).collect();
+        assert_eq!(
+            operations,
+            vec![
+                (Operation::RequestPool, true),
+                (Operation::RequestAddress, true),
+                (Operation::RequestAddress, false),
+                (Operation::ReleaseAddress, true),
+            ]
+        );
+        assert_eq!(events[0].pool_id.as_ref(), Some(&pool.pool_id));
+        assert_eq!(events[1].options["container_name"], "web");
+        assert!(events[2].error.as_ref().unwrap().contains("not in subnet"));
+
+        let by_ip = audit
+            .query(&AuditFilter {
+                ip_address: Some("10.65.0.7".parse().unwrap()),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(by_ip.len(), 2);
+        assert!(by_ip.iter().all(|e| e.container.as_deref() == Some("web")));
+    }
+
+    #[tokio::test]
+    async fn test_reserved_and_moved_addresses_are_pinned() {
+        use crate::audit::{AuditFilter, AuditLog};
+
+        let (plugin, temp) = create_test_plugin().await;
+        let audit = Arc::new(AuditLog::new(temp.path().join("audit.jsonl"), 1 << 20, 1));
+        let plugin = plugin.with_audit_log(audit.clone());
+        let pool_id = plugin
+            .request_pool(pool_request(Some("10.67.0.0/24"), None))
+            .await
+            .unwrap()
+            .pool_id;
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        let docker_release = |address: &str| ReleaseAddressRequest {
+            pool_id: pool_id.clone(),
+            address: address.to_string(),
+        };
+
+        // Reservations are skipped by allocation and survive Docker releases
+        let reserved = plugin
+            .reserve_address(&pool_id, None, Some("vm".into()), "static host")
+            .await
+            .unwrap();
+        assert_eq!(reserved.ip_address, ip("10.67.0.1"));
+        let allocated = plugin
+            .request_address(RequestAddressRequest {
+                pool_id: pool_id.clone(),
+                address: None,
+                options: None,
+            })
+            .await
+            .unwrap();
+        assert_eq!(allocated.address, "10.67.0.2/24");
+        plugin
+            .release_address(docker_release("10.67.0.1"))
+            .await
+            .unwrap();
+        assert!(plugin
+            .reserve_address(&pool_id, Some(ip("10.67.0.1")), None, "dup")
+            .await
+            .unwrap_err()
+            .is::<AddressInUseError>());
+
+        // A moved lease keeps the address when Docker releases it for the
+        // container that had it before
+        let moved = plugin
+            .move_lease(ip("10.67.0.2"), None, "api", "rename")
+            .await
+            .unwrap();
+        assert!(moved.reserved);
+        plugin
+            .release_address(docker_release("10.67.0.2"))
+            .await
+            .unwrap();
+        {
+            let state = plugin.storage.read().await;
+            assert_eq!(state.leases.len(), 2);
+            assert_eq!(state.history[&ip("10.67.0.2")][0].container_name, "unknown");
+        }
+
+        plugin
+            .force_release(ip("10.67.0.1"), None, "vm retired")
+            .await
+            .unwrap();
+        assert!(plugin
+            .force_release(ip("10.67.0.1"), None, "again")
+            .await
+            .unwrap_err()
+            .is::<NotFoundError>());
+        assert_eq!(plugin.storage.read().await.leases.len(), 1);
+
+        let reasons: Vec<_> = audit
+            .query(&AuditFilter::default())
+            .unwrap()
+            .into_iter()
+            .filter_map(|e| e.reason.map(|r| (e.operation, r, e.success)))
+            .collect();
+        assert_eq!(
+            reasons,
+            vec![
+                (Operation::ReserveAddress, "static host".to_string(), true),
+                (Operation::ReserveAddress, "dup".to_string(), false),
+                (Operation::MoveAddress, "rename".to_string(), true),
+                (
+                    Operation::ForceReleaseAddress,
+                    "vm retired".to_string(),
+                    true
+                ),
+                (Operation::ForceReleaseAddress, "again".to_string(), false),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reserved_address_is_only_given_to_its_container() {
+        let (plugin, _temp) = create_test_plugin().await;
+        let pool_id = plugin
+            .request_pool(pool_request(Some("10.71.0.0/24"), None))
+            .await
+            .unwrap()
+            .pool_id;
+        plugin
+            .reserve_address(
+                &pool_id,
+                Some("10.71.0.5".parse().unwrap()),
+                Some("vm".into()),
+                "static host",
+            )
+            .await
+            .unwrap();
+        let request = |container: &str| RequestAddressRequest {
+            pool_id: pool_id.clone(),
+            address: Some("10.71.0.5".to_string()),
+            options: Some(HashMap::from([(
+                "container_name".to_string(),
+                container.to_string(),
+            )])),
+        };
+
+        let e = plugin
+            .request_address(request("intruder"))
+            .await
+            .unwrap_err();
+        assert!(e.is::<AddressInUseError>());
+        {
+            let state = plugin.storage.read().await;
+            assert_eq!(state.leases.len(), 1);
+            assert_eq!(state.leases[0].container_name, "vm");
+        }
+
+        let allocated = plugin.request_address(request("vm")).await.unwrap();
+        assert_eq!(allocated.address, "10.71.0.5/24");
+        let state = plugin.storage.read().await;
+        assert_eq!(state.leases.len(), 1);
+        assert!(state.leases[0].reserved);
+    }
+
+    #[tokio::test]
+    async fn test_reserve_address_in_overlapping_pool() {
+        let (plugin, _temp) = create_test_plugin().await;
+        let outer = plugin
+            .request_pool(pool_request(Some("10.72.0.0/16"), None))
+            .await
+            .unwrap()
+            .pool_id;
+        let inner = plugin
+            .request_pool(pool_request(Some("10.72.1.0/24"), None))
+            .await
+            .unwrap()
+            .pool_id;
+        let ip: IpAddr = "10.72.1.10".parse().unwrap();
+        plugin
+            .reserve_address(&outer, Some(ip), Some("a".into()), "static host")
+            .await
+            .unwrap();
+
+        // The outer pool's lease does not hold the address in the inner one
+        plugin
+            .reserve_address(&inner, Some(ip), Some("b".into()), "static host")
+            .await
+            .unwrap();
+        let e = plugin
+            .reserve_address(&inner, Some(ip), Some("c".into()), "static host")
+            .await
+            .unwrap_err();
+        assert!(e.is::<AddressInUseError>());
+        let state = plugin.storage.read().await;
+        assert_eq!(state.leases.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_thresholds_changed_in_the_state_file() {
+        let (plugin, _temp) = create_test_plugin().await;
+        let mut events = plugin.events().subscribe();
+        let pool_id = plugin
+            .request_pool(RequestPoolRequest {
+                options: Some(HashMap::from([(
+                    "thresholds".to_string(),
+                    "10,20,30".to_string(),
+                )])),
+                ..pool_request(Some("10.70.0.0/29"), None)
+            })
+            .await
+            .unwrap()
+            .pool_id;
+        let allocate = || RequestAddressRequest {
+            pool_id: pool_id.clone(),
+            address: None,
+            options: None,
+        };
+        for _ in 0..2 {
+            plugin.request_address(allocate()).await.unwrap();
+        }
+
+        // An operator edit leaves the pool with fewer thresholds than it had
+        // reached
+        {
+            let mut state = plugin.storage.write().await;
+            state.pools.get_mut(&pool_id).unwrap().thresholds = Some(vec![90.0]);
+        }
+        plugin.request_address(allocate()).await.unwrap();
+        for _ in 0..3 {
+            plugin.request_address(allocate()).await.unwrap();
+        }
+
+        let mut crossed = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            if let EventKind::ThresholdCrossed {
+                threshold,
+                direction,
+                ..
+            } = event.kind
+            {
+                crossed.push((threshold, direction));
+            }
+        }
+        assert_eq!(
+            crossed,
+            vec![
+                (10.0, Direction::Rising),
+                (20.0, Direction::Rising),
+                (30.0, Direction::Rising),
+                (90.0, Direction::Rising),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_threshold_crossings_are_published() {
+        use crate::events::Event;
+
+        let (plugin, _temp) = create_test_plugin().await;
+        let plugin = plugin.with_thresholds(vec![50.0]);
+        let mut events = plugin.events().subscribe();
+        // 6 usable addresses, warning at a third and two thirds
+        let pool_id = plugin
+            .request_pool(RequestPoolRequest {
+                options: Some(HashMap::from([(
+                    "thresholds".to_string(),
+                    "66,33".to_string(),
+                )])),
+                ..pool_request(Some("10.68.0.0/29"), None)
+            })
+            .await
+            .unwrap()
+            .pool_id;
+        for _ in 0..4 {
+            plugin
+                .request_address(RequestAddressRequest {
+                    pool_id: pool_id.clone(),
+                    address: None,
+                    options: None,
+                })
+                .await
+                .unwrap();
+        }
+        plugin
+            .release_address(ReleaseAddressRequest {
+                pool_id: pool_id.clone(),
+                address: "10.68.0.1".to_string(),
+            })
+            .await
+            .unwrap();
+
+        let mut crossings = || {
+            let mut crossed = Vec::new();
+            while let Ok(Event { kind, .. }) = events.try_recv() {
+                if let EventKind::ThresholdCrossed {
+                    pool_id,
+                    threshold,
+                    direction,
+                    ..
+                } = kind
+                {
+                    crossed.push((pool_id, threshold, direction));
+                }
+            }
+            crossed
+        };
+        let crossed: Vec<_> = crossings()
+            .into_iter()
+            .map(|(id, threshold, direction)| {
+                assert_eq!(id, pool_id);
+                (threshold, direction)
+            })
+            .collect();
+        assert_eq!(
+            crossed,
+            vec![
+                (33.0, Direction::Rising),
+                (66.0, Direction::Rising),
+                (66.0, Direction::Falling),
+            ]
+        );
+
+        // Pools without their own thresholds use the plugin's
+        let pool_id = plugin
+            .request_pool(pool_request(Some("10.69.0.0/30"), None))
+            .await
+            .unwrap()
+            .pool_id;
+        plugin
+            .request_address(RequestAddressRequest {
+                pool_id: pool_id.clone(),
+                address: None,
+                options: None,
+            })
+            .await
+            .unwrap();
+        assert_eq!(crossings(), vec![(pool_id, 50.0, Direction::Rising)]);
+
+        let invalid = plugin
+            .request_pool(RequestPoolRequest {
+                options: Some(HashMap::from([(
+                    "thresholds".to_string(),
+                    "lots".to_string(),
+                )])),
+                ..pool_request(Some("10.70.0.0/24"), None)
+            })
+            .await;
+        assert!(invalid.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_exhausted_pool_reports_holders() {
+        let (plugin, _temp) = create_test_plugin().await;
+        let pool_id = plugin
+            .request_pool(pool_request(Some("10.71.0.0/30"), None))
+            .await
+            .unwrap()
+            .pool_id;
+        let request = || RequestAddressRequest {
+            pool_id: pool_id.clone(),
+            address: None,
+            options: Some(HashMap::from([(
+                "container_name".to_string(),
+                "scaler".to_string(),
+            )])),
+        };
+        for _ in 0..2 {
+            plugin.request_address(request()).await.unwrap();
+        }
+
+        let e = plugin.request_address(request()).await.unwrap_err();
+        assert!(e.is::<ExhaustedError>());
+        assert_eq!(
+            e.to_string(),
+            "No available IP addresses in subnet 10.71.0.0/30 (2 of 2 used, 0 reserved; \
+             top holders: scaler (2))"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_released_leases_are_kept_in_history() {
+        let (plugin, _temp) = create_test_plugin().await;
+        let plugin = plugin.with_lease_history(2);
+        let pool = plugin
+            .request_pool(pool_request(Some("10.67.0.0/24"), None))
+            .await
+            .unwrap();
+        let ip: IpAddr = "10.67.0.9".parse().unwrap();
+
+        for name in ["a", "b", "c"] {
+            let mut options = HashMap::new();
+            options.insert("container_name".to_string(), name.to_string());
+            plugin
+                .request_address(RequestAddressRequest {
+                    pool_id: pool.pool_id.clone(),
+                    address: Some(ip.to_string()),
+                    options: Some(options),
+                })
+                .await
+                .unwrap();
+            plugin
+                .release_address(ReleaseAddressRequest {
+                    pool_id: pool.pool_id.clone(),
+                    address: format!("{}/24", ip),
+                })
+                .await
+                .unwrap();
+        }
+
+        let state = plugin.storage.read().await;
+        let holders: Vec<_> = history::holders(&state, ip, None)
+            .into_iter()
+            .map(|r| r.container_name)
+            .collect();
+        assert_eq!(holders, vec!["c", "b"]);
+        assert!(state.history[&ip].iter().all(|r| r.release_time.is_some()));
+
+        // History survives a trip through the state file format
+        let yaml = serde_yaml::to_string(&*state).unwrap();
+        let reloaded: IpamState = serde_yaml::from_str(&yaml).unwrap();
+        assert_eq!(reloaded.history, state.history);
+    }
+
     #[tokio::test]
     async fn test_ipv6_pool_creation() {
         let (plugin, _temp) = create_test_plugin().await;
@@ -534,6 +2268,7 @@ mod tests {
             sub_pool: None,
             options: None,
             v6: Some(true),
+            address_space: None,
         };
         let pool_resp = plugin.request_pool(pool_req).await.unwrap();
         assert_eq!(pool_resp.pool, "2001:db8::/32");
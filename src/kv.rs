@@ -0,0 +1,563 @@
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use hyper::{Body, Client, Method, Request};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+/// A value stored in the KV store together with the revision it was last
+/// modified at
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KvEntry {
+    pub value: String,
+    pub revision: u64,
+}
+
+/// The subset of an etcd-style KV store needed to coordinate allocations
+/// between hosts
+///
+/// All writes are conditional on the key's revision, so two hosts racing
+/// for the same key cannot both succeed.
+#[async_trait]
+pub trait KvStore: Send + Sync {
+    async fn get(&self, key: &str) -> Result<Option<KvEntry>>;
+
+    /// All keys starting with `prefix`, in key order
+    async fn list(&self, prefix: &str) -> Result<Vec<(String, KvEntry)>>;
+
+    /// Write `value` if the key is currently at `expected` revision, or
+    /// absent when `expected` is `None`. Returns whether the write happened.
+    async fn compare_and_swap(&self, key: &str, expected: Option<u64>, value: &str)
+        -> Result<bool>;
+
+    /// Write every `(key, expected, value)` in one transaction if each key
+    /// is at its `expected` revision, or absent when `None`, and each
+    /// `(key, revision)` guard is still at that revision. Guard keys are
+    /// only compared, not written. Returns whether the writes happened;
+    /// either all of them do or none.
+    async fn compare_and_swap_all(
+        &self,
+        guards: &[(&str, u64)],
+        writes: &[(&str, Option<u64>, &str)],
+    ) -> Result<bool>;
+
+    /// Delete the key if it is currently at `expected` revision
+    async fn compare_and_delete(&self, key: &str, expected: u64) -> Result<bool>;
+
+    /// Delete every key starting with `prefix`
+    async fn delete_prefix(&self, prefix: &str) -> Result<()>;
+}
+
+/// In-process KV store with etcd revision semantics
+///
+/// Used as a stand-in for etcd in tests and for single-host setups that
+/// want to exercise the global address space code path.
+#[derive(Default)]
+pub struct MemoryKv {
+    inner: Mutex<MemoryKvInner>,
+}
+
+#[derive(Default)]
+struct MemoryKvInner {
+    revision: u64,
+    entries: BTreeMap<String, KvEntry>,
+}
+
+impl MemoryKv {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl KvStore for MemoryKv {
+    async fn get(&self, key: &str) -> Result<Option<KvEntry>> {
+        Ok(self.inner.lock().unwrap().entries.get(key).cloned())
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<(String, KvEntry)>> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner
+            .entries
+            .range(prefix.to_string()..)
+            .take_while(|(k, _)| k.starts_with(prefix))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect())
+    }
+
+    async fn compare_and_swap(
+        &self,
+        key: &str,
+        expected: Option<u64>,
+        value: &str,
+    ) -> Result<bool> {
+        self.compare_and_swap_all(&[], &[(key, expected, value)])
+            .await
+    }
+
+    async fn compare_and_swap_all(
+        &self,
+        guards: &[(&str, u64)],
+        writes: &[(&str, Option<u64>, &str)],
+    ) -> Result<bool> {
+        let mut inner = self.inner.lock().unwrap();
+        let revision = |key: &str| inner.entries.get(key).map(|e| e.revision);
+        if writes
+            .iter()
+            .any(|(key, expected, _)| revision(key) != *expected)
+            || guards
+                .iter()
+                .any(|(key, expected)| revision(key) != Some(*expected))
+        {
+            return Ok(false);
+        }
+        inner.revision += 1;
+        let revision = inner.revision;
+        for (key, _, value) in writes {
+            inner.entries.insert(
+                key.to_string(),
+                KvEntry {
+                    value: value.to_string(),
+                    revision,
+                },
+            );
+        }
+        Ok(true)
+    }
+
+    async fn compare_and_delete(&self, key: &str, expected: u64) -> Result<bool> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.entries.get(key).map(|e| e.revision) != Some(expected) {
+            return Ok(false);
+        }
+        inner.revision += 1;
+        inner.entries.remove(key);
+        Ok(true)
+    }
+
+    async fn delete_prefix(&self, prefix: &str) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.revision += 1;
+        inner.entries.retain(|k, _| !k.starts_with(prefix));
+        Ok(())
+    }
+}
+
+/// Client for etcd's v3 JSON gateway (`/v3/kv/range`, `/v3/kv/txn`)
+pub struct EtcdKv {
+    endpoint: String,
+    client: Client<hyper::client::HttpConnector>,
+}
+
+impl EtcdKv {
+    /// `endpoint` is the base URL of an etcd member, e.g. `http://10.0.0.5:2379`
+    pub fn new(endpoint: impl Into<String>) -> Self {
+        Self {
+            endpoint: endpoint.into().trim_end_matches('/').to_string(),
+            client: Client::new(),
+        }
+    }
+
+    async fn call(&self, path: &str, body: serde_json::Value) -> Result<serde_json::Value> {
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri(format!("{}{}", self.endpoint, path))
+            .header("Content-Type", "application/json")
+            .body(Body::from(body.to_string()))?;
+        let resp = self
+            .client
+            .request(req)
+            .await
+            .with_context(|| format!("KV request to {} failed", self.endpoint))?;
+        let status = resp.status();
+        let bytes = hyper::body::to_bytes(resp.into_body()).await?;
+        if !status.is_success() {
+            bail!(
+                "KV request {} returned {}: {}",
+                path,
+                status,
+                String::from_utf8_lossy(&bytes)
+            );
+        }
+        serde_json::from_slice(&bytes).context("Invalid KV response")
+    }
+
+    async fn txn(
+        &self,
+        compare: Vec<serde_json::Value>,
+        success: Vec<serde_json::Value>,
+    ) -> Result<bool> {
+        let resp = self
+            .call(
+                "/v3/kv/txn",
+                serde_json::json!({ "compare": compare, "success": success }),
+            )
+            .await?;
+        // proto3 JSON omits false booleans
+        Ok(resp["succeeded"].as_bool().unwrap_or(false))
+    }
+}
+
+/// A key-value pair as returned by the etcd JSON gateway
+#[derive(Deserialize)]
+struct EtcdKeyValue {
+    key: String,
+    #[serde(default)]
+    value: String,
+    #[serde(default)]
+    mod_revision: String,
+}
+
+impl EtcdKeyValue {
+    fn decode(self) -> Result<(String, KvEntry)> {
+        let key = String::from_utf8(BASE64.decode(self.key)?)?;
+        let value = String::from_utf8(BASE64.decode(self.value)?)?;
+        let revision = self
+            .mod_revision
+            .parse()
+            .map_err(|_| anyhow!("Invalid mod_revision for {}", key))?;
+        Ok((key, KvEntry { value, revision }))
+    }
+}
+
+/// The pairs of a `/v3/kv/range` response; proto3 JSON leaves `kvs` out
+/// when nothing matched
+fn range_kvs(resp: &serde_json::Value) -> Result<Vec<EtcdKeyValue>> {
+    match resp.get("kvs") {
+        None | Some(serde_json::Value::Null) => Ok(Vec::new()),
+        Some(kvs) => Vec::deserialize(kvs).context("Invalid KV range response"),
+    }
+}
+
+fn b64(s: &str) -> String {
+    BASE64.encode(s.as_bytes())
+}
+
+/// The smallest key greater than every key starting with `prefix`
+fn prefix_range_end(prefix: &str) -> Vec<u8> {
+    let mut end = prefix.as_bytes().to_vec();
+    while let Some(last) = end.pop() {
+        if last < 0xff {
+            end.push(last + 1);
+            return end;
+        }
+    }
+    // Every byte was 0xff: etcd reads "\0" as "to the end of the keyspace"
+    vec![0]
+}
+
+#[async_trait]
+impl KvStore for EtcdKv {
+    async fn get(&self, key: &str) -> Result<Option<KvEntry>> {
+        let resp = self
+            .call("/v3/kv/range", serde_json::json!({ "key": b64(key) }))
+            .await?;
+        match range_kvs(&resp)?.into_iter().next() {
+            Some(kv) => Ok(Some(kv.decode()?.1)),
+            None => Ok(None),
+        }
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<(String, KvEntry)>> {
+        let resp = self
+            .call(
+                "/v3/kv/range",
+                serde_json::json!({
+                    "key": b64(prefix),
+                    "range_end": BASE64.encode(prefix_range_end(prefix)),
+                }),
+            )
+            .await?;
+        range_kvs(&resp)?
+            .into_iter()
+            .map(EtcdKeyValue::decode)
+            .collect()
+    }
+
+    async fn compare_and_swap(
+        &self,
+        key: &str,
+        expected: Option<u64>,
+        value: &str,
+    ) -> Result<bool> {
+        self.compare_and_swap_all(&[], &[(key, expected, value)])
+            .await
+    }
+
+    async fn compare_and_swap_all(
+        &self,
+        guards: &[(&str, u64)],
+        writes: &[(&str, Option<u64>, &str)],
+    ) -> Result<bool> {
+        let mut compare = Vec::new();
+        let mut success = Vec::new();
+        for (key, expected, value) in writes {
+            // A key that does not exist has create_revision 0
+            compare.push(match expected {
+                None => serde_json::json!({
+                    "key": b64(key), "result": "EQUAL", "target": "CREATE", "create_revision": "0"
+                }),
+                Some(rev) => serde_json::json!({
+                    "key": b64(key), "result": "EQUAL", "target": "MOD",
+                    "mod_revision": rev.to_string()
+                }),
+            });
+            success.push(
+                serde_json::json!({ "request_put": { "key": b64(key), "value": b64(value) } }),
+            );
+        }
+        // Guards come after the writes' compares, which pair up with the puts
+        for (key, expected) in guards {
+            compare.push(serde_json::json!({
+                "key": b64(key), "result": "EQUAL", "target": "MOD",
+                "mod_revision": expected.to_string()
+            }));
+        }
+        self.txn(compare, success).await
+    }
+
+    async fn compare_and_delete(&self, key: &str, expected: u64) -> Result<bool> {
+        let compare = serde_json::json!({
+            "key": b64(key), "result": "EQUAL", "target": "MOD", "mod_revision": expected.to_string()
+        });
+        let delete = serde_json::json!({ "request_delete_range": { "key": b64(key) } });
+        self.txn(vec![compare], vec![delete]).await
+    }
+
+    async fn delete_prefix(&self, prefix: &str) -> Result<()> {
+        self.call(
+            "/v3/kv/deleterange",
+            serde_json::json!({
+                "key": b64(prefix),
+                "range_end": BASE64.encode(prefix_range_end(prefix)),
+            }),
+        )
+        .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Response, Server};
+    use std::convert::Infallible;
+    use std::sync::Arc;
+
+    /// Serve the parts of etcd's JSON gateway that `EtcdKv` uses, backed by a
+    /// `MemoryKv`. Returns the base URL.
+    pub(crate) async fn spawn_etcd_stand_in() -> String {
+        let kv = Arc::new(MemoryKv::new());
+        let make_svc = make_service_fn(move |_| {
+            let kv = kv.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let kv = kv.clone();
+                    async move {
+                        let path = req.uri().path().to_string();
+                        let bytes = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+                        let resp = handle_gateway(&kv, &path, &body).await;
+                        Ok::<_, Infallible>(Response::new(Body::from(resp.to_string())))
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_svc);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        format!("http://{}", addr)
+    }
+
+    fn unb64(v: &serde_json::Value) -> String {
+        String::from_utf8(BASE64.decode(v.as_str().unwrap_or_default()).unwrap()).unwrap()
+    }
+
+    fn kv_json(key: &str, entry: &KvEntry) -> serde_json::Value {
+        serde_json::json!({
+            "key": b64(key),
+            "value": b64(&entry.value),
+            "mod_revision": entry.revision.to_string(),
+        })
+    }
+
+    async fn handle_gateway(
+        kv: &MemoryKv,
+        path: &str,
+        body: &serde_json::Value,
+    ) -> serde_json::Value {
+        let key = unb64(&body["key"]);
+        match path {
+            "/v3/kv/range" if body.get("range_end").is_some() => {
+                let kvs: Vec<_> = kv
+                    .list(&key)
+                    .await
+                    .unwrap()
+                    .iter()
+                    .map(|(k, e)| kv_json(k, e))
+                    .collect();
+                serde_json::json!({ "kvs": kvs })
+            }
+            "/v3/kv/range" => match kv.get(&key).await.unwrap() {
+                Some(e) => serde_json::json!({ "kvs": [kv_json(&key, &e)] }),
+                None => serde_json::json!({}),
+            },
+            "/v3/kv/deleterange" => {
+                kv.delete_prefix(&key).await.unwrap();
+                serde_json::json!({})
+            }
+            "/v3/kv/txn" => {
+                let compare = &body["compare"][0];
+                let key = unb64(&compare["key"]);
+                let success = &body["success"][0];
+                let ok = if success.get("request_put").is_some() {
+                    // Puts pair up with the compares of their keys; any
+                    // compares left over are guards
+                    let compares = body["compare"].as_array().unwrap();
+                    let puts = body["success"].as_array().unwrap();
+                    let guards: Vec<_> = compares[puts.len()..]
+                        .iter()
+                        .map(|compare| {
+                            let expected: u64 =
+                                compare["mod_revision"].as_str().unwrap().parse().unwrap();
+                            (unb64(&compare["key"]), expected)
+                        })
+                        .collect();
+                    let guards: Vec<_> = guards.iter().map(|(k, e)| (k.as_str(), *e)).collect();
+                    let writes: Vec<_> = compares
+                        .iter()
+                        .zip(puts)
+                        .map(|(compare, put)| {
+                            let expected = match compare["target"].as_str() {
+                                Some("CREATE") => None,
+                                _ => compare["mod_revision"].as_str().unwrap().parse().ok(),
+                            };
+                            (
+                                unb64(&compare["key"]),
+                                expected,
+                                unb64(&put["request_put"]["value"]),
+                            )
+                        })
+                        .collect();
+                    let writes: Vec<_> = writes
+                        .iter()
+                        .map(|(k, e, v)| (k.as_str(), *e, v.as_str()))
+                        .collect();
+                    kv.compare_and_swap_all(&guards, &writes).await.unwrap()
+                } else {
+                    let expected = compare["mod_revision"].as_str().unwrap().parse().unwrap();
+                    kv.compare_and_delete(&key, expected).await.unwrap()
+                };
+                if ok {
+                    serde_json::json!({ "succeeded": true })
+                } else {
+                    serde_json::json!({})
+                }
+            }
+            _ => serde_json::json!({}),
+        }
+    }
+
+    async fn exercise(kv: &dyn KvStore) {
+        assert!(kv.get("a/1").await.unwrap().is_none());
+        assert!(kv.compare_and_swap("a/1", None, "one").await.unwrap());
+        // A second create of the same key must lose
+        assert!(!kv.compare_and_swap("a/1", None, "other").await.unwrap());
+
+        let entry = kv.get("a/1").await.unwrap().unwrap();
+        assert_eq!(entry.value, "one");
+        assert!(!kv
+            .compare_and_swap("a/1", Some(entry.revision + 100), "stale")
+            .await
+            .unwrap());
+        assert!(kv
+            .compare_and_swap("a/1", Some(entry.revision), "uno")
+            .await
+            .unwrap());
+
+        kv.compare_and_swap("a/2", None, "two").await.unwrap();
+        kv.compare_and_swap("b/1", None, "other prefix")
+            .await
+            .unwrap();
+        let listed: Vec<_> = kv
+            .list("a/")
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(k, e)| (k, e.value))
+            .collect();
+        assert_eq!(
+            listed,
+            vec![
+                ("a/1".to_string(), "uno".to_string()),
+                ("a/2".to_string(), "two".to_string())
+            ]
+        );
+
+        // Writes to several keys happen together or not at all
+        let a2 = kv.get("a/2").await.unwrap().unwrap().revision;
+        assert!(!kv
+            .compare_and_swap_all(&[], &[("a/2", Some(a2), "2"), ("b/1", None, "taken")])
+            .await
+            .unwrap());
+        assert_eq!(kv.get("a/2").await.unwrap().unwrap().value, "two");
+        // Guards are compared but not written
+        let b1 = kv.get("b/1").await.unwrap().unwrap().revision;
+        assert!(!kv
+            .compare_and_swap_all(&[("b/1", b1 + 100)], &[("a/2", Some(a2), "2")])
+            .await
+            .unwrap());
+        assert!(kv
+            .compare_and_swap_all(
+                &[("b/1", b1)],
+                &[("a/2", Some(a2), "2"), ("a/3", None, "three")]
+            )
+            .await
+            .unwrap());
+        assert_eq!(kv.get("b/1").await.unwrap().unwrap().revision, b1);
+        assert_eq!(kv.get("a/2").await.unwrap().unwrap().value, "2");
+        assert_eq!(kv.get("a/3").await.unwrap().unwrap().value, "three");
+        kv.delete_prefix("a/3").await.unwrap();
+
+        let entry = kv.get("a/2").await.unwrap().unwrap();
+        assert!(kv.compare_and_delete("a/2", entry.revision).await.unwrap());
+        assert!(kv.get("a/2").await.unwrap().is_none());
+
+        kv.delete_prefix("a/").await.unwrap();
+        assert!(kv.list("a/").await.unwrap().is_empty());
+        assert!(kv.get("b/1").await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_memory_kv_semantics() {
+        exercise(&MemoryKv::new()).await;
+    }
+
+    #[tokio::test]
+    async fn test_etcd_client_against_stand_in() {
+        let endpoint = spawn_etcd_stand_in().await;
+        exercise(&EtcdKv::new(endpoint)).await;
+    }
+
+    #[test]
+    fn test_range_kvs() {
+        assert!(range_kvs(&serde_json::json!({})).unwrap().is_empty());
+        let kvs = range_kvs(&serde_json::json!({
+            "kvs": [{ "key": b64("a"), "value": b64("1"), "mod_revision": "4" }]
+        }))
+        .unwrap();
+        assert_eq!(kvs.len(), 1);
+        // A malformed response is an error, not an empty range
+        assert!(range_kvs(&serde_json::json!({ "kvs": [{ "value": "x" }] })).is_err());
+        assert!(range_kvs(&serde_json::json!({ "kvs": "x" })).is_err());
+    }
+
+    #[test]
+    fn test_prefix_range_end() {
+        assert_eq!(prefix_range_end("a/"), b"a0".to_vec());
+        assert_eq!(prefix_range_end("\u{7f}"), vec![0x80]);
+    }
+}
@@ -1,7 +1,24 @@
 // Library interface for docker-ipam-plugin
 // This allows the modules to be used in integration tests
 
+pub mod address_space;
+pub mod admin;
+pub mod audit;
+pub mod crypto;
+pub mod diff;
+pub mod events;
+pub mod global;
+pub mod history;
 pub mod ipam;
+pub mod kv;
+pub mod metrics;
+pub mod migrations;
 pub mod server;
 pub mod storage;
+pub mod systemd;
+pub mod transfer;
 pub mod types;
+pub mod utilization;
+pub mod validate;
+pub mod watcher;
+pub mod webhook;
@@ -1,6 +1,21 @@
+use anyhow::Context;
+use docker_ipam_plugin::address_space::DefaultPool;
+use docker_ipam_plugin::admin::AdminServer;
+use docker_ipam_plugin::audit::{self, AuditFilter, AuditLog};
+use docker_ipam_plugin::crypto::{Keyring, StateKey};
+use docker_ipam_plugin::diff::StateDiff;
+use docker_ipam_plugin::global::{Coordinator, KvCoordinator};
+use docker_ipam_plugin::history;
 use docker_ipam_plugin::ipam::IpamPlugin;
-use docker_ipam_plugin::server::PluginServer;
-use docker_ipam_plugin::storage::Storage;
+use docker_ipam_plugin::kv::EtcdKv;
+use docker_ipam_plugin::metrics::{self, Metrics};
+use docker_ipam_plugin::server::{self, PluginServer, SocketPermissions};
+use docker_ipam_plugin::storage::{Storage, StorageOptions};
+use docker_ipam_plugin::systemd::Watchdog;
+use docker_ipam_plugin::transfer::{self, Format};
+use docker_ipam_plugin::utilization;
+use docker_ipam_plugin::watcher::StateWatcher;
+use docker_ipam_plugin::webhook::{WebhookOptions, Webhooks};
 use std::sync::Arc;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
@@ -19,35 +34,406 @@ async fn main() -> anyhow::Result<()> {
     let socket_path = std::env::var("SOCKET_PATH")
         .unwrap_or_else(|_| "/run/docker/plugins/ipam.sock".to_string());
 
+    // Management API; empty disables it
+    let admin_socket =
+        std::env::var("ADMIN_SOCKET").unwrap_or_else(|_| "/run/docker-ipam/admin.sock".to_string());
+
+    // Read-only management API for monitoring tools; unset disables it
+    let monitor_socket = std::env::var("MONITOR_SOCKET").unwrap_or_default();
+
     let state_file = std::env::var("STATE_FILE")
         .unwrap_or_else(|_| "/var/lib/docker-ipam/state.yaml".to_string());
 
     let default_subnet =
         std::env::var("DEFAULT_SUBNET").unwrap_or_else(|_| "172.18.0.0/16".to_string());
 
+    // Coordinator of the `global` address space, shared with other hosts
+    let coordinator = std::env::var("GLOBAL_KV_ENDPOINT").ok().map(|endpoint| {
+        let prefix =
+            std::env::var("GLOBAL_KV_PREFIX").unwrap_or_else(|_| "/docker-ipam/".to_string());
+        tracing::info!("Global address space shared via {} ({})", endpoint, prefix);
+        Arc::new(KvCoordinator::new(Arc::new(EtcdKv::new(endpoint)), prefix))
+    });
+
+    // Prometheus scrape address; `/metrics` is also on the admin socket
+    let metrics_addr = std::env::var("METRICS_ADDR").ok();
+
+    let mut storage_options = StorageOptions::default();
+    if let Ok(backups) = std::env::var("STATE_BACKUPS") {
+        storage_options.backups = backups.parse().context("Invalid STATE_BACKUPS")?;
+    }
+    if let Ok(strict) = std::env::var("STRICT_VALIDATION") {
+        storage_options.strict = strict == "true" || strict == "1";
+    }
+    if let Ok(window) = std::env::var("COMMIT_WINDOW_MS") {
+        let ms: u64 = window.parse().context("Invalid COMMIT_WINDOW_MS")?;
+        storage_options.commit_window = Some(std::time::Duration::from_millis(ms));
+    }
+    if let Ok(key_file) = std::env::var("STATE_KEY_FILE") {
+        let retired = std::env::var("STATE_RETIRED_KEY_FILES").unwrap_or_default();
+        storage_options.keyring = Keyring {
+            primary: Some(StateKey::from_file(&key_file)?),
+            retired: retired
+                .split(',')
+                .filter(|p| !p.trim().is_empty())
+                .map(|p| StateKey::from_file(p.trim()))
+                .collect::<anyhow::Result<_>>()?,
+        };
+    }
+
+    // Audit log next to the state file unless configured; empty disables it
+    let audit_path = std::env::var("AUDIT_LOG").unwrap_or_else(|_| {
+        std::path::Path::new(&state_file)
+            .with_file_name("audit.jsonl")
+            .display()
+            .to_string()
+    });
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        // `dump-state` prints the decrypted state without taking the lock, so
+        // it can be used while the plugin is running
+        Some("dump-state") => {
+            let storage =
+                Storage::open_read_only_with_options(&state_file, storage_options).await?;
+            print!("{}", serde_yaml::to_string(&*storage.read().await)?);
+            return Ok(());
+        }
+        // `export [json|csv|docker]` also works while the plugin is running
+        Some("export") => {
+            let format: Format = args.get(1).map_or("json", String::as_str).parse()?;
+            let storage =
+                Storage::open_read_only_with_options(&state_file, storage_options).await?;
+            print!("{}", transfer::export(&*storage.read().await, format)?);
+            return Ok(());
+        }
+        // `import <file> [json|csv|docker] [--dry-run]` needs the plugin to be
+        // stopped, as it takes the state lock
+        Some("import") => {
+            let path = args
+                .get(1)
+                .context("Usage: import <file> [json|csv|docker] [--dry-run]")?;
+            let dry_run = args.iter().any(|a| a == "--dry-run");
+            let contents = std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read {}", path))?;
+            let format = match args.get(2).filter(|a| *a != "--dry-run") {
+                Some(format) => format.parse()?,
+                None => Format::detect(path, &contents),
+            };
+            let imported = transfer::import(&contents, format)?;
+            // Imported pools are local and must not overlap a global pool
+            let global = match &coordinator {
+                Some(coordinator) => coordinator
+                    .pools()
+                    .await
+                    .context("Failed to list global pools to check the import against")?,
+                None => Vec::new(),
+            };
+
+            let storage = Storage::with_options(&state_file, storage_options).await?;
+            let report = {
+                let mut state = storage.write().await;
+                let mut merged = state.clone();
+                let report = transfer::merge(&mut merged, imported, &global);
+                if !dry_run {
+                    *state = merged;
+                }
+                report
+            };
+            for conflict in &report.conflicts {
+                eprintln!("conflict: {}", conflict);
+            }
+            if dry_run {
+                println!("{} (dry run, nothing written)", report);
+            } else {
+                storage.save().await?;
+                println!("{}", report);
+            }
+            return Ok(());
+        }
+        // `snapshot create|list|diff|delete` work while the plugin is
+        // running; `snapshot restore` needs it stopped, as it takes the lock
+        Some("snapshot") => {
+            const USAGE: &str = "Usage: snapshot create|restore|delete <name> | snapshot diff <name> [<other>] | snapshot list";
+            let command = args.get(1).context(USAGE)?.as_str();
+            let name = args.get(2).map(String::as_str);
+            if command == "restore" {
+                let storage = Storage::with_options(&state_file, storage_options).await?;
+                let diff = storage.restore_snapshot(name.context(USAGE)?).await?;
+                println!("{}", diff);
+                return Ok(());
+            }
+
+            let storage =
+                Storage::open_read_only_with_options(&state_file, storage_options).await?;
+            match (command, name) {
+                ("create", Some(name)) => {
+                    let info = storage.create_snapshot(name).await?;
+                    println!(
+                        "{}: {} pools, {} leases",
+                        info.name, info.pools, info.leases
+                    );
+                }
+                ("list", _) => {
+                    for info in storage.snapshots().await? {
+                        println!(
+                            "{}\t{}\t{} pools\t{} leases",
+                            info.name,
+                            info.created.to_rfc3339(),
+                            info.pools,
+                            info.leases
+                        );
+                    }
+                }
+                ("diff", Some(name)) => {
+                    let diff = match args.get(3) {
+                        Some(other) => StateDiff::between(
+                            &storage.load_snapshot(name).await?,
+                            &storage.load_snapshot(other).await?,
+                        ),
+                        None => storage.diff_snapshot(name).await?,
+                    };
+                    println!("{}", diff);
+                }
+                ("delete", Some(name)) => storage.delete_snapshot(name).await?,
+                _ => anyhow::bail!(USAGE),
+            }
+            return Ok(());
+        }
+        // `history <ip> [--pool <id>] [--at <time>]` lists past and present
+        // holders of an address, or only those holding it at the given time
+        Some("history") => {
+            let ip: std::net::IpAddr = args
+                .get(1)
+                .context("Usage: history <ip> [--pool <id>] [--at <time>]")?
+                .parse()
+                .context("Invalid IP address")?;
+            let mut pool_id = None;
+            let mut at = None;
+            let mut flags = args[2..].iter();
+            while let Some(flag) = flags.next() {
+                let value = flags
+                    .next()
+                    .with_context(|| format!("{} needs a value", flag))?;
+                match flag.as_str() {
+                    "--pool" => pool_id = Some(value.as_str()),
+                    "--at" => at = Some(value.parse().context("Invalid --at time")?),
+                    _ => anyhow::bail!("Unknown history option {}", flag),
+                }
+            }
+            let storage =
+                Storage::open_read_only_with_options(&state_file, storage_options).await?;
+            let state = storage.read().await;
+            let records = match at {
+                Some(at) => history::holders_at(&state, ip, pool_id, at),
+                None => history::holders(&state, ip, pool_id),
+            };
+            for record in records {
+                let released = record
+                    .release_time
+                    .map_or_else(|| "current".to_string(), |t| t.to_rfc3339());
+                println!(
+                    "{}\t{}\t{}\t{}",
+                    record.container_name,
+                    record.pool_id.as_deref().unwrap_or("-"),
+                    record.lease_time.to_rfc3339(),
+                    released
+                );
+            }
+            return Ok(());
+        }
+        // `audit [--ip <ip>] [--container <name>] [--since <time>] [--until <time>]`
+        // prints matching audit events as JSONL
+        Some("audit") => {
+            let mut filter = AuditFilter::default();
+            let mut flags = args[1..].iter();
+            while let Some(flag) = flags.next() {
+                let value = flags
+                    .next()
+                    .with_context(|| format!("{} needs a value", flag))?;
+                match flag.as_str() {
+                    "--ip" => filter.ip_address = Some(value.parse().context("Invalid --ip")?),
+                    "--container" => filter.container = Some(value.clone()),
+                    "--since" => filter.since = Some(value.parse().context("Invalid --since")?),
+                    "--until" => filter.until = Some(value.parse().context("Invalid --until")?),
+                    _ => anyhow::bail!("Unknown audit option {}", flag),
+                }
+            }
+            for event in audit::query(std::path::Path::new(&audit_path), &filter)? {
+                println!("{}", serde_json::to_string(&event)?);
+            }
+            return Ok(());
+        }
+        _ => {}
+    }
+
     tracing::info!("Starting Docker IPAM Plugin");
     tracing::info!("Socket path: {}", socket_path);
     tracing::info!("State file: {}", state_file);
     tracing::info!("Default subnet: {}", default_subnet);
 
     // Initialize storage
-    let storage = Arc::new(Storage::new(&state_file).await?);
+    let metrics = Arc::new(Metrics::new());
+    storage_options.metrics = Some(metrics.clone());
+    let storage = Arc::new(Storage::with_options(&state_file, storage_options).await?);
     tracing::info!("Storage initialized");
 
+    // Pick up operator edits to the state file while running
+    let watch_state = std::env::var("WATCH_STATE_FILE")
+        .map(|v| v != "false" && v != "0")
+        .unwrap_or(true);
+    let _watcher = if watch_state {
+        Some(StateWatcher::spawn(storage.clone())?)
+    } else {
+        None
+    };
+
+    // Ping systemd while the state stays usable, when `WatchdogSec` is set
+    let _watchdog = Watchdog::spawn(storage.clone())?;
+
     // Initialize IPAM plugin
-    let plugin = Arc::new(IpamPlugin::new(storage.clone(), default_subnet));
+    let mut plugin = IpamPlugin::new(storage.clone(), default_subnet).with_metrics(metrics);
+    if let Some(coordinator) = coordinator {
+        plugin = plugin.with_coordinator(coordinator);
+    }
+    if let Ok(pools) = std::env::var("GLOBAL_DEFAULT_POOLS") {
+        let pools = pools
+            .split(',')
+            .filter(|p| !p.trim().is_empty())
+            .map(str::parse)
+            .collect::<anyhow::Result<Vec<DefaultPool>>>()
+            .context("Invalid GLOBAL_DEFAULT_POOLS")?;
+        plugin = plugin.with_global_default_pools(pools);
+    }
+    if let Ok(thresholds) = std::env::var("POOL_THRESHOLDS") {
+        plugin = plugin.with_thresholds(
+            utilization::parse_thresholds(&thresholds).context("Invalid POOL_THRESHOLDS")?,
+        );
+    }
+    if let Ok(limit) = std::env::var("LEASE_HISTORY") {
+        plugin = plugin.with_lease_history(limit.parse().context("Invalid LEASE_HISTORY")?);
+    }
+    if !audit_path.is_empty() {
+        let max_bytes = match std::env::var("AUDIT_LOG_MAX_BYTES") {
+            Ok(v) => v.parse().context("Invalid AUDIT_LOG_MAX_BYTES")?,
+            Err(_) => 10 * 1024 * 1024,
+        };
+        let files = match std::env::var("AUDIT_LOG_FILES") {
+            Ok(v) => v.parse().context("Invalid AUDIT_LOG_FILES")?,
+            Err(_) => 5,
+        };
+        tracing::info!("Audit log: {}", audit_path);
+        plugin = plugin.with_audit_log(Arc::new(AuditLog::new(&audit_path, max_bytes, files)));
+    }
+    let plugin = Arc::new(plugin);
     tracing::info!("IPAM plugin initialized");
 
+    let webhook_urls: Vec<String> = std::env::var("WEBHOOK_URLS")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|u| !u.is_empty())
+        .map(str::to_string)
+        .collect();
+    let _webhooks = if webhook_urls.is_empty() {
+        None
+    } else {
+        // Queued events are kept next to the state file unless configured
+        let queue_dir = std::env::var("WEBHOOK_QUEUE_DIR").unwrap_or_else(|_| {
+            std::path::Path::new(&state_file)
+                .with_file_name("webhooks")
+                .display()
+                .to_string()
+        });
+        let mut options = WebhookOptions::new(queue_dir);
+        if let Ok(max) = std::env::var("WEBHOOK_QUEUE_MAX") {
+            options.max_queued = max.parse().context("Invalid WEBHOOK_QUEUE_MAX")?;
+        }
+        Some(Webhooks::spawn(plugin.events(), &webhook_urls, options)?)
+    };
+
+    let drain_timeout = match std::env::var("SHUTDOWN_TIMEOUT_SECS") {
+        Ok(secs) => {
+            std::time::Duration::from_secs(secs.parse().context("Invalid SHUTDOWN_TIMEOUT_SECS")?)
+        }
+        Err(_) => server::DEFAULT_DRAIN_TIMEOUT,
+    };
+    let shutdown = server::shutdown_signal()?;
+    // Waited for after the plugin server stops, so their sockets are gone
+    // before the process exits
+    let mut admin_servers = Vec::new();
+
+    if !admin_socket.is_empty() {
+        let admin = AdminServer::new(plugin.clone())
+            .with_drain_timeout(drain_timeout)
+            .with_socket_permissions(socket_permissions("ADMIN_SOCKET", 0o660)?);
+        let shutdown = shutdown.clone();
+        admin_servers.push(tokio::spawn(async move {
+            if let Err(e) = admin.serve_unix(&admin_socket, shutdown.wait()).await {
+                tracing::error!("Admin API stopped: {:#}", e);
+            }
+        }));
+    }
+
+    if !monitor_socket.is_empty() {
+        let monitor = AdminServer::new(plugin.clone())
+            .read_only()
+            .with_drain_timeout(drain_timeout)
+            .with_socket_permissions(socket_permissions("MONITOR_SOCKET", 0o660)?);
+        let shutdown = shutdown.clone();
+        admin_servers.push(tokio::spawn(async move {
+            if let Err(e) = monitor.serve_unix(&monitor_socket, shutdown.wait()).await {
+                tracing::error!("Read-only admin API stopped: {:#}", e);
+            }
+        }));
+    }
+
+    if let Some(addr) = metrics_addr {
+        let plugin = plugin.clone();
+        tokio::spawn(async move {
+            if let Err(e) = metrics::serve_tcp(&addr, plugin).await {
+                tracing::error!("Metrics endpoint stopped: {:#}", e);
+            }
+        });
+    }
+
     // Start server
-    let server = PluginServer::new(plugin);
+    let server = PluginServer::new(plugin)
+        .with_drain_timeout(drain_timeout)
+        .with_socket_permissions(socket_permissions("SOCKET", 0o600)?);
 
     // Check if we should use TCP (for testing) or Unix socket
     if let Ok(tcp_addr) = std::env::var("TCP_ADDR") {
         tracing::warn!("Running in TCP mode (for testing only)");
-        server.serve_tcp(&tcp_addr).await?;
+        server.serve_tcp(&tcp_addr, shutdown.wait()).await?;
     } else {
-        server.serve_unix(&socket_path).await?;
+        server.serve_unix(&socket_path, shutdown.wait()).await?;
+    }
+    for admin in admin_servers {
+        let _ = admin.await;
     }
 
     Ok(())
 }
+
+/// Mode and ownership of a socket from `<prefix>_MODE`, `<prefix>_OWNER` and
+/// `<prefix>_GROUP`
+fn socket_permissions(prefix: &str, default_mode: u32) -> anyhow::Result<SocketPermissions> {
+    let mode = match std::env::var(format!("{}_MODE", prefix)) {
+        Ok(mode) => SocketPermissions::parse_mode(&mode)
+            .with_context(|| format!("Invalid {}_MODE", prefix))?,
+        Err(_) => default_mode,
+    };
+    let mut permissions = SocketPermissions::new(mode);
+    if let Ok(owner) = std::env::var(format!("{}_OWNER", prefix)) {
+        permissions = permissions
+            .with_owner(&owner)
+            .with_context(|| format!("Invalid {}_OWNER", prefix))?;
+    }
+    if let Ok(group) = std::env::var(format!("{}_GROUP", prefix)) {
+        permissions = permissions
+            .with_group(&group)
+            .with_context(|| format!("Invalid {}_GROUP", prefix))?;
+    }
+    Ok(permissions)
+}
@@ -0,0 +1,373 @@
+use crate::ipam::IpamPlugin;
+use crate::utilization::Utilization;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Upper bounds of the latency histogram buckets, in seconds
+const BUCKETS: &[f64] = &[
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Content type of the Prometheus text format
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+#[derive(Debug, Clone, Default)]
+struct Histogram {
+    /// Observations per bucket, not cumulative; the last one is `+Inf`
+    counts: [u64; BUCKETS.len() + 1],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, seconds: f64) {
+        let bucket = BUCKETS
+            .iter()
+            .position(|le| seconds <= *le)
+            .unwrap_or(BUCKETS.len());
+        self.counts[bucket] += 1;
+        self.sum += seconds;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let sep = if labels.is_empty() { "" } else { "," };
+        let mut cumulative = 0;
+        for (i, count) in self.counts.iter().enumerate() {
+            cumulative += count;
+            let le = BUCKETS
+                .get(i)
+                .map_or_else(|| "+Inf".to_string(), |b| b.to_string());
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{}{}le=\"{}\"}} {}",
+                name, labels, sep, le, cumulative
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{} {}",
+            series(&format!("{}_sum", name), labels),
+            self.sum
+        );
+        let _ = writeln!(
+            out,
+            "{} {}",
+            series(&format!("{}_count", name), labels),
+            self.count
+        );
+    }
+}
+
+/// Counters and histograms for the Prometheus `/metrics` endpoint
+///
+/// Pool gauges are not kept here; they are computed from the current state
+/// on every scrape.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// Keyed by `(endpoint, success)`
+    requests: Mutex<BTreeMap<(String, bool), Histogram>>,
+    saves: Mutex<Histogram>,
+    save_failures: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record one `IpamDriver.<endpoint>` request
+    pub fn record_request(&self, endpoint: &str, success: bool, elapsed: Duration) {
+        self.requests
+            .lock()
+            .unwrap()
+            .entry((endpoint.to_string(), success))
+            .or_default()
+            .observe(elapsed.as_secs_f64());
+    }
+
+    /// Record one `Storage::save`
+    pub fn record_save(&self, success: bool, elapsed: Duration) {
+        self.saves.lock().unwrap().observe(elapsed.as_secs_f64());
+        if !success {
+            self.save_failures.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Everything in the Prometheus text format, including the current pool
+    /// utilization
+    pub async fn render(&self, plugin: &IpamPlugin) -> String {
+        let mut out = String::new();
+        render_pools(&mut out, plugin).await;
+
+        let requests = self.requests.lock().unwrap().clone();
+        header(
+            &mut out,
+            "ipam_requests_total",
+            "counter",
+            "IpamDriver requests handled",
+        );
+        for ((endpoint, success), histogram) in &requests {
+            let _ = writeln!(
+                out,
+                "ipam_requests_total{{{}}} {}",
+                request_labels(endpoint, *success),
+                histogram.count
+            );
+        }
+        header(
+            &mut out,
+            "ipam_request_duration_seconds",
+            "histogram",
+            "Time taken to handle IpamDriver requests",
+        );
+        for ((endpoint, success), histogram) in &requests {
+            histogram.render(
+                &mut out,
+                "ipam_request_duration_seconds",
+                &request_labels(endpoint, *success),
+            );
+        }
+
+        header(
+            &mut out,
+            "ipam_state_save_duration_seconds",
+            "histogram",
+            "Time taken to write the state file",
+        );
+        self.saves
+            .lock()
+            .unwrap()
+            .render(&mut out, "ipam_state_save_duration_seconds", "");
+        header(
+            &mut out,
+            "ipam_state_save_failures_total",
+            "counter",
+            "State file writes that failed",
+        );
+        let _ = writeln!(
+            out,
+            "ipam_state_save_failures_total {}",
+            self.save_failures.load(Ordering::Relaxed)
+        );
+        out
+    }
+}
+
+/// Name, help text and value of a per-pool gauge
+type PoolGauge = (&'static str, &'static str, fn(&Utilization) -> u128);
+
+async fn render_pools(out: &mut String, plugin: &IpamPlugin) {
+    let pools = match plugin.pools().await {
+        Ok(pools) => pools,
+        Err(e) => {
+            tracing::warn!("Cannot list pools for metrics: {:#}", e);
+            return;
+        }
+    };
+    let mut rows = Vec::new();
+    for (space, pool) in pools {
+        match plugin.pool_leases(&pool).await {
+            Ok(leases) => {
+                let labels = format!(
+                    "pool=\"{}\",subnet=\"{}\",address_space=\"{}\"",
+                    escape(&pool.pool_id),
+                    pool.subnet,
+                    space.as_str()
+                );
+                rows.push((labels, Utilization::of(&pool.subnet, &leases)));
+            }
+            Err(e) => tracing::warn!(
+                "Cannot list leases of {} for metrics: {:#}",
+                pool.pool_id,
+                e
+            ),
+        }
+    }
+    rows.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let gauges: [PoolGauge; 5] = [
+        ("ipam_pool_size", "Addresses a pool can hand out", |u| {
+            u.total
+        }),
+        ("ipam_pool_used", "Leased addresses in a pool", |u| u.used),
+        ("ipam_pool_free", "Free addresses in a pool", |u| u.free),
+        (
+            "ipam_pool_reserved",
+            "Addresses in a pool reserved through the admin API",
+            |u| u.reserved,
+        ),
+        // Released addresses go back to the pool at once; exported so that
+        // dashboards built for quarantining allocators keep working
+        (
+            "ipam_pool_quarantined",
+            "Released addresses held back from allocation; always 0",
+            |_| 0,
+        ),
+    ];
+    for (name, help, value) in gauges {
+        header(out, name, "gauge", help);
+        for (labels, utilization) in &rows {
+            let _ = writeln!(out, "{}{{{}}} {}", name, labels, value(utilization));
+        }
+    }
+}
+
+/// `name{labels}`, or just `name` without labels
+fn series(name: &str, labels: &str) -> String {
+    if labels.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}{{{}}}", name, labels)
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn request_labels(endpoint: &str, success: bool) -> String {
+    format!(
+        "endpoint=\"{}\",result=\"{}\"",
+        escape(endpoint),
+        if success { "success" } else { "error" }
+    )
+}
+
+/// Escape a label value
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// The `/metrics` response
+pub async fn metrics_response(plugin: &IpamPlugin) -> Response<Body> {
+    let body = match plugin.metrics() {
+        Some(metrics) => metrics.render(plugin).await,
+        None => String::new(),
+    };
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", CONTENT_TYPE)
+        .body(Body::from(body))
+        .unwrap()
+}
+
+/// Serve `/metrics` over TCP for Prometheus, which cannot scrape the Unix
+/// admin socket
+pub async fn serve_tcp(addr: &str, plugin: Arc<IpamPlugin>) -> anyhow::Result<()> {
+    let addr = addr.parse()?;
+    let make_svc = make_service_fn(move |_conn| {
+        let plugin = plugin.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                let plugin = plugin.clone();
+                async move {
+                    Ok::<_, Infallible>(match (req.method(), req.uri().path()) {
+                        (&Method::GET, "/metrics") => metrics_response(&plugin).await,
+                        _ => Response::builder()
+                            .status(StatusCode::NOT_FOUND)
+                            .body(Body::from("Not Found"))
+                            .unwrap(),
+                    })
+                }
+            }))
+        }
+    });
+
+    let server = Server::bind(&addr).serve(make_svc);
+    tracing::info!("Metrics listening on http://{}/metrics", addr);
+    server.await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{Storage, StorageOptions};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_histogram_buckets_are_cumulative() {
+        let mut h = Histogram::default();
+        h.observe(0.0005);
+        h.observe(0.003);
+        h.observe(60.0);
+        let mut out = String::new();
+        h.render(&mut out, "x", "a=\"b\"");
+        assert!(out.contains("x_bucket{a=\"b\",le=\"0.001\"} 1\n"));
+        assert!(out.contains("x_bucket{a=\"b\",le=\"0.005\"} 2\n"));
+        assert!(out.contains("x_bucket{a=\"b\",le=\"10\"} 2\n"));
+        assert!(out.contains("x_bucket{a=\"b\",le=\"+Inf\"} 3\n"));
+        assert!(out.contains("x_count{a=\"b\"} 3\n"));
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+
+    #[tokio::test]
+    async fn test_state_saves_are_timed_and_failures_counted() {
+        let temp_dir = TempDir::new().unwrap();
+        let state_file = temp_dir.path().join("state.yaml");
+        let metrics = Arc::new(Metrics::new());
+        let options = StorageOptions {
+            metrics: Some(metrics.clone()),
+            ..StorageOptions::default()
+        };
+        let storage = Arc::new(Storage::with_options(&state_file, options).await.unwrap());
+        let plugin = IpamPlugin::new(storage.clone(), "10.0.0.0/24".to_string())
+            .with_metrics(metrics.clone());
+
+        storage.save().await.unwrap();
+        // A directory where the temp file goes makes the next save fail
+        std::fs::create_dir(state_file.with_extension("tmp")).unwrap();
+        assert!(storage.save().await.is_err());
+
+        let text = metrics.render(&plugin).await;
+        assert!(text.contains("ipam_state_save_duration_seconds_count 2\n"));
+        assert!(text.contains("ipam_state_save_failures_total 1\n"));
+        assert!(text.contains("# TYPE ipam_pool_reserved gauge\n"));
+        assert!(text.contains("# TYPE ipam_pool_quarantined gauge\n"));
+    }
+
+    #[tokio::test]
+    async fn test_pool_gauges() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = Arc::new(
+            Storage::new(temp_dir.path().join("state.yaml"))
+                .await
+                .unwrap(),
+        );
+        let plugin = IpamPlugin::new(storage, "10.0.0.0/24".to_string());
+        let pool_id = plugin
+            .request_pool(crate::types::RequestPoolRequest {
+                pool: Some("10.80.0.0/29".to_string()),
+                sub_pool: None,
+                options: None,
+                v6: None,
+                address_space: None,
+            })
+            .await
+            .unwrap()
+            .pool_id;
+
+        let text = Metrics::new().render(&plugin).await;
+        let labels = format!(
+            "pool=\"{}\",subnet=\"10.80.0.0/29\",address_space=\"local\"",
+            pool_id
+        );
+        assert!(text.contains(&format!("ipam_pool_size{{{}}} 6\n", labels)));
+        assert!(text.contains(&format!("ipam_pool_quarantined{{{}}} 0\n", labels)));
+    }
+}
@@ -0,0 +1,328 @@
+use anyhow::{anyhow, bail, Context, Result};
+use ipnetwork::IpNetwork;
+use serde_yaml::{Mapping, Value};
+use std::fmt;
+use std::net::IpAddr;
+
+/// Schema version written by this build of the plugin
+pub const CURRENT_VERSION: u64 = 7;
+
+/// Key holding the schema version at the top of the state file
+pub const VERSION_KEY: &str = "version";
+
+/// A single upgrade step; entry `n` turns a version `n` document into version `n + 1`
+type Migration = fn(Mapping) -> Result<Mapping>;
+
+const MIGRATIONS: &[Migration] = &[
+    v0_to_v1, v1_to_v2, v2_to_v3, v3_to_v4, v4_to_v5, v5_to_v6, v6_to_v7,
+];
+
+/// Version 0 is the original unversioned layout. Its shape is identical to
+/// version 1, which only adds the `version` key itself.
+fn v0_to_v1(doc: Mapping) -> Result<Mapping> {
+    Ok(doc)
+}
+
+/// Version 2 types pool subnets and gateways. Subnets are normalized to
+/// their network address and empty gateways become null; anything that
+/// does not parse fails the migration instead of being carried along.
+fn v1_to_v2(mut doc: Mapping) -> Result<Mapping> {
+    let Some(Value::Mapping(pools)) = doc.get_mut("pools") else {
+        return Ok(doc);
+    };
+    for (key, pool) in pools.iter_mut() {
+        let id = key.as_str().unwrap_or("?");
+        let Value::Mapping(pool) = pool else {
+            bail!("Pool {} is not a mapping", id);
+        };
+
+        let subnet = pool
+            .get("subnet")
+            .and_then(Value::as_str)
+            .unwrap_or_default();
+        let network: IpNetwork = subnet
+            .parse()
+            .map_err(|e| anyhow!("Pool {} has invalid subnet {:?}: {}", id, subnet, e))?;
+        let network = IpNetwork::new(network.network(), network.prefix())?;
+        pool.insert(Value::from("subnet"), Value::from(network.to_string()));
+
+        match pool.get("gateway") {
+            None | Some(Value::Null) => {}
+            Some(Value::String(g)) if g.trim().is_empty() => {
+                pool.insert(Value::from("gateway"), Value::Null);
+            }
+            Some(Value::String(g)) => {
+                g.parse::<IpAddr>()
+                    .map_err(|e| anyhow!("Pool {} has invalid gateway {:?}: {}", id, g, e))?;
+            }
+            Some(other) => bail!("Pool {} has invalid gateway {:?}", id, other),
+        }
+    }
+    Ok(doc)
+}
+
+/// Version 3 adds endpoint metadata to leases. The new fields are optional,
+/// so only `pool_id` is filled in, and only where exactly one pool contains
+/// the address; leases in overlapping pools are left for the operator.
+fn v2_to_v3(mut doc: Mapping) -> Result<Mapping> {
+    let networks: Vec<(String, IpNetwork)> = match doc.get("pools") {
+        Some(Value::Mapping(pools)) => pools
+            .iter()
+            .filter_map(|(id, pool)| {
+                let subnet = pool.get("subnet")?.as_str()?.parse().ok()?;
+                Some((id.as_str()?.to_string(), subnet))
+            })
+            .collect(),
+        _ => Vec::new(),
+    };
+    let Some(Value::Sequence(leases)) = doc.get_mut("leases") else {
+        return Ok(doc);
+    };
+    for lease in leases.iter_mut() {
+        let Value::Mapping(lease) = lease else {
+            continue;
+        };
+        if lease.contains_key("pool_id") {
+            continue;
+        }
+        let Some(ip) = lease
+            .get("ip_address")
+            .and_then(Value::as_str)
+            .and_then(|ip| ip.parse::<IpAddr>().ok())
+        else {
+            continue;
+        };
+        let mut owners = networks.iter().filter(|(_, net)| net.contains(ip));
+        if let (Some((pool_id, _)), None) = (owners.next(), owners.next()) {
+            lease.insert(Value::from("pool_id"), Value::from(pool_id.as_str()));
+        }
+    }
+    Ok(doc)
+}
+
+/// Version 4 adds the per-address `history` of past holders. It starts
+/// empty, so nothing needs converting; the bump only stops older plugins
+/// from loading the file and dropping the history.
+fn v3_to_v4(doc: Mapping) -> Result<Mapping> {
+    Ok(doc)
+}
+
+/// Version 5 adds the `reserved` flag to leases. Existing leases are not
+/// reserved, which is what a missing flag means; the bump only stops older
+/// plugins from loading the file and dropping reservations.
+fn v4_to_v5(doc: Mapping) -> Result<Mapping> {
+    Ok(doc)
+}
+
+/// Version 6 adds per-pool `thresholds`. Pools without them use the
+/// plugin's, which is what a missing list means; the bump only stops older
+/// plugins from loading the file and dropping the thresholds.
+fn v5_to_v6(doc: Mapping) -> Result<Mapping> {
+    Ok(doc)
+}
+
+/// Version 7 adds the `pool_id` of each history record. Older records stay
+/// without one and match every pool; the bump only stops older plugins from
+/// loading the file and dropping the pools.
+fn v6_to_v7(doc: Mapping) -> Result<Mapping> {
+    Ok(doc)
+}
+
+/// The state file was written by a newer plugin than this one
+///
+/// Kept as a distinct type so callers can tell it apart from a corrupt file:
+/// falling back to an older backup here would silently roll back state.
+#[derive(Debug)]
+pub struct NewerVersionError {
+    pub found: u64,
+}
+
+impl fmt::Display for NewerVersionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "State file has schema version {} but this plugin only supports up to {}; refusing to load it",
+            self.found, CURRENT_VERSION
+        )
+    }
+}
+
+impl std::error::Error for NewerVersionError {}
+
+/// Read the schema version of a parsed state document
+///
+/// Files written before versioning was introduced have no `version` key and
+/// are treated as version 0.
+pub fn document_version(doc: &Mapping) -> Result<u64> {
+    match doc.get(VERSION_KEY) {
+        None => Ok(0),
+        Some(v) => v
+            .as_u64()
+            .ok_or_else(|| anyhow!("Invalid state file version: {:?}", v)),
+    }
+}
+
+/// Upgrade a parsed state document to `CURRENT_VERSION`
+///
+/// Returns the upgraded document and the version it started at. Documents
+/// from a newer version are rejected rather than loaded, since doing so
+/// would silently drop fields this build does not know about.
+pub fn migrate(doc: Value) -> Result<(Value, u64)> {
+    let mut doc = match doc {
+        Value::Mapping(m) => m,
+        // An empty file parses as null
+        Value::Null => Mapping::new(),
+        _ => bail!("State file is not a YAML mapping"),
+    };
+
+    let from = document_version(&doc)?;
+    if from > CURRENT_VERSION {
+        return Err(NewerVersionError { found: from }.into());
+    }
+
+    for (version, migration) in MIGRATIONS.iter().enumerate().skip(from as usize) {
+        doc = migration(doc)
+            .with_context(|| format!("Failed to migrate state from version {}", version))?;
+        tracing::info!("Migrated state from version {} to {}", version, version + 1);
+    }
+
+    doc.insert(Value::from(VERSION_KEY), Value::from(CURRENT_VERSION));
+    Ok((Value::Mapping(doc), from))
+}
+
+/// Prefix a serialized state mapping with the current `version` key
+pub fn stamp(doc: Value) -> Result<Value> {
+    let Value::Mapping(body) = doc else {
+        bail!("State did not serialize to a YAML mapping");
+    };
+
+    let mut out = Mapping::new();
+    out.insert(Value::from(VERSION_KEY), Value::from(CURRENT_VERSION));
+    for (k, v) in body {
+        if k.as_str() != Some(VERSION_KEY) {
+            out.insert(k, v);
+        }
+    }
+    Ok(Value::Mapping(out))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_migration_chain_reaches_current_version() {
+        assert_eq!(MIGRATIONS.len() as u64, CURRENT_VERSION);
+
+        // Versions that only add optional fields leave documents as they are
+        let doc: Value = serde_yaml::from_str(
+            "pools: {}\nleases:\n- ip_address: 10.0.0.2\n  container_name: web\n",
+        )
+        .unwrap();
+        let Value::Mapping(doc) = doc else {
+            unreachable!()
+        };
+        let additive: &[Migration] = &[v3_to_v4, v4_to_v5, v5_to_v6, v6_to_v7];
+        for migration in additive {
+            assert_eq!(migration(doc.clone()).unwrap(), doc);
+        }
+    }
+
+    #[test]
+    fn test_unversioned_document_is_version_zero() {
+        let doc: Value = serde_yaml::from_str("pools: {}\nleases: []\n").unwrap();
+        let (migrated, from) = migrate(doc).unwrap();
+        assert_eq!(from, 0);
+        assert_eq!(
+            migrated.get(VERSION_KEY).and_then(Value::as_u64),
+            Some(CURRENT_VERSION)
+        );
+    }
+
+    #[test]
+    fn test_newer_version_is_rejected() {
+        let doc: Value = serde_yaml::from_str(&format!(
+            "version: {}\npools: {{}}\nleases: []\n",
+            CURRENT_VERSION + 1
+        ))
+        .unwrap();
+        let err = migrate(doc).unwrap_err();
+        assert!(err.to_string().contains("refusing to load"));
+        assert!(err.downcast_ref::<NewerVersionError>().is_some());
+    }
+
+    #[test]
+    fn test_v2_normalizes_pools() {
+        let doc: Value = serde_yaml::from_str(
+            "version: 1\npools:\n  p:\n    pool_id: p\n    subnet: 10.0.0.5/24\n    gateway: ''\nleases: []\n",
+        )
+        .unwrap();
+        let (migrated, from) = migrate(doc).unwrap();
+        assert_eq!(from, 1);
+        let pool = &migrated["pools"]["p"];
+        assert_eq!(pool["subnet"].as_str(), Some("10.0.0.0/24"));
+        assert!(pool["gateway"].is_null());
+
+        for bad in [
+            "subnet: nope\n    gateway: null",
+            "subnet: 10.0.0.0/24\n    gateway: nope",
+        ] {
+            let doc: Value = serde_yaml::from_str(&format!(
+                "version: 1\npools:\n  p:\n    pool_id: p\n    {}\nleases: []\n",
+                bad
+            ))
+            .unwrap();
+            let err = migrate(doc).unwrap_err();
+            assert!(format!("{:#}", err).contains("Pool p has invalid"));
+        }
+    }
+
+    #[test]
+    fn test_v3_assigns_leases_to_pools() {
+        let doc: Value = serde_yaml::from_str(
+            "version: 2
+pools:
+  a:
+    pool_id: a
+    subnet: 10.0.0.0/24
+    gateway: null
+  b:
+    pool_id: b
+    subnet: 10.1.0.0/24
+    gateway: null
+  c:
+    pool_id: c
+    subnet: 10.1.0.0/16
+    gateway: null
+leases:
+- ip_address: 10.0.0.2
+  container_name: web
+  lease_time: 2025-01-09T10:30:00Z
+- ip_address: 10.1.0.2
+  container_name: ambiguous
+  lease_time: 2025-01-09T10:30:00Z
+- ip_address: 192.168.0.2
+  container_name: stray
+  lease_time: 2025-01-09T10:30:00Z
+",
+        )
+        .unwrap();
+        let (migrated, from) = migrate(doc).unwrap();
+        assert_eq!(from, 2);
+        let leases = migrated["leases"].as_sequence().unwrap();
+        assert_eq!(leases[0]["pool_id"].as_str(), Some("a"));
+        assert!(leases[1].get("pool_id").is_none());
+        assert!(leases[2].get("pool_id").is_none());
+
+        let state: crate::types::IpamState = serde_yaml::from_value(migrated).unwrap();
+        assert_eq!(state.leases[0].pool_id.as_deref(), Some("a"));
+        assert!(state.leases[0].labels.is_empty());
+    }
+
+    #[test]
+    fn test_stamp_puts_version_first() {
+        let doc: Value = serde_yaml::from_str("pools: {}\nleases: []\n").unwrap();
+        let yaml = serde_yaml::to_string(&stamp(doc).unwrap()).unwrap();
+        assert!(yaml.starts_with(&format!("version: {}\n", CURRENT_VERSION)));
+    }
+}
@@ -1,91 +1,515 @@
 use crate::ipam::IpamPlugin;
+use crate::systemd;
 use crate::types::*;
+use anyhow::Context;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use serde::Serialize;
+use std::collections::BTreeMap;
 use std::convert::Infallible;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::net::UnixListener;
 
+/// How long `/ready` waits for the state lock before reporting storage stuck
+const STORAGE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long requests may take to finish once shutdown starts, unless
+/// configured otherwise
+pub const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
 /// HTTP server for the Docker IPAM plugin
 pub struct PluginServer {
     plugin: Arc<IpamPlugin>,
+    /// Set once the socket or port is bound, cleared when shutdown starts
+    bound: Arc<AtomicBool>,
+    drain_timeout: Duration,
+    socket_permissions: SocketPermissions,
 }
 
 impl PluginServer {
     pub fn new(plugin: Arc<IpamPlugin>) -> Self {
-        Self { plugin }
+        Self {
+            plugin,
+            bound: Arc::new(AtomicBool::new(false)),
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+            socket_permissions: SocketPermissions::default(),
+        }
     }
 
-    /// Start the server on a Unix socket
-    pub async fn serve_unix(self, socket_path: &str) -> anyhow::Result<()> {
-        // Remove existing socket if it exists
-        let _ = std::fs::remove_file(socket_path);
-
-        // Ensure parent directory exists
-        if let Some(parent) = std::path::Path::new(socket_path).parent() {
-            std::fs::create_dir_all(parent)?;
-        }
+    /// Set the mode and ownership of the socket bound by `serve_unix`
+    pub fn with_socket_permissions(mut self, permissions: SocketPermissions) -> Self {
+        self.socket_permissions = permissions;
+        self
+    }
 
-        let listener = UnixListener::bind(socket_path)?;
-        tracing::info!("IPAM plugin listening on {}", socket_path);
+    /// Set how long requests still running at shutdown may take to finish
+    pub fn with_drain_timeout(mut self, timeout: Duration) -> Self {
+        self.drain_timeout = timeout;
+        self
+    }
 
-        // Make socket accessible
-        #[cfg(unix)]
-        {
-            use std::os::unix::fs::PermissionsExt;
-            std::fs::set_permissions(socket_path, std::fs::Permissions::from_mode(0o666))?;
-        }
+    /// Start the server on a Unix socket, until `shutdown` resolves
+    ///
+    /// The socket is the one systemd passed through socket activation if
+    /// there is one, otherwise it is bound at `socket_path`. systemd is told
+    /// when the plugin is ready and when it stops.
+    ///
+    /// On shutdown the socket stops accepting connections, running requests
+    /// get up to the drain timeout to finish, the state is saved a last time
+    /// and the socket file is removed, unless systemd owns it.
+    pub async fn serve_unix(
+        self,
+        socket_path: &str,
+        shutdown: impl Future<Output = ()>,
+    ) -> anyhow::Result<()> {
+        let (listener, activated) = match systemd::listener()? {
+            Some(listener) => {
+                tracing::info!(
+                    "IPAM plugin listening on {:?} passed by systemd",
+                    listener.local_addr()?
+                );
+                (UnixListener::from_std(listener)?, true)
+            }
+            None => {
+                let listener = bind_unix(socket_path, &self.socket_permissions)?;
+                tracing::info!("IPAM plugin listening on {}", socket_path);
+                (listener, false)
+            }
+        };
+        self.bound.store(true, Ordering::SeqCst);
+        notify_systemd("READY=1");
 
         let plugin = self.plugin.clone();
-
-        loop {
-            match listener.accept().await {
-                Ok((stream, _)) => {
-                    let plugin = plugin.clone();
-                    tokio::spawn(async move {
-                        let service = service_fn(move |req| {
-                            let plugin = plugin.clone();
-                            async move { handle_request(req, plugin).await }
-                        });
-
-                        if let Err(e) = hyper::server::conn::Http::new()
-                            .serve_connection(stream, service)
-                            .await
-                        {
-                            tracing::error!("Error serving connection: {}", e);
-                        }
-                    });
-                }
-                Err(e) => {
-                    tracing::error!("Error accepting connection: {}", e);
-                }
-            }
+        let bound = self.bound.clone();
+        let stopping = async {
+            shutdown.await;
+            self.bound.store(false, Ordering::SeqCst);
+            notify_systemd("STOPPING=1");
+        };
+        serve_until(
+            listener,
+            move |req| serve_request(req, plugin.clone(), bound.clone()),
+            stopping,
+            self.drain_timeout,
+        )
+        .await?;
+
+        // systemd keeps its socket open while the plugin restarts
+        if activated {
+            return self.final_save().await;
+        }
+        if let Err(e) = std::fs::remove_file(socket_path) {
+            tracing::warn!("Failed to remove socket {}: {}", socket_path, e);
         }
+        self.final_save().await
     }
 
-    /// Start the server on a TCP port (for testing)
-    pub async fn serve_tcp(self, addr: &str) -> anyhow::Result<()> {
+    /// Start the server on a TCP port (for testing), until `shutdown`
+    /// resolves; shuts down like `serve_unix`
+    pub async fn serve_tcp(
+        self,
+        addr: &str,
+        shutdown: impl Future<Output = ()>,
+    ) -> anyhow::Result<()> {
         let addr = addr.parse()?;
         let plugin = self.plugin.clone();
+        let bound = self.bound.clone();
 
         let make_svc = make_service_fn(move |_conn| {
             let plugin = plugin.clone();
+            let bound = bound.clone();
             async move {
                 Ok::<_, Infallible>(service_fn(move |req| {
-                    let plugin = plugin.clone();
-                    async move { handle_request(req, plugin).await }
+                    serve_request(req, plugin.clone(), bound.clone())
                 }))
             }
         });
 
-        let server = Server::bind(&addr).serve(make_svc);
+        let server = Server::try_bind(&addr)?.serve(make_svc);
+        self.bound.store(true, Ordering::SeqCst);
         tracing::info!("IPAM plugin listening on http://{}", addr);
+        notify_systemd("READY=1");
 
-        server.await?;
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let server = server.with_graceful_shutdown(async {
+            let _ = stopped.await;
+        });
+        tokio::pin!(server);
+        tokio::select! {
+            result = &mut server => return Ok(result?),
+            _ = shutdown => {}
+        }
+        self.bound.store(false, Ordering::SeqCst);
+        notify_systemd("STOPPING=1");
+        let _ = stop.send(());
+        match tokio::time::timeout(self.drain_timeout, server).await {
+            Ok(result) => result?,
+            Err(_) => tracing::warn!(
+                "Requests still running after {:?}, stopping anyway",
+                self.drain_timeout
+            ),
+        }
+        self.final_save().await
+    }
+
+    /// Save the state once nothing can change it any more
+    async fn final_save(&self) -> anyhow::Result<()> {
+        let storage = self.plugin.storage();
+        if storage.is_read_only() {
+            return Ok(());
+        }
+        storage
+            .save()
+            .await
+            .context("Final save of the state failed")?;
+        tracing::info!("State saved, IPAM plugin stopped");
         Ok(())
     }
 }
 
+/// Tell systemd about a state change, if it is listening; a failure is only
+/// logged as the plugin works without it
+fn notify_systemd(state: &str) {
+    if let Err(e) = systemd::notify(state) {
+        tracing::warn!("{:#}", e);
+    }
+}
+
+/// Stops on the first SIGTERM or SIGINT
+///
+/// The handlers are installed right away, so a signal arriving before any
+/// server waits for it is not lost.
+pub fn shutdown_signal() -> anyhow::Result<Shutdown> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    Ok(Shutdown::new(async move {
+        let name = tokio::select! {
+            _ = terminate.recv() => "SIGTERM",
+            _ = interrupt.recv() => "SIGINT",
+        };
+        tracing::info!("Received {}, shutting down", name);
+    }))
+}
+
+/// A shutdown that every server of the plugin waits for; clones all stop
+/// together
+#[derive(Clone)]
+pub struct Shutdown(tokio::sync::watch::Receiver<bool>);
+
+impl Shutdown {
+    /// Stop once `signal` resolves
+    pub fn new(signal: impl Future<Output = ()> + Send + 'static) -> Self {
+        let (stop, stopped) = tokio::sync::watch::channel(false);
+        tokio::spawn(async move {
+            signal.await;
+            let _ = stop.send(true);
+        });
+        Self(stopped)
+    }
+
+    /// Resolves once shutdown starts
+    pub async fn wait(mut self) {
+        let _ = self.0.wait_for(|stopped| *stopped).await;
+    }
+}
+
+/// Mode and ownership of a Unix socket
+///
+/// Anyone who can connect to a socket can use everything served on it, so
+/// the mode decides who may allocate and release addresses.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SocketPermissions {
+    pub mode: u32,
+    /// User ID to give the socket; the user running the plugin when unset
+    pub owner: Option<u32>,
+    /// Group ID to give the socket; the group of the plugin when unset
+    pub group: Option<u32>,
+}
+
+impl SocketPermissions {
+    pub fn new(mode: u32) -> Self {
+        Self {
+            mode,
+            owner: None,
+            group: None,
+        }
+    }
+
+    /// Parse an octal mode such as `0660`
+    pub fn parse_mode(s: &str) -> anyhow::Result<u32> {
+        let digits = s.trim().trim_start_matches("0o");
+        let mode = u32::from_str_radix(digits, 8)
+            .with_context(|| format!("Invalid socket mode {:?}, expected octal", s))?;
+        if mode > 0o777 {
+            anyhow::bail!("Invalid socket mode {:?}, expected at most 0777", s);
+        }
+        Ok(mode)
+    }
+
+    /// Give the socket to a user, by name or numeric ID
+    pub fn with_owner(mut self, user: &str) -> anyhow::Result<Self> {
+        self.owner = Some(lookup_id(user, "user", |name, buf| unsafe {
+            let mut entry: libc::passwd = std::mem::zeroed();
+            let mut found = std::ptr::null_mut();
+            let rc = libc::getpwnam_r(
+                name.as_ptr(),
+                &mut entry,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut found,
+            );
+            (rc, (!found.is_null()).then_some(entry.pw_uid))
+        })?);
+        Ok(self)
+    }
+
+    /// Give the socket to a group, by name or numeric ID
+    pub fn with_group(mut self, group: &str) -> anyhow::Result<Self> {
+        self.group = Some(lookup_id(group, "group", |name, buf| unsafe {
+            let mut entry: libc::group = std::mem::zeroed();
+            let mut found = std::ptr::null_mut();
+            let rc = libc::getgrnam_r(
+                name.as_ptr(),
+                &mut entry,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut found,
+            );
+            (rc, (!found.is_null()).then_some(entry.gr_gid))
+        })?);
+        Ok(self)
+    }
+}
+
+/// Only the user running the plugin, normally root, may connect
+impl Default for SocketPermissions {
+    fn default() -> Self {
+        Self::new(0o600)
+    }
+}
+
+/// Resolve a user or group `name` with `getxxnam_r`, which fills the buffer
+/// it is given and returns an error code and the ID if found; a numeric name
+/// is taken as the ID itself
+fn lookup_id(
+    name: &str,
+    kind: &str,
+    getxxnam_r: impl Fn(&std::ffi::CStr, &mut [libc::c_char]) -> (libc::c_int, Option<u32>),
+) -> anyhow::Result<u32> {
+    if let Ok(id) = name.parse() {
+        return Ok(id);
+    }
+    let c_name = std::ffi::CString::new(name)?;
+    let mut buf = vec![0 as libc::c_char; 1024];
+    loop {
+        match getxxnam_r(&c_name, &mut buf) {
+            (libc::ERANGE, _) if buf.len() < 1 << 20 => buf.resize(buf.len() * 2, 0),
+            (0, Some(id)) => return Ok(id),
+            (0, None) => anyhow::bail!("Unknown {} {:?}", kind, name),
+            (rc, _) => {
+                return Err(std::io::Error::from_raw_os_error(rc))
+                    .with_context(|| format!("Failed to look up {} {:?}", kind, name))
+            }
+        }
+    }
+}
+
+/// Bind a Unix socket at `socket_path`, replacing a stale one, and set its
+/// mode and ownership
+pub(crate) fn bind_unix(
+    socket_path: &str,
+    permissions: &SocketPermissions,
+) -> anyhow::Result<UnixListener> {
+    // Remove existing socket if it exists
+    let _ = std::fs::remove_file(socket_path);
+
+    // Ensure parent directory exists
+    if let Some(parent) = std::path::Path::new(socket_path).parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let listener = UnixListener::bind(socket_path)?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(
+            socket_path,
+            std::fs::Permissions::from_mode(permissions.mode),
+        )?;
+        if permissions.owner.is_some() || permissions.group.is_some() {
+            std::os::unix::fs::chown(socket_path, permissions.owner, permissions.group)
+                .with_context(|| format!("Failed to change the owner of {}", socket_path))?;
+        }
+    }
+
+    Ok(listener)
+}
+
+/// Serve every connection accepted on `listener` with `handler` until
+/// `shutdown` resolves, then stop accepting and give the open connections
+/// up to `drain_timeout` to finish the requests they are serving
+pub(crate) async fn serve_until<H, F>(
+    listener: UnixListener,
+    handler: H,
+    shutdown: impl Future<Output = ()>,
+    drain_timeout: Duration,
+) -> anyhow::Result<()>
+where
+    H: Fn(Request<Body>) -> F + Clone + Send + 'static,
+    F: Future<Output = Result<Response<Body>, Infallible>> + Send + 'static,
+{
+    let (stop, stopping) = tokio::sync::watch::channel(false);
+    // Every connection holds a sender, so `recv` returns `None` once all
+    // of them are closed
+    let (open, mut closed) = tokio::sync::mpsc::channel::<()>(1);
+    tokio::pin!(shutdown);
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = &mut shutdown => break,
+        };
+        match accepted {
+            Ok((stream, _)) => {
+                let handler = handler.clone();
+                let mut stopping = stopping.clone();
+                let open = open.clone();
+                tokio::spawn(async move {
+                    let conn = hyper::server::conn::Http::new()
+                        .serve_connection(stream, service_fn(handler));
+                    tokio::pin!(conn);
+                    let result = tokio::select! {
+                        result = conn.as_mut() => result,
+                        _ = stopping.changed() => {
+                            // Finish the request in progress, then close
+                            conn.as_mut().graceful_shutdown();
+                            conn.await
+                        }
+                    };
+                    if let Err(e) = result {
+                        tracing::error!("Error serving connection: {}", e);
+                    }
+                    drop(open);
+                });
+            }
+            Err(e) => {
+                tracing::error!("Error accepting connection: {}", e);
+            }
+        }
+    }
+
+    drop(listener);
+    let _ = stop.send(true);
+    drop(open);
+    if tokio::time::timeout(drain_timeout, closed.recv())
+        .await
+        .is_err()
+    {
+        tracing::warn!(
+            "Requests still running after {:?}, stopping anyway",
+            drain_timeout
+        );
+    }
+    Ok(())
+}
+
+/// Handle health probes, then the plugin API
+async fn serve_request(
+    req: Request<Body>,
+    plugin: Arc<IpamPlugin>,
+    bound: Arc<AtomicBool>,
+) -> Result<Response<Body>, Infallible> {
+    match (req.method(), req.uri().path()) {
+        (&Method::GET, "/health") => Ok(json_response(serde_json::json!({ "status": "ok" }))),
+        (&Method::GET, "/ready") => {
+            let readiness = readiness(&plugin, bound.load(Ordering::SeqCst)).await;
+            let status = if readiness.ready {
+                StatusCode::OK
+            } else {
+                StatusCode::SERVICE_UNAVAILABLE
+            };
+            let mut response = json_response(readiness);
+            *response.status_mut() = status;
+            Ok(response)
+        }
+        _ => handle_request(req, plugin).await,
+    }
+}
+
+/// One `/ready` check
+#[derive(Debug, Serialize)]
+pub struct Check {
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Check {
+    fn of(result: anyhow::Result<()>) -> Self {
+        match result {
+            Ok(()) => Self {
+                ok: true,
+                error: None,
+            },
+            Err(e) => Self {
+                ok: false,
+                error: Some(format!("{:#}", e)),
+            },
+        }
+    }
+}
+
+/// `/ready` body: ready when every check passes
+#[derive(Debug, Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    pub checks: BTreeMap<&'static str, Check>,
+}
+
+/// Whether the plugin can serve requests: its state is loaded and not stuck
+/// behind a lock, the state file can be written, the last save succeeded
+/// and the socket is bound
+pub async fn readiness(plugin: &IpamPlugin, socket_bound: bool) -> Readiness {
+    let storage = plugin.storage();
+    let mut checks = BTreeMap::new();
+    checks.insert(
+        "storage",
+        Check::of(
+            match tokio::time::timeout(STORAGE_TIMEOUT, storage.read()).await {
+                Ok(_) => Ok(()),
+                Err(_) => Err(anyhow::anyhow!(
+                    "State lock not available within {:?}",
+                    STORAGE_TIMEOUT
+                )),
+            },
+        ),
+    );
+    checks.insert("state_writable", Check::of(storage.check_writable()));
+    checks.insert(
+        "last_save",
+        Check::of(match storage.last_save().and_then(|s| s.error) {
+            Some(error) => Err(anyhow::anyhow!(error)),
+            None => Ok(()),
+        }),
+    );
+    checks.insert(
+        "socket",
+        Check::of(if socket_bound {
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!("Not bound yet"))
+        }),
+    );
+    Readiness {
+        ready: checks.values().all(|c| c.ok),
+        checks,
+    }
+}
+
 /// Handle incoming HTTP requests
 async fn handle_request(
     req: Request<Body>,
@@ -96,77 +520,105 @@ async fn handle_request(
 
     tracing::debug!("{} {}", method, path);
 
-    let response = match (&method, path.as_str()) {
-        (&Method::POST, "/Plugin.Activate") => json_response(serde_json::json!({
+    let started = Instant::now();
+    let response = match dispatch(req, &plugin).await {
+        Some(result) => {
+            if let (Some(metrics), Some(endpoint)) =
+                (plugin.metrics(), path.strip_prefix("/IpamDriver."))
+            {
+                metrics.record_request(endpoint, result.is_ok(), started.elapsed());
+            }
+            result.unwrap_or_else(|e| error_response(&e))
+        }
+        None => {
+            tracing::warn!("Unknown endpoint: {} {}", method, path);
+            Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::from("Not Found"))
+                .unwrap()
+        }
+    };
+
+    Ok(response)
+}
+
+/// Run the endpoint `req` is for; `None` if there is no such endpoint
+async fn dispatch(
+    req: Request<Body>,
+    plugin: &IpamPlugin,
+) -> Option<Result<Response<Body>, String>> {
+    let response = match (req.method(), req.uri().path()) {
+        (&Method::POST, "/Plugin.Activate") => Ok(json_response(serde_json::json!({
             "Implements": ["IpamDriver"]
-        })),
+        }))),
 
-        (&Method::POST, "/IpamDriver.GetCapabilities") => match plugin.get_capabilities().await {
-            Ok(caps) => json_response(caps),
-            Err(e) => error_response(&e.to_string()),
-        },
+        (&Method::POST, "/IpamDriver.GetCapabilities") => plugin
+            .get_capabilities()
+            .await
+            .map(json_response)
+            .map_err(|e| e.to_string()),
 
         (&Method::POST, "/IpamDriver.GetDefaultAddressSpaces") => {
-            json_response(serde_json::json!({
+            Ok(json_response(serde_json::json!({
                 "LocalDefaultAddressSpace": "local",
                 "GlobalDefaultAddressSpace": "global"
-            }))
+            })))
         }
 
         (&Method::POST, "/IpamDriver.RequestPool") => {
             match parse_body::<RequestPoolRequest>(req).await {
-                Ok(request) => match plugin.request_pool(request).await {
-                    Ok(response) => json_response(response),
-                    Err(e) => error_response(&e.to_string()),
-                },
-                Err(e) => error_response(&e),
+                Ok(request) => plugin
+                    .request_pool(request)
+                    .await
+                    .map(json_response)
+                    .map_err(|e| e.to_string()),
+                Err(e) => Err(e),
             }
         }
 
         (&Method::POST, "/IpamDriver.ReleasePool") => {
             match parse_body::<ReleasePoolRequest>(req).await {
-                Ok(request) => match plugin.release_pool(request).await {
-                    Ok(_) => json_response(serde_json::json!({})),
-                    Err(e) => error_response(&e.to_string()),
-                },
-                Err(e) => error_response(&e),
+                Ok(request) => plugin
+                    .release_pool(request)
+                    .await
+                    .map(|_| json_response(serde_json::json!({})))
+                    .map_err(|e| e.to_string()),
+                Err(e) => Err(e),
             }
         }
 
         (&Method::POST, "/IpamDriver.RequestAddress") => {
             match parse_body::<RequestAddressRequest>(req).await {
-                Ok(request) => match plugin.request_address(request).await {
-                    Ok(response) => json_response(response),
-                    Err(e) => error_response(&e.to_string()),
-                },
-                Err(e) => error_response(&e),
+                Ok(request) => plugin
+                    .request_address(request)
+                    .await
+                    .map(json_response)
+                    .map_err(|e| e.to_string()),
+                Err(e) => Err(e),
             }
         }
 
         (&Method::POST, "/IpamDriver.ReleaseAddress") => {
             match parse_body::<ReleaseAddressRequest>(req).await {
-                Ok(request) => match plugin.release_address(request).await {
-                    Ok(_) => json_response(serde_json::json!({})),
-                    Err(e) => error_response(&e.to_string()),
-                },
-                Err(e) => error_response(&e),
+                Ok(request) => plugin
+                    .release_address(request)
+                    .await
+                    .map(|_| json_response(serde_json::json!({})))
+                    .map_err(|e| e.to_string()),
+                Err(e) => Err(e),
             }
         }
 
-        _ => {
-            tracing::warn!("Unknown endpoint: {} {}", method, path);
-            Response::builder()
-                .status(StatusCode::NOT_FOUND)
-                .body(Body::from("Not Found"))
-                .unwrap()
-        }
+        _ => return None,
     };
 
-    Ok(response)
+    Some(response)
 }
 
 /// Parse request body as JSON
-async fn parse_body<T: serde::de::DeserializeOwned>(req: Request<Body>) -> Result<T, String> {
+pub(crate) async fn parse_body<T: serde::de::DeserializeOwned>(
+    req: Request<Body>,
+) -> Result<T, String> {
     let body_bytes = hyper::body::to_bytes(req.into_body())
         .await
         .map_err(|e| format!("Failed to read body: {}", e))?;
@@ -175,7 +627,7 @@ async fn parse_body<T: serde::de::DeserializeOwned>(req: Request<Body>) -> Resul
 }
 
 /// Create a JSON response
-fn json_response<T: serde::Serialize>(data: T) -> Response<Body> {
+pub(crate) fn json_response<T: serde::Serialize>(data: T) -> Response<Body> {
     let json = serde_json::to_string(&data).unwrap();
     Response::builder()
         .status(StatusCode::OK)
@@ -447,6 +899,248 @@ mod tests {
         assert!(body_str.contains("Err") || body_str.contains("error"));
     }
 
+    #[tokio::test]
+    async fn test_requests_are_counted_per_endpoint() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = Arc::new(
+            Storage::new(&temp_dir.path().join("state.yaml"))
+                .await
+                .unwrap(),
+        );
+        let plugin = Arc::new(
+            IpamPlugin::new(storage, "10.0.0.0/24".to_string())
+                .with_metrics(Arc::new(crate::metrics::Metrics::new())),
+        );
+        for body in [r#"{"Pool": "192.168.40.0/24"}"#, "invalid json"] {
+            let req = Request::builder()
+                .method(Method::POST)
+                .uri("/IpamDriver.RequestPool")
+                .body(Body::from(body))
+                .unwrap();
+            handle_request(req, plugin.clone()).await.unwrap();
+        }
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri("/IpamDriver.Unknown")
+            .body(Body::empty())
+            .unwrap();
+        handle_request(req, plugin.clone()).await.unwrap();
+
+        let text = plugin.metrics().unwrap().render(&plugin).await;
+        assert!(
+            text.contains("ipam_requests_total{endpoint=\"RequestPool\",result=\"success\"} 1\n")
+        );
+        assert!(text.contains("ipam_requests_total{endpoint=\"RequestPool\",result=\"error\"} 1\n"));
+        assert!(!text.contains("endpoint=\"Unknown\""));
+        assert!(text.contains("ipam_pool_size{pool="));
+        assert!(text.contains("subnet=\"192.168.40.0/24\",address_space=\"local\"} 254\n"));
+    }
+
+    async fn probe(plugin: &Arc<IpamPlugin>, bound: bool, path: &str) -> (StatusCode, String) {
+        let req = Request::builder()
+            .method(Method::GET)
+            .uri(path)
+            .body(Body::empty())
+            .unwrap();
+        let response = serve_request(req, plugin.clone(), Arc::new(AtomicBool::new(bound)))
+            .await
+            .unwrap();
+        let status = response.status();
+        let body = to_bytes(response.into_body()).await.unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_health_and_readiness() {
+        let (plugin, temp) = create_test_plugin().await;
+        let (status, body) = probe(&plugin, false, "/health").await;
+        assert_eq!(
+            (status, body.as_str()),
+            (StatusCode::OK, r#"{"status":"ok"}"#)
+        );
+
+        let (status, body) = probe(&plugin, false, "/ready").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        let ready: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(ready["ready"], false);
+        assert_eq!(ready["checks"]["socket"]["ok"], false);
+        assert_eq!(ready["checks"]["storage"]["ok"], true);
+        assert_eq!(probe(&plugin, true, "/ready").await.0, StatusCode::OK);
+
+        // A failed save makes the plugin unready until a save succeeds
+        let blocker = temp.path().join("state.tmp");
+        std::fs::create_dir(&blocker).unwrap();
+        assert!(plugin.storage().save().await.is_err());
+        let (status, body) = probe(&plugin, true, "/ready").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        let ready: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(ready["checks"]["last_save"]["ok"], false);
+        assert!(ready["checks"]["last_save"]["error"]
+            .as_str()
+            .unwrap()
+            .contains("Failed to write state file"));
+        std::fs::remove_dir(&blocker).unwrap();
+        plugin.storage().save().await.unwrap();
+        assert_eq!(probe(&plugin, true, "/ready").await.0, StatusCode::OK);
+
+        // A read-only instance can never save
+        let read_only = Arc::new(
+            Storage::open_read_only(temp.path().join("state.yaml"))
+                .await
+                .unwrap(),
+        );
+        let inspector = Arc::new(IpamPlugin::new(read_only, "10.0.0.0/24".to_string()));
+        let (status, body) = probe(&inspector, true, "/ready").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(body.contains("read-only"));
+
+        // Plugin endpoints are still served
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri("/Plugin.Activate")
+            .body(Body::empty())
+            .unwrap();
+        let response = serve_request(req, plugin, Arc::new(AtomicBool::new(true)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_drains_requests() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::net::UnixStream;
+
+        let (plugin, temp) = create_test_plugin().await;
+        let pool_req = Request::builder()
+            .method(Method::POST)
+            .uri("/IpamDriver.RequestPool")
+            .body(Body::from(r#"{"Pool": "192.168.30.0/24"}"#))
+            .unwrap();
+        let pool_response = handle_request(pool_req, plugin.clone()).await.unwrap();
+        let pool_body_bytes = to_bytes(pool_response.into_body()).await.unwrap();
+        let pool_resp: RequestPoolResponse = serde_json::from_slice(&pool_body_bytes).unwrap();
+
+        let socket = temp.path().join("ipam.sock");
+        let socket_path = socket.to_str().unwrap().to_string();
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let server = PluginServer::new(plugin.clone());
+        let serving = tokio::spawn(async move {
+            server
+                .serve_unix(&socket_path, async {
+                    let _ = stopped.await;
+                })
+                .await
+        });
+        while !socket.exists() {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+
+        // Hold the state so that the request is still running at shutdown
+        let state = plugin.storage().write().await;
+        let body = serde_json::json!({ "PoolID": pool_resp.pool_id }).to_string();
+        let mut client = UnixStream::connect(&socket).await.unwrap();
+        client
+            .write_all(
+                format!(
+                    "POST /IpamDriver.RequestAddress HTTP/1.1\r\nHost: localhost\r\n\
+                     Content-Length: {}\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        stop.send(()).unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(UnixStream::connect(&socket).await.is_err());
+        assert!(!serving.is_finished());
+
+        drop(state);
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.contains("192.168.30."));
+
+        serving.await.unwrap().unwrap();
+        assert!(!socket.exists());
+        let on_disk = Storage::open_read_only(temp.path().join("state.yaml"))
+            .await
+            .unwrap();
+        assert_eq!(on_disk.read().await.leases.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_gives_up_after_drain_timeout() {
+        let (plugin, temp) = create_test_plugin().await;
+        let socket = temp.path().join("ipam.sock");
+        let socket_path = socket.to_str().unwrap().to_string();
+        let server = PluginServer::new(plugin).with_drain_timeout(Duration::from_millis(50));
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let serving = tokio::spawn(async move {
+            server
+                .serve_unix(&socket_path, async {
+                    let _ = stopped.await;
+                })
+                .await
+        });
+        while !socket.exists() {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+
+        // A client that never finishes its request
+        let mut client = tokio::net::UnixStream::connect(&socket).await.unwrap();
+        tokio::io::AsyncWriteExt::write_all(&mut client, b"POST /Plugin.Activate HTTP/1.1\r\n")
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        stop.send(()).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), serving)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert!(!socket.exists());
+    }
+
+    #[test]
+    fn test_socket_permissions() {
+        assert_eq!(SocketPermissions::default().mode, 0o600);
+        assert_eq!(SocketPermissions::parse_mode("0660").unwrap(), 0o660);
+        assert_eq!(SocketPermissions::parse_mode("0o600").unwrap(), 0o600);
+        assert!(SocketPermissions::parse_mode("0668").is_err());
+        assert!(SocketPermissions::parse_mode("1777").is_err());
+
+        let root = SocketPermissions::new(0o600)
+            .with_owner("root")
+            .unwrap()
+            .with_group("0")
+            .unwrap();
+        assert_eq!((root.owner, root.group), (Some(0), Some(0)));
+        let e = SocketPermissions::default()
+            .with_group("no-such-group-here")
+            .unwrap_err();
+        assert_eq!(e.to_string(), r#"Unknown group "no-such-group-here""#);
+    }
+
+    #[tokio::test]
+    async fn test_bind_unix_sets_permissions() {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        let temp = TempDir::new().unwrap();
+        let socket = temp.path().join("admin.sock");
+        let gid = unsafe { libc::getgid() };
+        let permissions = SocketPermissions::new(0o640)
+            .with_group(&gid.to_string())
+            .unwrap();
+        let _listener = bind_unix(socket.to_str().unwrap(), &permissions).unwrap();
+        let metadata = std::fs::metadata(&socket).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o640);
+        assert_eq!(metadata.gid(), gid);
+    }
+
     #[tokio::test]
     async fn test_json_response_helper() {
         let data = serde_json::json!({
@@ -1,40 +1,278 @@
+use crate::crypto::{DecryptError, Keyring};
+use crate::diff::StateDiff;
+use crate::ipam::NotFoundError;
+use crate::metrics::Metrics;
+use crate::migrations::{self, NewerVersionError, CURRENT_VERSION};
 use crate::types::IpamState;
-use anyhow::{Context, Result};
+use crate::validate::{self, Diagnostic, ValidationError};
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{DateTime, Utc};
+use std::io::{Read, Seek, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::fs;
-use tokio::sync::RwLock;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{oneshot, Mutex, RwLock};
+
+/// Tunables for how `Storage` persists state
+#[derive(Debug, Clone)]
+pub struct StorageOptions {
+    /// Number of rotating backups (`<file>.bak.1` is the newest) that `save`
+    /// keeps of the previous state file. Zero disables backups.
+    pub backups: usize,
+    /// When set, `commit` groups changes arriving within this window into a
+    /// single save instead of saving once per change
+    pub commit_window: Option<Duration>,
+    /// Refuse to load state that fails validation with error-level
+    /// diagnostics, instead of logging them and carrying on
+    pub strict: bool,
+    /// Keys for encrypting the state file and its backups at rest
+    pub keyring: Keyring,
+    /// Where to record how long saves take and how often they fail
+    pub metrics: Option<Arc<Metrics>>,
+}
+
+impl Default for StorageOptions {
+    fn default() -> Self {
+        Self {
+            backups: 3,
+            commit_window: None,
+            strict: false,
+            keyring: Keyring::default(),
+            metrics: None,
+        }
+    }
+}
+
+/// Outcome of the last `save`
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct SaveStatus {
+    pub time: DateTime<Utc>,
+    /// Why the save failed; `None` if it succeeded
+    pub error: Option<String>,
+}
+
+/// A named point-in-time copy of the state
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct SnapshotInfo {
+    pub name: String,
+    pub created: DateTime<Utc>,
+    pub pools: usize,
+    pub leases: usize,
+}
+
+/// A snapshot name that cannot be used
+#[derive(Debug)]
+pub enum SnapshotError {
+    InvalidName(String),
+    Exists(String),
+}
+
+impl std::fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidName(name) => write!(
+                f,
+                "Invalid snapshot name {:?}: use letters, digits, '-', '_' and '.'",
+                name
+            ),
+            Self::Exists(name) => write!(f, "Snapshot {:?} already exists", name),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
 
 /// Manages persistence of IPAM state to a YAML file
 pub struct Storage {
     file_path: PathBuf,
+    options: StorageOptions,
     state: RwLock<IpamState>,
+    /// Contents of the state file as last read or written by this instance,
+    /// used to tell our own writes apart from external edits
+    last_written: std::sync::Mutex<Option<String>>,
+    /// Serializes writers of the state file
+    save_lock: Mutex<()>,
+    /// Callers of `commit` waiting for the next group save
+    pending_commits: std::sync::Mutex<Vec<oneshot::Sender<Result<(), String>>>>,
+    /// Problems found the last time state was loaded from disk
+    diagnostics: std::sync::Mutex<Vec<Diagnostic>>,
+    last_save: std::sync::Mutex<Option<SaveStatus>>,
+    /// Held for the lifetime of the instance; `None` in read-only mode
+    lock: Option<StateLock>,
 }
 
 impl Storage {
-    /// Create a new Storage instance
+    /// Create a new Storage instance with default options
     pub async fn new(file_path: impl AsRef<Path>) -> Result<Self> {
+        Self::with_options(file_path, StorageOptions::default()).await
+    }
+
+    /// Create a new Storage instance
+    ///
+    /// Takes an exclusive lock on `<file>.lock` so that no other process can
+    /// open the same state file for writing at the same time. If the state
+    /// file cannot be parsed it is moved aside and the newest valid backup is
+    /// loaded instead.
+    pub async fn with_options(
+        file_path: impl AsRef<Path>,
+        options: StorageOptions,
+    ) -> Result<Self> {
         let file_path = file_path.as_ref().to_path_buf();
 
+        // Create parent directory if it doesn't exist
+        if let Some(parent) = file_path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+
+        let lock = StateLock::acquire(&sibling_path(&file_path, ".lock"))?;
+
         // Try to load existing state or create new one
-        let state = if file_path.exists() {
-            let contents = fs::read_to_string(&file_path)
-                .await
-                .context("Failed to read state file")?;
-            serde_yaml::from_str(&contents).context("Failed to parse state file")?
-        } else {
-            // Create parent directory if it doesn't exist
-            if let Some(parent) = file_path.parent() {
-                fs::create_dir_all(parent).await?;
+        let mut recovered = false;
+        let loaded = if file_path.exists() {
+            match read_state(&file_path, &options).await {
+                Ok(loaded) => loaded,
+                Err(e)
+                    if e.downcast_ref::<NewerVersionError>().is_some()
+                        || e.downcast_ref::<ValidationError>().is_some()
+                        || e.downcast_ref::<DecryptError>().is_some() =>
+                {
+                    return Err(e)
+                }
+                Err(e) => {
+                    recovered = true;
+                    recover_from_backups(&file_path, &options, e).await?
+                }
             }
-            IpamState::default()
+        } else {
+            Loaded::empty()
         };
+        let Loaded {
+            state,
+            from_version,
+            diagnostics,
+        } = loaded;
+        for d in &diagnostics {
+            d.log(&file_path.display().to_string());
+        }
 
-        Ok(Self {
+        let last_written = if recovered || from_version < CURRENT_VERSION {
+            None
+        } else {
+            read_contents(&file_path).await.ok()
+        };
+
+        let storage = Self {
             file_path,
+            options,
             state: RwLock::new(state),
+            last_written: std::sync::Mutex::new(last_written),
+            save_lock: Mutex::new(()),
+            pending_commits: std::sync::Mutex::new(Vec::new()),
+            diagnostics: std::sync::Mutex::new(diagnostics),
+            last_save: std::sync::Mutex::new(None),
+            lock: Some(lock),
+        };
+
+        if recovered {
+            // Put a valid state file back in place straight away
+            storage.save().await?;
+        } else if from_version < CURRENT_VERSION {
+            // Keep the pre-migration file around, then persist the upgraded layout
+            let backup = sibling_path(&storage.file_path, &format!(".v{}.bak", from_version));
+            fs::copy(&storage.file_path, &backup)
+                .await
+                .with_context(|| format!("Failed to back up state file to {:?}", backup))?;
+            tracing::info!(
+                "State file upgraded from version {} to {}; previous file kept at {:?}",
+                from_version,
+                CURRENT_VERSION,
+                backup
+            );
+            storage.save().await?;
+        }
+
+        Ok(storage)
+    }
+
+    /// Open an existing state file for inspection only
+    ///
+    /// No lock is taken, so this works while the plugin is running. The
+    /// returned instance refuses to `save`.
+    pub async fn open_read_only(file_path: impl AsRef<Path>) -> Result<Self> {
+        Self::open_read_only_with_options(file_path, StorageOptions::default()).await
+    }
+
+    /// Like `open_read_only`, but with options such as the keys needed to
+    /// decrypt an encrypted state file
+    pub async fn open_read_only_with_options(
+        file_path: impl AsRef<Path>,
+        options: StorageOptions,
+    ) -> Result<Self> {
+        let file_path = file_path.as_ref().to_path_buf();
+        let options = StorageOptions {
+            strict: false,
+            ..options
+        };
+
+        let loaded = read_state(&file_path, &options)
+            .await
+            .with_context(|| format!("Failed to load state file {:?}", file_path))?;
+
+        Ok(Self {
+            file_path,
+            options,
+            state: RwLock::new(loaded.state),
+            last_written: std::sync::Mutex::new(None),
+            save_lock: Mutex::new(()),
+            pending_commits: std::sync::Mutex::new(Vec::new()),
+            diagnostics: std::sync::Mutex::new(loaded.diagnostics),
+            last_save: std::sync::Mutex::new(None),
+            lock: None,
         })
     }
 
+    /// Path of the state file backing this instance
+    pub fn file_path(&self) -> &Path {
+        &self.file_path
+    }
+
+    /// Problems found by validation the last time state was loaded or reloaded
+    pub fn diagnostics(&self) -> Vec<Diagnostic> {
+        self.diagnostics.lock().unwrap().clone()
+    }
+
+    /// The outcome of the last `save`, if there was one
+    pub fn last_save(&self) -> Option<SaveStatus> {
+        self.last_save.lock().unwrap().clone()
+    }
+
+    /// Check that `save` could write the state file: this instance is not
+    /// read-only and the directory holding the file is writable
+    pub fn check_writable(&self) -> Result<()> {
+        if self.is_read_only() {
+            bail!("State file {:?} is opened read-only", self.file_path);
+        }
+        let dir = match self.file_path.parent() {
+            Some(p) if !p.as_os_str().is_empty() => p,
+            _ => Path::new("."),
+        };
+        let c_dir = std::ffi::CString::new(dir.as_os_str().as_bytes())?;
+        if unsafe { libc::access(c_dir.as_ptr(), libc::W_OK) } != 0 {
+            return Err(std::io::Error::last_os_error())
+                .with_context(|| format!("State directory {:?} is not writable", dir));
+        }
+        Ok(())
+    }
+
+    /// Whether this instance was opened with `open_read_only`
+    pub fn is_read_only(&self) -> bool {
+        self.lock.is_none()
+    }
+
     /// Get a read-only reference to the state
     pub async fn read(&self) -> tokio::sync::RwLockReadGuard<'_, IpamState> {
         self.state.read().await
@@ -47,14 +285,44 @@ impl Storage {
 
     /// Persist the current state to the YAML file
     pub async fn save(&self) -> Result<()> {
-        let state = self.state.read().await;
-        let yaml = serde_yaml::to_string(&*state).context("Failed to serialize state")?;
+        if self.is_read_only() {
+            bail!("State file {:?} is opened read-only", self.file_path);
+        }
+
+        let _guard = self.save_lock.lock().await;
+        let started = Instant::now();
+        let result = self.write_state_file().await;
+        if let Some(metrics) = &self.options.metrics {
+            metrics.record_save(result.is_ok(), started.elapsed());
+        }
+        *self.last_save.lock().unwrap() = Some(SaveStatus {
+            time: Utc::now(),
+            error: result.as_ref().err().map(|e| format!("{:#}", e)),
+        });
+        result
+    }
+
+    async fn write_state_file(&self) -> Result<()> {
+        let yaml = {
+            let state = self.state.read().await;
+            encode_state(&state, &self.options.keyring)?
+        };
+        *self.last_written.lock().unwrap() = Some(yaml.clone());
 
         // Write to a temp file first, then rename for atomicity
         let temp_path = self.file_path.with_extension("tmp");
-        fs::write(&temp_path, yaml)
+        let mut file = fs::File::create(&temp_path)
+            .await
+            .context("Failed to write state file")?;
+        file.write_all(yaml.as_bytes())
             .await
             .context("Failed to write state file")?;
+        file.sync_all()
+            .await
+            .context("Failed to flush state file")?;
+        drop(file);
+
+        self.rotate_backups().await?;
 
         fs::rename(&temp_path, &self.file_path)
             .await
@@ -64,21 +332,408 @@ impl Storage {
         Ok(())
     }
 
+    /// Make the changes made so far durable
+    ///
+    /// Without a `commit_window` this is just `save`. With one, the first
+    /// caller opens a batch that is flushed by a single `save` once the window
+    /// elapses, and every caller that joined the batch returns only after
+    /// that save has completed (or fails with its error).
+    pub async fn commit(self: &Arc<Self>) -> Result<()> {
+        let Some(window) = self.options.commit_window else {
+            return self.save().await;
+        };
+
+        let (tx, rx) = oneshot::channel();
+        let opens_batch = {
+            let mut pending = self.pending_commits.lock().unwrap();
+            pending.push(tx);
+            pending.len() == 1
+        };
+
+        // The flush runs in its own task so that a caller going away (e.g. a
+        // dropped connection) cannot strand the rest of the batch
+        if opens_batch {
+            let storage = self.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(window).await;
+                storage.flush_pending_commits().await;
+            });
+        }
+
+        match rx.await {
+            Ok(result) => result.map_err(|e| anyhow!(e)),
+            Err(_) => Err(anyhow!("State flush was abandoned")),
+        }
+    }
+
+    async fn flush_pending_commits(&self) {
+        let waiters = std::mem::take(&mut *self.pending_commits.lock().unwrap());
+        let result = self.save().await.map_err(|e| format!("{:#}", e));
+        if waiters.len() > 1 {
+            tracing::debug!("Group commit flushed {} changes", waiters.len());
+        }
+        for waiter in waiters {
+            let _ = waiter.send(result.clone());
+        }
+    }
+
+    /// Shift `<file>.bak.N` up by one and copy the current file to `.bak.1`
+    async fn rotate_backups(&self) -> Result<()> {
+        let count = self.options.backups;
+        if count == 0 || !self.file_path.exists() {
+            return Ok(());
+        }
+
+        for n in (1..count).rev() {
+            let from = backup_path(&self.file_path, n);
+            if from.exists() {
+                fs::rename(&from, backup_path(&self.file_path, n + 1))
+                    .await
+                    .context("Failed to rotate state backups")?;
+            }
+        }
+        fs::copy(&self.file_path, backup_path(&self.file_path, 1))
+            .await
+            .context("Failed to back up state file")?;
+        Ok(())
+    }
+
     /// Reload state from disk
-    #[allow(dead_code)]
-    pub async fn reload(&self) -> Result<()> {
-        if self.file_path.exists() {
-            let contents = fs::read_to_string(&self.file_path)
-                .await
-                .context("Failed to read state file")?;
-            let new_state: IpamState =
-                serde_yaml::from_str(&contents).context("Failed to parse state file")?;
+    ///
+    /// The new content is fully parsed before it replaces the in-memory
+    /// state, so an invalid file leaves the current state untouched.
+    pub async fn reload(&self) -> Result<StateDiff> {
+        if !self.file_path.exists() {
+            return Ok(StateDiff::default());
+        }
+        let contents = read_contents(&self.file_path).await?;
+        self.swap_in(contents).await
+    }
 
+    /// Reload state only if the file differs from what this instance last
+    /// read or wrote, i.e. it was edited by someone else
+    ///
+    /// Returns `None` when there was nothing to reload.
+    pub async fn reload_if_changed(&self) -> Result<Option<StateDiff>> {
+        if !self.file_path.exists() {
+            return Ok(None);
+        }
+        let contents = read_contents(&self.file_path).await?;
+        if self.last_written.lock().unwrap().as_deref() == Some(contents.as_str()) {
+            return Ok(None);
+        }
+        self.swap_in(contents).await.map(Some)
+    }
+
+    /// Directory holding named snapshots, next to the state file
+    fn snapshot_dir(&self) -> PathBuf {
+        sibling_path(&self.file_path, ".snapshots")
+    }
+
+    fn snapshot_path(&self, name: &str) -> Result<PathBuf> {
+        let valid = !name.is_empty()
+            && !name.starts_with('.')
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+        if !valid {
+            return Err(SnapshotError::InvalidName(name.to_string()).into());
+        }
+        Ok(self.snapshot_dir().join(format!("{}.yaml", name)))
+    }
+
+    /// Save a copy of the current state under `name`
+    ///
+    /// Snapshots are written like the state file itself (versioned,
+    /// checksummed and encrypted if a key is configured). This does not
+    /// touch the state file, so it also works on a read-only instance.
+    pub async fn create_snapshot(&self, name: &str) -> Result<SnapshotInfo> {
+        let path = self.snapshot_path(name)?;
+        if path.exists() {
+            return Err(SnapshotError::Exists(name.to_string()).into());
+        }
+        fs::create_dir_all(self.snapshot_dir())
+            .await
+            .context("Failed to create snapshot directory")?;
+
+        let (yaml, pools, leases) = {
+            let state = self.state.read().await;
+            (
+                encode_state(&state, &self.options.keyring)?,
+                state.pools.len(),
+                state.leases.len(),
+            )
+        };
+        let temp_path = path.with_extension("tmp");
+        fs::write(&temp_path, yaml)
+            .await
+            .context("Failed to write snapshot")?;
+        fs::rename(&temp_path, &path)
+            .await
+            .context("Failed to write snapshot")?;
+
+        tracing::info!("Snapshot {:?} created", name);
+        Ok(SnapshotInfo {
+            name: name.to_string(),
+            created: Utc::now(),
+            pools,
+            leases,
+        })
+    }
+
+    /// All snapshots, oldest first
+    pub async fn snapshots(&self) -> Result<Vec<SnapshotInfo>> {
+        let mut snapshots = Vec::new();
+        let mut entries = match fs::read_dir(self.snapshot_dir()).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(snapshots),
+            Err(e) => return Err(e).context("Failed to list snapshots"),
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("yaml") {
+                continue;
+            }
+            let Some(name) = path.file_stem().and_then(|n| n.to_str()) else {
+                continue;
+            };
+            let state = self.load_snapshot(name).await?;
+            let created = entry.metadata().await?.modified()?;
+            snapshots.push(SnapshotInfo {
+                name: name.to_string(),
+                created: created.into(),
+                pools: state.pools.len(),
+                leases: state.leases.len(),
+            });
+        }
+        snapshots.sort_by(|a, b| a.created.cmp(&b.created).then(a.name.cmp(&b.name)));
+        Ok(snapshots)
+    }
+
+    /// Read the state saved in a snapshot
+    pub async fn load_snapshot(&self, name: &str) -> Result<IpamState> {
+        let path = self.snapshot_path(name)?;
+        if !path.exists() {
+            return Err(NotFoundError(format!("Snapshot {:?} not found", name)).into());
+        }
+        let loaded = read_state(&path, &self.options)
+            .await
+            .with_context(|| format!("Snapshot {:?} is unreadable", name))?;
+        Ok(loaded.state)
+    }
+
+    /// What changed in the current state since the snapshot was taken
+    pub async fn diff_snapshot(&self, name: &str) -> Result<StateDiff> {
+        let snapshot = self.load_snapshot(name).await?;
+        let state = self.state.read().await;
+        Ok(StateDiff::between(&snapshot, &state))
+    }
+
+    /// Replace the current state with a snapshot and persist it
+    ///
+    /// Returns what the restore changed.
+    pub async fn restore_snapshot(&self, name: &str) -> Result<StateDiff> {
+        if self.is_read_only() {
+            bail!("State file {:?} is opened read-only", self.file_path);
+        }
+        let snapshot = self.load_snapshot(name).await?;
+        let diff = {
             let mut state = self.state.write().await;
-            *state = new_state;
-            tracing::debug!("State reloaded from {:?}", self.file_path);
+            let diff = StateDiff::between(&state, &snapshot);
+            *state = snapshot;
+            diff
+        };
+        self.save().await?;
+        tracing::warn!("State restored from snapshot {:?}: {}", name, diff);
+        Ok(diff)
+    }
+
+    pub async fn delete_snapshot(&self, name: &str) -> Result<()> {
+        let path = self.snapshot_path(name)?;
+        fs::remove_file(&path)
+            .await
+            .with_context(|| format!("Failed to delete snapshot {:?}", name))
+    }
+
+    async fn swap_in(&self, contents: String) -> Result<StateDiff> {
+        let loaded = decode_state(&contents, &self.options)?;
+        for d in &loaded.diagnostics {
+            d.log(&self.file_path.display().to_string());
         }
-        Ok(())
+
+        let mut state = self.state.write().await;
+        let diff = StateDiff::between(&state, &loaded.state);
+        *state = loaded.state;
+        *self.last_written.lock().unwrap() = Some(contents);
+        *self.diagnostics.lock().unwrap() = loaded.diagnostics;
+        tracing::debug!("State reloaded from {:?}", self.file_path);
+        Ok(diff)
+    }
+}
+
+/// Read the state file at `path` as text
+async fn read_contents(path: &Path) -> Result<String> {
+    let bytes = fs::read(path).await.context("Failed to read state file")?;
+    String::from_utf8(bytes).context("State file is not valid UTF-8")
+}
+
+/// Read and parse the state file at `path`
+async fn read_state(path: &Path, options: &StorageOptions) -> Result<Loaded> {
+    decode_state(&read_contents(path).await?, options)
+}
+
+/// Quarantine an unreadable state file and load the newest valid backup
+///
+/// The broken file is only moved aside once a usable backup has been found;
+/// otherwise it is left in place and the original error is returned, so a
+/// restart never comes up with an empty state by accident.
+async fn recover_from_backups(
+    file_path: &Path,
+    options: &StorageOptions,
+    cause: anyhow::Error,
+) -> Result<Loaded> {
+    tracing::error!("State file {:?} is unreadable: {:#}", file_path, cause);
+
+    for n in 1..=options.backups {
+        let backup = backup_path(file_path, n);
+        if !backup.exists() {
+            continue;
+        }
+        match read_state(&backup, options).await {
+            Ok(loaded) => {
+                let quarantine = sibling_path(
+                    file_path,
+                    &format!(".corrupt-{}", Utc::now().format("%Y%m%dT%H%M%SZ")),
+                );
+                fs::rename(file_path, &quarantine)
+                    .await
+                    .context("Failed to quarantine corrupt state file")?;
+                tracing::warn!(
+                    "!!! RECOVERED STATE FROM BACKUP {:?} !!! Changes made after that backup are lost. \
+                     The corrupt file was moved to {:?} for inspection",
+                    backup,
+                    quarantine
+                );
+                return Ok(loaded);
+            }
+            Err(e) => tracing::warn!("Backup {:?} is unusable: {:#}", backup, e),
+        }
+    }
+
+    Err(cause.context(format!(
+        "State file {:?} is corrupt and no valid backup was found",
+        file_path
+    )))
+}
+
+/// A decoded state file
+struct Loaded {
+    state: IpamState,
+    /// Schema version the file was written with
+    from_version: u64,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Loaded {
+    fn empty() -> Self {
+        Self {
+            state: IpamState::default(),
+            from_version: CURRENT_VERSION,
+            diagnostics: Vec::new(),
+        }
+    }
+}
+
+/// Decrypt and parse a state file, verifying its checksum and consistency and
+/// upgrading older schema versions on the way
+///
+/// In strict mode, error-level diagnostics fail the load with a
+/// `ValidationError`.
+fn decode_state(contents: &str, options: &StorageOptions) -> Result<Loaded> {
+    let contents = options.keyring.open(contents)?;
+    let mut doc: serde_yaml::Value =
+        serde_yaml::from_str(&contents).context("Failed to parse state file")?;
+    let mut diagnostics: Vec<Diagnostic> = validate::unseal(&mut doc).into_iter().collect();
+
+    let (doc, from_version) = migrations::migrate(doc)?;
+    let state = serde_yaml::from_value(doc).context("Failed to parse state file")?;
+    diagnostics.extend(validate::validate(&state));
+
+    if options.strict && validate::has_errors(&diagnostics) {
+        return Err(ValidationError { diagnostics }.into());
+    }
+    Ok(Loaded {
+        state,
+        from_version,
+        diagnostics,
+    })
+}
+
+/// Serialize state in the current schema version, with a content checksum,
+/// encrypting it if a key is configured
+fn encode_state(state: &IpamState, keyring: &Keyring) -> Result<String> {
+    let doc = serde_yaml::to_value(state).context("Failed to serialize state")?;
+    let doc = validate::seal(migrations::stamp(doc)?);
+    let yaml = serde_yaml::to_string(&doc).context("Failed to serialize state")?;
+    keyring.seal(&yaml)
+}
+
+/// Path of the `n`th rotating backup, `1` being the newest
+fn backup_path(file_path: &Path, n: usize) -> PathBuf {
+    sibling_path(file_path, &format!(".bak.{}", n))
+}
+
+/// `file_path` with `suffix` appended to its file name, e.g. `state.yaml.lock`
+fn sibling_path(file_path: &Path, suffix: &str) -> PathBuf {
+    let mut name = file_path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// An advisory `flock(2)` lock on the state's lock file
+///
+/// The kernel drops the lock when the file is closed, including when the
+/// holder dies, so a stale lock file never blocks a restart.
+struct StateLock {
+    _file: std::fs::File,
+}
+
+impl StateLock {
+    fn acquire(path: &Path) -> Result<Self> {
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .with_context(|| format!("Failed to open lock file {:?}", path))?;
+
+        let rc = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+        if rc != 0 {
+            let err = std::io::Error::last_os_error();
+            if err.raw_os_error() == Some(libc::EWOULDBLOCK) {
+                let mut holder = String::new();
+                let _ = file.read_to_string(&mut holder);
+                let holder = holder.trim();
+                return Err(anyhow!(
+                    "State file is locked by another process{} (lock file {:?})",
+                    if holder.is_empty() {
+                        String::new()
+                    } else {
+                        format!(" (pid {})", holder)
+                    },
+                    path
+                ));
+            }
+            return Err(err).with_context(|| format!("Failed to lock {:?}", path));
+        }
+
+        // Record our pid so the error above can name the holder
+        file.set_len(0)?;
+        file.rewind()?;
+        write!(file, "{}", std::process::id())?;
+
+        Ok(Self { _file: file })
     }
 }
 
@@ -116,28 +771,33 @@ mod tests {
                 "pool-1".to_string(),
                 PoolInfo {
                     pool_id: "pool-1".to_string(),
-                    subnet: "172.18.0.0/16".to_string(),
+                    subnet: "172.18.0.0/16".parse().unwrap(),
                     gateway: None,
+                    thresholds: None,
                 },
             );
-            state.leases.push(IpLease {
-                ip_address: "172.18.0.2".parse::<IpAddr>().unwrap(),
-                container_name: "test-container".to_string(),
-                lease_time: Utc::now(),
-            });
+            state.leases.push(IpLease::new(
+                "172.18.0.2".parse::<IpAddr>().unwrap(),
+                "test-container".to_string(),
+                Utc::now(),
+            ));
         }
 
         // Save to disk
         storage.save().await.unwrap();
 
         // Create new storage instance from same file
+        drop(storage);
         let storage2 = Storage::new(&state_file).await.unwrap();
         let state = storage2.read().await;
 
         // Verify data was persisted
         assert_eq!(state.pools.len(), 1);
         assert_eq!(state.leases.len(), 1);
-        assert_eq!(state.pools.get("pool-1").unwrap().subnet, "172.18.0.0/16");
+        assert_eq!(
+            state.pools.get("pool-1").unwrap().subnet.to_string(),
+            "172.18.0.0/16"
+        );
         assert_eq!(state.leases[0].container_name, "test-container");
     }
 
@@ -151,11 +811,11 @@ mod tests {
         // Write data
         {
             let mut state = storage.write().await;
-            state.leases.push(IpLease {
-                ip_address: "10.0.0.1".parse::<IpAddr>().unwrap(),
-                container_name: "container1".to_string(),
-                lease_time: Utc::now(),
-            });
+            state.leases.push(IpLease::new(
+                "10.0.0.1".parse::<IpAddr>().unwrap(),
+                "container1".to_string(),
+                Utc::now(),
+            ));
         }
 
         // Read data
@@ -174,11 +834,11 @@ mod tests {
         let storage = Storage::new(&state_file).await.unwrap();
         {
             let mut state = storage.write().await;
-            state.leases.push(IpLease {
-                ip_address: "192.168.1.1".parse::<IpAddr>().unwrap(),
-                container_name: "test".to_string(),
-                lease_time: Utc::now(),
-            });
+            state.leases.push(IpLease::new(
+                "192.168.1.1".parse::<IpAddr>().unwrap(),
+                "test".to_string(),
+                Utc::now(),
+            ));
         }
 
         // Save multiple times to test atomicity
@@ -203,11 +863,11 @@ mod tests {
         // Add some data
         {
             let mut state = storage.write().await;
-            state.leases.push(IpLease {
-                ip_address: "10.0.0.1".parse::<IpAddr>().unwrap(),
-                container_name: "container1".to_string(),
-                lease_time: Utc::now(),
-            });
+            state.leases.push(IpLease::new(
+                "10.0.0.1".parse::<IpAddr>().unwrap(),
+                "container1".to_string(),
+                Utc::now(),
+            ));
         }
         storage.save().await.unwrap();
 
@@ -235,11 +895,11 @@ mod tests {
         let storage = Storage::new(&state_file).await.unwrap();
         {
             let mut state = storage.write().await;
-            state.leases.push(IpLease {
-                ip_address: "10.0.0.2".parse::<IpAddr>().unwrap(),
-                container_name: "test".to_string(),
-                lease_time: Utc::now(),
-            });
+            state.leases.push(IpLease::new(
+                "10.0.0.2".parse::<IpAddr>().unwrap(),
+                "test".to_string(),
+                Utc::now(),
+            ));
         }
         storage.save().await.unwrap();
 
@@ -274,11 +934,11 @@ mod tests {
         // Add some data and save
         {
             let mut state = storage.write().await;
-            state.leases.push(IpLease {
-                ip_address: "10.0.0.3".parse::<IpAddr>().unwrap(),
-                container_name: "container-reload".to_string(),
-                lease_time: Utc::now(),
-            });
+            state.leases.push(IpLease::new(
+                "10.0.0.3".parse::<IpAddr>().unwrap(),
+                "container-reload".to_string(),
+                Utc::now(),
+            ));
         }
         storage.save().await.unwrap();
 
@@ -311,26 +971,28 @@ mod tests {
                 "pool-1".to_string(),
                 PoolInfo {
                     pool_id: "pool-1".to_string(),
-                    subnet: "192.168.1.0/24".to_string(),
-                    gateway: Some("192.168.1.1".to_string()),
+                    subnet: "192.168.1.0/24".parse().unwrap(),
+                    gateway: Some("192.168.1.1".parse().unwrap()),
+                    thresholds: None,
                 },
             );
 
             // Add leases
-            state.leases.push(IpLease {
-                ip_address: "192.168.1.10".parse::<IpAddr>().unwrap(),
-                container_name: "container1".to_string(),
-                lease_time: Utc::now(),
-            });
-            state.leases.push(IpLease {
-                ip_address: "192.168.1.11".parse::<IpAddr>().unwrap(),
-                container_name: "container2".to_string(),
-                lease_time: Utc::now(),
-            });
+            state.leases.push(IpLease::new(
+                "192.168.1.10".parse::<IpAddr>().unwrap(),
+                "container1".to_string(),
+                Utc::now(),
+            ));
+            state.leases.push(IpLease::new(
+                "192.168.1.11".parse::<IpAddr>().unwrap(),
+                "container2".to_string(),
+                Utc::now(),
+            ));
         }
 
         // Save and reload
         storage.save().await.unwrap();
+        drop(storage);
         let new_storage = Storage::new(&state_file).await.unwrap();
 
         // Verify all data persisted
@@ -340,7 +1002,477 @@ mod tests {
         assert!(state.pools.contains_key("pool-1"));
         assert_eq!(
             state.pools.get("pool-1").unwrap().gateway,
-            Some("192.168.1.1".to_string())
+            Some("192.168.1.1".parse().unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_storage_writes_schema_version() {
+        let temp_dir = TempDir::new().unwrap();
+        let state_file = temp_dir.path().join("state.yaml");
+
+        let storage = Storage::new(&state_file).await.unwrap();
+        storage.save().await.unwrap();
+
+        let contents = tokio::fs::read_to_string(&state_file).await.unwrap();
+        assert!(contents.starts_with(&format!("version: {}\n", CURRENT_VERSION)));
+    }
+
+    #[tokio::test]
+    async fn test_storage_migrates_unversioned_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let state_file = temp_dir.path().join("state.yaml");
+        let legacy = "pools:\n  pool-1:\n    pool_id: pool-1\n    subnet: 10.1.0.0/24\n    gateway: null\nleases:\n- ip_address: 10.1.0.5\n  container_name: legacy\n  lease_time: 2025-01-09T10:30:00Z\n";
+        std::fs::write(&state_file, legacy).unwrap();
+
+        let storage = Storage::new(&state_file).await.unwrap();
+        {
+            let state = storage.read().await;
+            assert_eq!(state.pools.len(), 1);
+            assert_eq!(state.leases[0].container_name, "legacy");
+        }
+
+        // Original file is backed up and the upgraded one is written in place
+        let backup = temp_dir.path().join("state.yaml.v0.bak");
+        assert_eq!(std::fs::read_to_string(&backup).unwrap(), legacy);
+        let contents = std::fs::read_to_string(&state_file).unwrap();
+        assert!(contents.starts_with(&format!("version: {}\n", CURRENT_VERSION)));
+    }
+
+    #[tokio::test]
+    async fn test_storage_refuses_newer_version() {
+        let temp_dir = TempDir::new().unwrap();
+        let state_file = temp_dir.path().join("state.yaml");
+        let future = format!(
+            "version: {}\npools: {{}}\nleases: []\n",
+            CURRENT_VERSION + 1
+        );
+        std::fs::write(&state_file, &future).unwrap();
+
+        let result = Storage::new(&state_file).await;
+        assert!(result.is_err());
+
+        // The file must be left untouched
+        assert_eq!(std::fs::read_to_string(&state_file).unwrap(), future);
+    }
+
+    #[tokio::test]
+    async fn test_storage_rotates_backups() {
+        let temp_dir = TempDir::new().unwrap();
+        let state_file = temp_dir.path().join("state.yaml");
+
+        let options = StorageOptions {
+            backups: 2,
+            ..Default::default()
+        };
+        let storage = Storage::with_options(&state_file, options).await.unwrap();
+        for i in 0..4 {
+            storage.write().await.leases.push(IpLease::new(
+                format!("10.0.0.{}", i + 1).parse::<IpAddr>().unwrap(),
+                format!("svc-{}", i),
+                Utc::now(),
+            ));
+            storage.save().await.unwrap();
+        }
+
+        // .bak.1 holds the state before the last save, .bak.2 the one before that
+        let bak1 = std::fs::read_to_string(temp_dir.path().join("state.yaml.bak.1")).unwrap();
+        let bak2 = std::fs::read_to_string(temp_dir.path().join("state.yaml.bak.2")).unwrap();
+        assert!(bak1.contains("svc-2") && !bak1.contains("svc-3"));
+        assert!(bak2.contains("svc-1") && !bak2.contains("svc-2"));
+        assert!(!temp_dir.path().join("state.yaml.bak.3").exists());
+    }
+
+    #[tokio::test]
+    async fn test_storage_recovers_from_corrupt_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let state_file = temp_dir.path().join("state.yaml");
+
+        let storage = Storage::new(&state_file).await.unwrap();
+        storage.write().await.leases.push(IpLease::new(
+            "10.0.0.7".parse::<IpAddr>().unwrap(),
+            "survivor".to_string(),
+            Utc::now(),
+        ));
+        storage.save().await.unwrap();
+        storage.save().await.unwrap();
+        drop(storage);
+
+        std::fs::write(&state_file, "pools: [this is not\n  valid").unwrap();
+
+        let storage = Storage::new(&state_file).await.unwrap();
+        assert_eq!(storage.read().await.leases[0].container_name, "survivor");
+
+        // The broken file is kept for forensics and a valid one is back in place
+        let quarantined: Vec<_> = std::fs::read_dir(temp_dir.path())
+            .unwrap()
+            .filter_map(|e| e.ok())
+            .filter(|e| {
+                e.file_name()
+                    .to_string_lossy()
+                    .starts_with("state.yaml.corrupt-")
+            })
+            .collect();
+        assert_eq!(quarantined.len(), 1);
+        let contents = std::fs::read_to_string(quarantined[0].path()).unwrap();
+        assert!(contents.contains("this is not"));
+        drop(storage);
+        Storage::new(&state_file).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_storage_corrupt_file_without_backups_fails() {
+        let temp_dir = TempDir::new().unwrap();
+        let state_file = temp_dir.path().join("state.yaml");
+        std::fs::write(&state_file, "pools: [broken").unwrap();
+
+        let result = Storage::new(&state_file).await;
+        assert!(result.is_err());
+
+        // Without a backup to fall back to, the file must stay where it is
+        assert_eq!(
+            std::fs::read_to_string(&state_file).unwrap(),
+            "pools: [broken"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_storage_newer_version_does_not_fall_back() {
+        let temp_dir = TempDir::new().unwrap();
+        let state_file = temp_dir.path().join("state.yaml");
+
+        let storage = Storage::new(&state_file).await.unwrap();
+        storage.save().await.unwrap();
+        storage.save().await.unwrap();
+        drop(storage);
+
+        let future = format!(
+            "version: {}\npools: {{}}\nleases: []\n",
+            CURRENT_VERSION + 1
+        );
+        std::fs::write(&state_file, &future).unwrap();
+
+        assert!(Storage::new(&state_file).await.is_err());
+        assert_eq!(std::fs::read_to_string(&state_file).unwrap(), future);
+    }
+
+    #[tokio::test]
+    async fn test_storage_reload_if_changed_ignores_own_writes() {
+        let temp_dir = TempDir::new().unwrap();
+        let state_file = temp_dir.path().join("state.yaml");
+
+        let storage = Storage::new(&state_file).await.unwrap();
+        storage.write().await.leases.push(IpLease::new(
+            "10.0.0.8".parse::<IpAddr>().unwrap(),
+            "mine".to_string(),
+            Utc::now(),
+        ));
+        storage.save().await.unwrap();
+        assert!(storage.reload_if_changed().await.unwrap().is_none());
+
+        // An external edit is picked up and reported
+        let edited = std::fs::read_to_string(&state_file)
+            .unwrap()
+            .replace("container_name: mine", "container_name: theirs");
+        std::fs::write(&state_file, edited).unwrap();
+        let diff = storage.reload_if_changed().await.unwrap().unwrap();
+        assert_eq!(diff.leases_changed.len(), 1);
+        assert_eq!(storage.read().await.leases[0].container_name, "theirs");
+    }
+
+    #[tokio::test]
+    async fn test_storage_reload_rejects_invalid_edit() {
+        let temp_dir = TempDir::new().unwrap();
+        let state_file = temp_dir.path().join("state.yaml");
+
+        let storage = Storage::new(&state_file).await.unwrap();
+        storage.write().await.leases.push(IpLease::new(
+            "10.0.0.9".parse::<IpAddr>().unwrap(),
+            "keep".to_string(),
+            Utc::now(),
+        ));
+        storage.save().await.unwrap();
+
+        std::fs::write(&state_file, "leases: {oops").unwrap();
+        assert!(storage.reload_if_changed().await.is_err());
+        assert_eq!(storage.read().await.leases[0].container_name, "keep");
+    }
+
+    #[tokio::test]
+    async fn test_storage_rejects_malformed_subnet() {
+        let temp_dir = TempDir::new().unwrap();
+        let state_file = temp_dir.path().join("state.yaml");
+
+        let storage = Storage::new(&state_file).await.unwrap();
+        storage.save().await.unwrap();
+
+        let edited = format!(
+            "version: {}\npools:\n  pool-a:\n    pool_id: pool-a\n    subnet: 10.0.0.0/33\n    gateway: null\nleases: []\n",
+            CURRENT_VERSION
+        );
+        std::fs::write(&state_file, &edited).unwrap();
+        assert!(storage.reload_if_changed().await.is_err());
+        assert!(storage.read().await.pools.is_empty());
+
+        drop(storage);
+        assert!(Storage::new(&state_file).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_storage_group_commit_persists_every_change() {
+        let temp_dir = TempDir::new().unwrap();
+        let state_file = temp_dir.path().join("state.yaml");
+        let options = StorageOptions {
+            commit_window: Some(std::time::Duration::from_millis(5)),
+            ..Default::default()
+        };
+        let storage = Arc::new(Storage::with_options(&state_file, options).await.unwrap());
+
+        let mut handles = vec![];
+        for i in 0..50u8 {
+            let storage = storage.clone();
+            handles.push(tokio::spawn(async move {
+                storage.write().await.leases.push(IpLease::new(
+                    IpAddr::from([10, 1, 0, i + 1]),
+                    format!("c{}", i),
+                    Utc::now(),
+                ));
+                storage.commit().await.unwrap();
+            }));
+        }
+        for handle in handles {
+            handle.await.unwrap();
+        }
+
+        // Every caller has returned, so every change must already be on disk
+        let on_disk = Storage::open_read_only(&state_file).await.unwrap();
+        assert_eq!(on_disk.read().await.leases.len(), 50);
+    }
+
+    #[tokio::test]
+    async fn test_storage_group_commit_reports_failures() {
+        let temp_dir = TempDir::new().unwrap();
+        let state_file = temp_dir.path().join("state.yaml");
+        let options = StorageOptions {
+            commit_window: Some(std::time::Duration::from_millis(5)),
+            ..Default::default()
+        };
+        let storage = Arc::new(Storage::with_options(&state_file, options).await.unwrap());
+
+        // A directory in the way of the temp file makes the save fail
+        std::fs::create_dir(state_file.with_extension("tmp")).unwrap();
+        let (a, b) = tokio::join!(storage.commit(), storage.commit());
+        assert!(a.is_err());
+        assert!(b.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_storage_checksum_detects_tampering() {
+        let temp_dir = TempDir::new().unwrap();
+        let state_file = temp_dir.path().join("state.yaml");
+
+        let storage = Storage::new(&state_file).await.unwrap();
+        storage.write().await.leases.push(IpLease::new(
+            "10.0.0.10".parse::<IpAddr>().unwrap(),
+            "original".to_string(),
+            Utc::now(),
+        ));
+        storage.save().await.unwrap();
+        drop(storage);
+
+        let contents = std::fs::read_to_string(&state_file).unwrap();
+        assert!(contents.contains("checksum: sha256:"));
+        std::fs::write(&state_file, contents.replace("original", "mangled")).unwrap();
+
+        // Lenient mode loads the file but reports the mismatch
+        let storage = Storage::new(&state_file).await.unwrap();
+        let codes: Vec<_> = storage.diagnostics().iter().map(|d| d.code).collect();
+        assert!(codes.contains(&"checksum_mismatch"));
+        drop(storage);
+
+        // Strict mode refuses to start, and does not fall back to a backup
+        let options = StorageOptions {
+            strict: true,
+            ..Default::default()
+        };
+        let err = Storage::with_options(&state_file, options)
+            .await
+            .err()
+            .expect("strict load should fail");
+        assert!(err.downcast_ref::<ValidationError>().is_some());
+        assert!(std::fs::read_to_string(&state_file)
+            .unwrap()
+            .contains("mangled"));
+    }
+
+    #[tokio::test]
+    async fn test_storage_strict_reload_rejects_inconsistent_edit() {
+        let temp_dir = TempDir::new().unwrap();
+        let state_file = temp_dir.path().join("state.yaml");
+        let options = StorageOptions {
+            strict: true,
+            ..Default::default()
+        };
+        let storage = Storage::with_options(&state_file, options).await.unwrap();
+        storage.save().await.unwrap();
+
+        // Pool key disagrees with pool_id; no checksum since it was hand-written
+        let edited = format!(
+            "version: {}\npools:\n  pool-a:\n    pool_id: pool-b\n    subnet: 10.0.0.0/24\n    gateway: null\nleases: []\n",
+            CURRENT_VERSION
+        );
+        std::fs::write(&state_file, edited).unwrap();
+        assert!(storage.reload_if_changed().await.is_err());
+        assert!(storage.read().await.pools.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_storage_encryption_and_key_rotation() {
+        use crate::crypto::StateKey;
+
+        let temp_dir = TempDir::new().unwrap();
+        let state_file = temp_dir.path().join("state.yaml");
+        let old_key = StateKey::from_bytes(&[1; 32]).unwrap();
+        let new_key = StateKey::from_bytes(&[2; 32]).unwrap();
+        let options = |primary: &StateKey, retired: Vec<StateKey>| StorageOptions {
+            keyring: Keyring {
+                primary: Some(primary.clone()),
+                retired,
+            },
+            ..Default::default()
+        };
+
+        let storage = Storage::with_options(&state_file, options(&old_key, vec![]))
+            .await
+            .unwrap();
+        storage.write().await.leases.push(IpLease::new(
+            "10.0.0.11".parse::<IpAddr>().unwrap(),
+            "secret-container".to_string(),
+            Utc::now(),
+        ));
+        storage.save().await.unwrap();
+        drop(storage);
+
+        let on_disk = std::fs::read_to_string(&state_file).unwrap();
+        assert!(!on_disk.contains("secret-container"));
+        assert!(on_disk.contains(old_key.id()));
+
+        // Without the key the file cannot be opened, and is not replaced by a backup
+        assert!(Storage::new(&state_file).await.is_err());
+        assert_eq!(std::fs::read_to_string(&state_file).unwrap(), on_disk);
+
+        // Rotate: the retired key opens the file, the next save uses the new one
+        let storage = Storage::with_options(&state_file, options(&new_key, vec![old_key.clone()]))
+            .await
+            .unwrap();
+        assert_eq!(
+            storage.read().await.leases[0].container_name,
+            "secret-container"
+        );
+        storage.save().await.unwrap();
+        drop(storage);
+        assert!(std::fs::read_to_string(&state_file)
+            .unwrap()
+            .contains(new_key.id()));
+
+        // Inspection tools can decrypt with the same keys
+        let inspector =
+            Storage::open_read_only_with_options(&state_file, options(&new_key, vec![]))
+                .await
+                .unwrap();
+        assert_eq!(inspector.read().await.leases.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_storage_lock_rejects_second_instance() {
+        let temp_dir = TempDir::new().unwrap();
+        let state_file = temp_dir.path().join("state.yaml");
+
+        let storage = Storage::new(&state_file).await.unwrap();
+        let result = Storage::new(&state_file).await;
+        let err = result.err().expect("second open should fail");
+        assert!(err.to_string().contains("locked by another process"));
+        assert!(err.to_string().contains(&std::process::id().to_string()));
+
+        // Lock is released once the holder goes away
+        drop(storage);
+        Storage::new(&state_file).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_storage_snapshots() {
+        let temp_dir = TempDir::new().unwrap();
+        let state_file = temp_dir.path().join("state.yaml");
+        let storage = Storage::new(&state_file).await.unwrap();
+        let lease = |ip: &str, name: &str| {
+            IpLease::new(ip.parse::<IpAddr>().unwrap(), name.to_string(), Utc::now())
+        };
+
+        storage.write().await.leases.push(lease("10.0.0.2", "keep"));
+        storage.save().await.unwrap();
+        storage.create_snapshot("before-cleanup").await.unwrap();
+        assert!(storage.create_snapshot("before-cleanup").await.is_err());
+        assert!(storage.create_snapshot("../escape").await.is_err());
+
+        {
+            let mut state = storage.write().await;
+            state.leases.clear();
+            state.leases.push(lease("10.0.0.3", "new"));
+        }
+        storage.save().await.unwrap();
+
+        let snapshots = storage.snapshots().await.unwrap();
+        assert_eq!(snapshots.len(), 1);
+        assert_eq!(snapshots[0].name, "before-cleanup");
+        assert_eq!(snapshots[0].leases, 1);
+
+        let diff = storage.diff_snapshot("before-cleanup").await.unwrap();
+        assert_eq!(diff.leases_removed[0].container_name, "keep");
+        assert_eq!(diff.leases_added[0].container_name, "new");
+
+        let restored = storage.restore_snapshot("before-cleanup").await.unwrap();
+        assert_eq!(restored.leases_added[0].container_name, "keep");
+        drop(storage);
+
+        // The restore was persisted
+        let storage = Storage::new(&state_file).await.unwrap();
+        let state = storage.read().await;
+        assert_eq!(state.leases.len(), 1);
+        assert_eq!(state.leases[0].container_name, "keep");
+        drop(state);
+
+        storage.delete_snapshot("before-cleanup").await.unwrap();
+        assert!(storage.snapshots().await.unwrap().is_empty());
+        assert!(storage
+            .restore_snapshot("before-cleanup")
+            .await
+            .unwrap_err()
+            .is::<NotFoundError>());
+    }
+
+    #[tokio::test]
+    async fn test_storage_read_only_open() {
+        let temp_dir = TempDir::new().unwrap();
+        let state_file = temp_dir.path().join("state.yaml");
+
+        let storage = Storage::new(&state_file).await.unwrap();
+        {
+            let mut state = storage.write().await;
+            state.leases.push(IpLease::new(
+                "10.0.0.4".parse::<IpAddr>().unwrap(),
+                "inspect-me".to_string(),
+                Utc::now(),
+            ));
+        }
+        storage.save().await.unwrap();
+
+        // Read-only open works while the writer still holds the lock
+        let inspector = Storage::open_read_only(&state_file).await.unwrap();
+        assert!(inspector.is_read_only());
+        assert_eq!(
+            inspector.read().await.leases[0].container_name,
+            "inspect-me"
         );
+        assert!(inspector.save().await.is_err());
     }
 }
@@ -0,0 +1,212 @@
+use crate::storage::Storage;
+use anyhow::{bail, Context, Result};
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::FromRawFd;
+use std::os::unix::net::{UnixDatagram, UnixListener};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+/// First file descriptor systemd passes with `LISTEN_FDS`
+const LISTEN_FDS_START: i32 = 3;
+
+/// The listening socket systemd passed through socket activation, if any
+///
+/// Only the first socket is used. `LISTEN_PID` must name this process, so
+/// that a child which inherited the variables does not take the socket.
+pub fn listener() -> Result<Option<UnixListener>> {
+    let fds = listen_fds(
+        std::env::var("LISTEN_PID").ok().as_deref(),
+        std::env::var("LISTEN_FDS").ok().as_deref(),
+        std::process::id(),
+    )?;
+    if fds == 0 {
+        return Ok(None);
+    }
+    if fds > 1 {
+        tracing::warn!("systemd passed {} sockets, only the first is used", fds);
+    }
+
+    unsafe { libc::fcntl(LISTEN_FDS_START, libc::F_SETFD, libc::FD_CLOEXEC) };
+    let listener = unsafe { UnixListener::from_raw_fd(LISTEN_FDS_START) };
+    listener
+        .local_addr()
+        .context("The socket passed by systemd is not a Unix socket")?;
+    listener.set_nonblocking(true)?;
+    Ok(Some(listener))
+}
+
+/// Number of sockets passed to process `own_pid`, from the values of
+/// `LISTEN_PID` and `LISTEN_FDS`
+fn listen_fds(pid: Option<&str>, fds: Option<&str>, own_pid: u32) -> Result<usize> {
+    let (Some(pid), Some(fds)) = (pid, fds) else {
+        return Ok(0);
+    };
+    let pid: u32 = pid.parse().context("Invalid LISTEN_PID")?;
+    if pid != own_pid {
+        return Ok(0);
+    }
+    fds.parse().context("Invalid LISTEN_FDS")
+}
+
+/// Tell systemd about a state change such as `READY=1`, when it asked to be
+/// told through `NOTIFY_SOCKET`; returns whether a message was sent
+pub fn notify(state: &str) -> Result<bool> {
+    let Some(socket) = std::env::var_os("NOTIFY_SOCKET") else {
+        return Ok(false);
+    };
+    notify_socket(&socket, state)
+        .with_context(|| format!("Failed to notify systemd of {:?}", state))?;
+    Ok(true)
+}
+
+/// Send `state` to the notification socket at `path`, which starts with `@`
+/// for an abstract socket
+fn notify_socket(path: &OsStr, state: &str) -> Result<()> {
+    let socket = UnixDatagram::unbound()?;
+    match path.as_bytes().strip_prefix(b"@") {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &addr)?;
+        }
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => bail!("Abstract sockets are only supported on Linux"),
+        None => {
+            socket.send_to(state.as_bytes(), path)?;
+        }
+    }
+    Ok(())
+}
+
+/// How often systemd expects `WATCHDOG=1`, from the values of
+/// `WATCHDOG_USEC` and `WATCHDOG_PID`
+fn watchdog_interval(
+    usec: Option<&str>,
+    pid: Option<&str>,
+    own_pid: u32,
+) -> Result<Option<Duration>> {
+    let Some(usec) = usec else {
+        return Ok(None);
+    };
+    if let Some(pid) = pid {
+        if pid.parse::<u32>().context("Invalid WATCHDOG_PID")? != own_pid {
+            return Ok(None);
+        }
+    }
+    let usec: u64 = usec.parse().context("Invalid WATCHDOG_USEC")?;
+    if usec == 0 {
+        bail!("Invalid WATCHDOG_USEC 0");
+    }
+    Ok(Some(Duration::from_micros(usec)))
+}
+
+/// Sends `WATCHDOG=1` to systemd at half the interval set by `WatchdogSec`
+///
+/// A ping is skipped while the state lock cannot be taken, so that systemd
+/// restarts a plugin that is stuck rather than only one that crashed. Stops
+/// when dropped.
+pub struct Watchdog {
+    task: JoinHandle<()>,
+}
+
+impl Watchdog {
+    /// `None` unless systemd expects watchdog pings from this process
+    pub fn spawn(storage: Arc<Storage>) -> Result<Option<Self>> {
+        let interval = watchdog_interval(
+            std::env::var("WATCHDOG_USEC").ok().as_deref(),
+            std::env::var("WATCHDOG_PID").ok().as_deref(),
+            std::process::id(),
+        )?;
+        let Some(interval) = interval else {
+            return Ok(None);
+        };
+        tracing::info!("Sending systemd watchdog pings every {:?}", interval / 2);
+
+        let task = tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(interval / 2);
+            loop {
+                ticks.tick().await;
+                if tokio::time::timeout(interval / 2, storage.read())
+                    .await
+                    .is_err()
+                {
+                    tracing::warn!(
+                        "State lock held for {:?}, skipping watchdog ping",
+                        interval / 2
+                    );
+                    continue;
+                }
+                if let Err(e) = notify("WATCHDOG=1") {
+                    tracing::warn!("{:#}", e);
+                }
+            }
+        });
+        Ok(Some(Self { task }))
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_listen_fds_are_for_this_process() {
+        assert_eq!(listen_fds(None, None, 42).unwrap(), 0);
+        assert_eq!(listen_fds(Some("42"), Some("1"), 42).unwrap(), 1);
+        // Inherited from a parent that was activated
+        assert_eq!(listen_fds(Some("41"), Some("1"), 42).unwrap(), 0);
+        assert!(listen_fds(Some("42"), Some("many"), 42).is_err());
+    }
+
+    #[test]
+    fn test_watchdog_interval() {
+        assert_eq!(watchdog_interval(None, None, 42).unwrap(), None);
+        assert_eq!(
+            watchdog_interval(Some("30000000"), None, 42).unwrap(),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            watchdog_interval(Some("30000000"), Some("42"), 42).unwrap(),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            watchdog_interval(Some("30000000"), Some("41"), 42).unwrap(),
+            None
+        );
+        assert!(watchdog_interval(Some("0"), None, 42).is_err());
+    }
+
+    #[test]
+    fn test_notify_socket() {
+        let temp = TempDir::new().unwrap();
+        let path = temp.path().join("notify.sock");
+        let receiver = UnixDatagram::bind(&path).unwrap();
+        notify_socket(path.as_os_str(), "READY=1").unwrap();
+        let mut buf = [0; 64];
+        let n = receiver.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1");
+
+        assert!(notify_socket(temp.path().join("missing").as_os_str(), "READY=1").is_err());
+
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::linux::net::SocketAddrExt;
+            let name = format!("docker-ipam-test-{}", std::process::id());
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(&name).unwrap();
+            let receiver = UnixDatagram::bind_addr(&addr).unwrap();
+            notify_socket(OsStr::new(&format!("@{}", name)), "WATCHDOG=1").unwrap();
+            let n = receiver.recv(&mut buf).unwrap();
+            assert_eq!(&buf[..n], b"WATCHDOG=1");
+        }
+    }
+}
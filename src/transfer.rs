@@ -0,0 +1,860 @@
+use crate::address_space::overlaps;
+use crate::ipam::parse_subnet;
+use crate::types::{IpLease, IpamState, PoolInfo};
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{DateTime, Utc};
+use ipnetwork::IpNetwork;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// Formats the state can be exported to and imported from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// `IpamState` as JSON
+    Json,
+    /// One row per lease: ip, pool, subnet, container, lease time
+    Csv,
+    /// An array of networks shaped like `docker network inspect` output
+    Docker,
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "json" => Ok(Self::Json),
+            "csv" => Ok(Self::Csv),
+            "docker" => Ok(Self::Docker),
+            _ => bail!("Unknown format {:?} (expected json, csv or docker)", s),
+        }
+    }
+}
+
+impl Format {
+    /// Guess the format of an import file: `.csv` files are CSV, and a JSON
+    /// array is Docker-inspect output
+    pub fn detect(path: &str, contents: &str) -> Self {
+        if path.ends_with(".csv") {
+            Self::Csv
+        } else if contents.trim_start().starts_with('[') {
+            Self::Docker
+        } else {
+            Self::Json
+        }
+    }
+}
+
+const CSV_HEADER: &str = "ip,pool,subnet,container,lease_time";
+
+pub fn export(state: &IpamState, format: Format) -> Result<String> {
+    match format {
+        Format::Json => {
+            // Going through Value sorts the pool map by ID
+            let value = serde_json::to_value(sorted(state))?;
+            let mut json = serde_json::to_string_pretty(&value)?;
+            json.push('\n');
+            Ok(json)
+        }
+        Format::Csv => Ok(export_csv(state)),
+        Format::Docker => {
+            let mut json = serde_json::to_string_pretty(&export_docker(state))?;
+            json.push('\n');
+            Ok(json)
+        }
+    }
+}
+
+pub fn import(contents: &str, format: Format) -> Result<IpamState> {
+    match format {
+        Format::Json => serde_json::from_str(contents).context("Invalid JSON state"),
+        Format::Csv => import_csv(contents),
+        Format::Docker => {
+            let networks: Vec<DockerNetwork> =
+                serde_json::from_str(contents).context("Invalid docker inspect output")?;
+            import_docker(networks)
+        }
+    }
+}
+
+/// A copy of the state with leases in address order, so exports of the same
+/// state are identical
+fn sorted(state: &IpamState) -> IpamState {
+    let mut state = state.clone();
+    state.leases.sort_by_key(|l| l.ip_address);
+    state
+}
+
+/// The pools in ID order
+fn pools(state: &IpamState) -> Vec<&PoolInfo> {
+    let mut pools: Vec<_> = state.pools.values().collect();
+    pools.sort_by(|a, b| a.pool_id.cmp(&b.pool_id));
+    pools
+}
+
+/// The pool a lease belongs to
+///
+/// Pools may overlap, so a lease that knows its pool is matched by ID and
+/// only older leases without one by address.
+fn pool_of<'a>(pools: &[&'a PoolInfo], lease: &IpLease) -> Option<&'a PoolInfo> {
+    match &lease.pool_id {
+        Some(pool_id) => pools.iter().copied().find(|pool| pool.pool_id == *pool_id),
+        None => pools
+            .iter()
+            .copied()
+            .find(|pool| pool.subnet.contains(lease.ip_address)),
+    }
+}
+
+fn export_csv(state: &IpamState) -> String {
+    let pools = pools(state);
+    let mut out = format!("{}\n", CSV_HEADER);
+    for lease in sorted(state).leases {
+        let (pool_id, subnet) = match pool_of(&pools, &lease) {
+            Some(pool) => (pool.pool_id.clone(), pool.subnet.to_string()),
+            None => (String::new(), String::new()),
+        };
+        let row = [
+            lease.ip_address.to_string(),
+            pool_id,
+            subnet,
+            lease.container_name.clone(),
+            lease.lease_time.to_rfc3339(),
+        ];
+        let row: Vec<String> = row.iter().map(|f| csv_field(f)).collect();
+        out.push_str(&row.join(","));
+        out.push('\n');
+    }
+    out
+}
+
+/// Quote a CSV field if it contains a separator, quote or line break
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Split one CSV line into fields, honouring quotes
+fn parse_csv_line(line: &str) -> Result<Vec<String>> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut chars = line.chars().peekable();
+    let mut quoted = false;
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' if quoted => quoted = false,
+            '"' if field.is_empty() => quoted = true,
+            ',' if !quoted => fields.push(std::mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+    if quoted {
+        bail!("Unterminated quote");
+    }
+    fields.push(field);
+    Ok(fields)
+}
+
+/// Rebuild pools from the pool and subnet columns, so pools without any
+/// lease are not part of a CSV export
+fn import_csv(contents: &str) -> Result<IpamState> {
+    let mut state = IpamState::default();
+    let mut lines = contents.lines().enumerate();
+    match lines.next() {
+        Some((_, header)) if header.trim() == CSV_HEADER => {}
+        _ => bail!("CSV must start with the header {:?}", CSV_HEADER),
+    }
+
+    for (i, line) in lines {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let row = i + 1;
+        let fields = parse_csv_line(line).with_context(|| format!("CSV row {}", row))?;
+        let [ip, pool_id, subnet, container, lease_time] = fields.as_slice() else {
+            bail!("CSV row {} has {} fields, expected 5", row, fields.len());
+        };
+
+        let ip_address: IpAddr = ip
+            .parse()
+            .with_context(|| format!("CSV row {}: invalid IP {:?}", row, ip))?;
+        let lease_time = DateTime::parse_from_rfc3339(lease_time)
+            .with_context(|| format!("CSV row {}: invalid lease time {:?}", row, lease_time))?
+            .with_timezone(&Utc);
+        if !pool_id.is_empty() && !state.pools.contains_key(pool_id) {
+            let subnet = subnet
+                .parse()
+                .with_context(|| format!("CSV row {}: invalid subnet {:?}", row, subnet))?;
+            state.pools.insert(
+                pool_id.clone(),
+                PoolInfo {
+                    pool_id: pool_id.clone(),
+                    subnet,
+                    gateway: None,
+                    thresholds: None,
+                },
+            );
+        }
+        state.leases.push(IpLease {
+            pool_id: Some(pool_id.clone()).filter(|id| !id.is_empty()),
+            ..IpLease::new(ip_address, container.clone(), lease_time)
+        });
+    }
+    Ok(state)
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct DockerNetwork {
+    name: String,
+    id: String,
+    #[serde(rename = "IPAM")]
+    ipam: DockerIpam,
+    #[serde(default)]
+    containers: BTreeMap<String, DockerEndpoint>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct DockerIpam {
+    #[serde(default)]
+    driver: String,
+    #[serde(default)]
+    config: Vec<DockerIpamConfig>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct DockerIpamConfig {
+    subnet: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    gateway: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct DockerEndpoint {
+    #[serde(rename = "Name")]
+    name: String,
+    #[serde(
+        rename = "EndpointID",
+        default,
+        skip_serializing_if = "String::is_empty"
+    )]
+    endpoint_id: String,
+    #[serde(rename = "IPv4Address", default)]
+    ipv4_address: String,
+    #[serde(rename = "IPv6Address", default)]
+    ipv6_address: String,
+}
+
+/// One network per pool. Docker keys containers by ID; leases recorded
+/// without one are keyed by name instead. Lease times are not part of the
+/// format.
+fn export_docker(state: &IpamState) -> Vec<DockerNetwork> {
+    let pools = pools(state);
+    let mut out: Vec<DockerNetwork> = pools
+        .iter()
+        .map(|pool| DockerNetwork {
+            name: pool.pool_id.clone(),
+            id: pool.pool_id.clone(),
+            ipam: DockerIpam {
+                driver: "docker-ipam-plugin".to_string(),
+                config: vec![DockerIpamConfig {
+                    subnet: pool.subnet.to_string(),
+                    gateway: pool.gateway.map(|g| g.to_string()),
+                }],
+            },
+            containers: BTreeMap::new(),
+        })
+        .collect();
+
+    for lease in &state.leases {
+        let Some(pool) = pool_of(&pools, lease) else {
+            continue;
+        };
+        let address = format!("{}/{}", lease.ip_address, pool.subnet.prefix());
+        let (ipv4_address, ipv6_address) = if lease.ip_address.is_ipv4() {
+            (address, String::new())
+        } else {
+            (String::new(), address)
+        };
+        if let Some(net) = out.iter_mut().find(|n| n.id == pool.pool_id) {
+            net.containers.insert(
+                lease
+                    .container_id
+                    .clone()
+                    .unwrap_or_else(|| lease.container_name.clone()),
+                DockerEndpoint {
+                    name: lease.container_name.clone(),
+                    endpoint_id: lease.endpoint_id.clone().unwrap_or_default(),
+                    ipv4_address,
+                    ipv6_address,
+                },
+            );
+        }
+    }
+    out
+}
+
+fn import_docker(networks: Vec<DockerNetwork>) -> Result<IpamState> {
+    let mut state = IpamState::default();
+    let now = Utc::now();
+    for net in networks {
+        let config = net
+            .ipam
+            .config
+            .first()
+            .ok_or_else(|| anyhow!("Network {} has no IPAM config", net.name))?;
+        state.pools.insert(
+            net.id.clone(),
+            PoolInfo {
+                pool_id: net.id.clone(),
+                subnet: parse_subnet(&config.subnet).with_context(|| {
+                    format!(
+                        "Network {} has invalid subnet {:?}",
+                        net.name, config.subnet
+                    )
+                })?,
+                // Docker reports a missing gateway as an empty string
+                gateway: match config.gateway.as_deref() {
+                    None | Some("") => None,
+                    Some(g) => Some(g.parse().with_context(|| {
+                        format!("Network {} has invalid gateway {:?}", net.name, g)
+                    })?),
+                },
+                thresholds: None,
+            },
+        );
+
+        for (key, endpoint) in &net.containers {
+            for address in [&endpoint.ipv4_address, &endpoint.ipv6_address] {
+                if address.is_empty() {
+                    continue;
+                }
+                let ip = address.split('/').next().unwrap_or_default();
+                let ip = ip.parse().with_context(|| {
+                    format!("Invalid address {:?} of {}", address, endpoint.name)
+                })?;
+                state.leases.push(IpLease {
+                    pool_id: Some(net.id.clone()),
+                    network_id: Some(net.id.clone()),
+                    // Our own exports key endpoints without an ID by name
+                    container_id: Some(key.clone()).filter(|k| *k != endpoint.name),
+                    endpoint_id: Some(endpoint.endpoint_id.clone()).filter(|e| !e.is_empty()),
+                    ..IpLease::new(ip, endpoint.name.clone(), now)
+                });
+            }
+        }
+    }
+    Ok(state)
+}
+
+/// Something in an import that could not be merged
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Conflict {
+    /// A pool with this ID already exists with a different subnet
+    PoolSubnet {
+        pool_id: String,
+        existing: IpNetwork,
+        imported: IpNetwork,
+    },
+    /// The address is already leased to another container
+    LeaseTaken {
+        ip_address: IpAddr,
+        existing: String,
+        imported: String,
+    },
+    /// The address is not inside any pool
+    LeaseOutsidePools {
+        ip_address: IpAddr,
+        container_name: String,
+    },
+    /// The lease names a pool that does not contain its address
+    LeaseOutsidePool {
+        ip_address: IpAddr,
+        container_name: String,
+        pool_id: String,
+    },
+    /// The lease names a pool that was skipped
+    LeasePoolSkipped {
+        ip_address: IpAddr,
+        container_name: String,
+        pool_id: String,
+    },
+    /// A new pool overlaps a pool of the global address space
+    PoolOverlap {
+        pool_id: String,
+        subnet: IpNetwork,
+        existing_pool: String,
+        existing_subnet: IpNetwork,
+    },
+}
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::PoolSubnet {
+                pool_id,
+                existing,
+                imported,
+            } => write!(
+                f,
+                "pool {} exists with subnet {}, import has {}",
+                pool_id, existing, imported
+            ),
+            Self::LeaseTaken {
+                ip_address,
+                existing,
+                imported,
+            } => write!(
+                f,
+                "{} is leased to {}, import assigns it to {}",
+                ip_address, existing, imported
+            ),
+            Self::LeaseOutsidePools {
+                ip_address,
+                container_name,
+            } => write!(
+                f,
+                "{} of {} is not inside any pool",
+                ip_address, container_name
+            ),
+            Self::LeaseOutsidePool {
+                ip_address,
+                container_name,
+                pool_id,
+            } => write!(
+                f,
+                "{} of {} is not inside pool {}",
+                ip_address, container_name, pool_id
+            ),
+            Self::LeasePoolSkipped {
+                ip_address,
+                container_name,
+                pool_id,
+            } => write!(
+                f,
+                "{} of {} is in pool {}, which was not imported",
+                ip_address, container_name, pool_id
+            ),
+            Self::PoolOverlap {
+                pool_id,
+                subnet,
+                existing_pool,
+                existing_subnet,
+            } => write!(
+                f,
+                "pool {} has subnet {}, which overlaps {} of global pool {}",
+                pool_id, subnet, existing_subnet, existing_pool
+            ),
+        }
+    }
+}
+
+/// Outcome of merging an import into the state
+#[derive(Debug, Default)]
+pub struct MergeReport {
+    pub pools_added: usize,
+    pub leases_added: usize,
+    /// Entries already present with the same content
+    pub unchanged: usize,
+    /// Entries that were skipped
+    pub conflicts: Vec<Conflict>,
+}
+
+impl fmt::Display for MergeReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} pools added, {} leases added, {} unchanged, {} conflicts",
+            self.pools_added,
+            self.leases_added,
+            self.unchanged,
+            self.conflicts.len()
+        )
+    }
+}
+
+/// Merge `imported` into `state`
+///
+/// Existing entries always win: a conflicting pool or lease is reported and
+/// skipped, never overwritten. Imported pools become local pools, so like
+/// any local pool they may overlap each other but none of the `global`
+/// pools. Local pools may overlap, so leases are matched by pool and
+/// address.
+pub fn merge(state: &mut IpamState, imported: IpamState, global: &[PoolInfo]) -> MergeReport {
+    let mut report = MergeReport::default();
+
+    let mut skipped = Vec::new();
+    let mut pools: Vec<_> = imported.pools.into_values().collect();
+    pools.sort_by(|a, b| a.pool_id.cmp(&b.pool_id));
+    for pool in pools {
+        match state.pools.get(&pool.pool_id) {
+            Some(existing) if existing.subnet == pool.subnet => report.unchanged += 1,
+            Some(existing) => {
+                report.conflicts.push(Conflict::PoolSubnet {
+                    pool_id: pool.pool_id.clone(),
+                    existing: existing.subnet,
+                    imported: pool.subnet,
+                });
+                skipped.push(pool.pool_id);
+            }
+            None => match global.iter().find(|g| overlaps(&g.subnet, &pool.subnet)) {
+                Some(existing) => {
+                    report.conflicts.push(Conflict::PoolOverlap {
+                        pool_id: pool.pool_id.clone(),
+                        subnet: pool.subnet,
+                        existing_pool: existing.pool_id.clone(),
+                        existing_subnet: existing.subnet,
+                    });
+                    skipped.push(pool.pool_id);
+                }
+                None => {
+                    state.pools.insert(pool.pool_id.clone(), pool);
+                    report.pools_added += 1;
+                }
+            },
+        }
+    }
+
+    let mut leased: HashMap<(String, IpAddr), String> = HashMap::new();
+    for lease in &state.leases {
+        for pool_id in owners(state, lease) {
+            leased.insert((pool_id, lease.ip_address), lease.container_name.clone());
+        }
+    }
+    for lease in imported.leases {
+        if let Some(pool_id) = &lease.pool_id {
+            if skipped.contains(pool_id) {
+                report.conflicts.push(Conflict::LeasePoolSkipped {
+                    ip_address: lease.ip_address,
+                    container_name: lease.container_name,
+                    pool_id: pool_id.clone(),
+                });
+                continue;
+            }
+            if !state
+                .pools
+                .get(pool_id)
+                .is_some_and(|p| p.subnet.contains(lease.ip_address))
+            {
+                report.conflicts.push(Conflict::LeaseOutsidePool {
+                    ip_address: lease.ip_address,
+                    container_name: lease.container_name,
+                    pool_id: pool_id.clone(),
+                });
+                continue;
+            }
+        }
+        let pool_ids = owners(state, &lease);
+        if pool_ids.is_empty() {
+            report.conflicts.push(Conflict::LeaseOutsidePools {
+                ip_address: lease.ip_address,
+                container_name: lease.container_name,
+            });
+            continue;
+        }
+        let taken = pool_ids
+            .iter()
+            .find_map(|pool_id| leased.get(&(pool_id.clone(), lease.ip_address)));
+        if let Some(existing) = taken {
+            if *existing == lease.container_name {
+                report.unchanged += 1;
+            } else {
+                report.conflicts.push(Conflict::LeaseTaken {
+                    ip_address: lease.ip_address,
+                    existing: existing.clone(),
+                    imported: lease.container_name,
+                });
+            }
+            continue;
+        }
+        for pool_id in pool_ids {
+            leased.insert((pool_id, lease.ip_address), lease.container_name.clone());
+        }
+        state.leases.push(lease);
+        report.leases_added += 1;
+    }
+
+    report
+}
+
+/// IDs of the pools of `state` a lease counts against: the one it names, or
+/// for leases recorded without one every pool containing the address
+fn owners(state: &IpamState, lease: &IpLease) -> Vec<String> {
+    match &lease.pool_id {
+        Some(pool_id) => vec![pool_id.clone()],
+        None => state
+            .pools
+            .values()
+            .filter(|p| p.subnet.contains(lease.ip_address))
+            .map(|p| p.pool_id.clone())
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> IpamState {
+        let mut state = IpamState::default();
+        for (id, subnet) in [("pool-a", "10.1.0.0/24"), ("pool-b", "fd00::/64")] {
+            state.pools.insert(
+                id.into(),
+                PoolInfo {
+                    pool_id: id.into(),
+                    subnet: subnet.parse().unwrap(),
+                    gateway: None,
+                    thresholds: None,
+                },
+            );
+        }
+        for (ip, name) in [
+            ("10.1.0.2", "web"),
+            ("10.1.0.3", "db, primary"),
+            ("fd00::2", "v6"),
+        ] {
+            state.leases.push(IpLease::new(
+                ip.parse().unwrap(),
+                name,
+                "2024-05-01T10:00:00Z".parse().unwrap(),
+            ));
+        }
+        state
+    }
+
+    fn round_trip(format: Format) -> IpamState {
+        import(&export(&sample(), format).unwrap(), format).unwrap()
+    }
+
+    fn leases(state: &IpamState) -> Vec<(IpAddr, String)> {
+        let mut leases: Vec<_> = state
+            .leases
+            .iter()
+            .map(|l| (l.ip_address, l.container_name.clone()))
+            .collect();
+        leases.sort();
+        leases
+    }
+
+    #[test]
+    fn test_round_trips() {
+        for format in [Format::Json, Format::Csv, Format::Docker] {
+            let state = round_trip(format);
+            assert_eq!(leases(&state), leases(&sample()), "{:?}", format);
+            assert_eq!(state.pools["pool-a"].subnet.to_string(), "10.1.0.0/24");
+        }
+        // Only CSV and JSON carry lease times
+        assert_eq!(
+            round_trip(Format::Csv).leases[0].lease_time,
+            sample().leases[0].lease_time
+        );
+    }
+
+    #[test]
+    fn test_csv_layout() {
+        let csv = export(&sample(), Format::Csv).unwrap();
+        let mut lines = csv.lines();
+        assert_eq!(lines.next(), Some(CSV_HEADER));
+        assert_eq!(
+            lines.next(),
+            Some("10.1.0.2,pool-a,10.1.0.0/24,web,2024-05-01T10:00:00+00:00")
+        );
+        assert!(csv.contains("\"db, primary\""));
+        assert!(import("ip,container\n", Format::Csv).is_err());
+    }
+
+    #[test]
+    fn test_docker_layout() {
+        let json = export(&sample(), Format::Docker).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value[0]["IPAM"]["Config"][0]["Subnet"], "10.1.0.0/24");
+        assert_eq!(value[0]["Containers"]["web"]["IPv4Address"], "10.1.0.2/24");
+        assert_eq!(value[1]["Containers"]["v6"]["IPv6Address"], "fd00::2/64");
+    }
+
+    #[test]
+    fn test_docker_keeps_endpoint_ids() {
+        let mut state = sample();
+        state.leases[0].container_id = Some("3f4e9a".into());
+        state.leases[0].endpoint_id = Some("ep-1".into());
+        let json = export(&state, Format::Docker).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value[0]["Containers"]["3f4e9a"]["Name"], "web");
+        assert_eq!(value[0]["Containers"]["3f4e9a"]["EndpointID"], "ep-1");
+
+        let imported = import(&json, Format::Docker).unwrap();
+        let web = imported
+            .leases
+            .iter()
+            .find(|l| l.container_name == "web")
+            .unwrap();
+        assert_eq!(web.container_id.as_deref(), Some("3f4e9a"));
+        assert_eq!(web.endpoint_id.as_deref(), Some("ep-1"));
+        assert_eq!(web.pool_id.as_deref(), Some("pool-a"));
+        let db = imported
+            .leases
+            .iter()
+            .find(|l| l.container_name == "db, primary")
+            .unwrap();
+        assert_eq!(db.container_id, None);
+    }
+
+    #[test]
+    fn test_exports_use_lease_pool() {
+        let mut state = sample();
+        state.pools.insert(
+            "pool-c".into(),
+            PoolInfo {
+                pool_id: "pool-c".into(),
+                subnet: "10.1.0.0/16".parse().unwrap(),
+                gateway: None,
+                thresholds: None,
+            },
+        );
+        state.leases[0].pool_id = Some("pool-c".into());
+
+        let csv = export(&state, Format::Csv).unwrap();
+        assert!(csv.contains("10.1.0.2,pool-c,10.1.0.0/16,web,"));
+        assert!(csv.contains("10.1.0.3,pool-a,10.1.0.0/24,"));
+
+        let json = export(&state, Format::Docker).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        let network = |id: &str| {
+            value
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|n| n["Id"] == id)
+                .unwrap()
+                .clone()
+        };
+        assert_eq!(
+            network("pool-c")["Containers"]["web"]["IPv4Address"],
+            "10.1.0.2/16"
+        );
+        assert!(network("pool-a")["Containers"].get("web").is_none());
+    }
+
+    #[test]
+    fn test_docker_subnets_are_normalized() {
+        let json = r#"[{"Name": "net", "Id": "abc", "IPAM": {"Driver": "x",
+            "Config": [{"Subnet": "10.4.0.7/24"}]}, "Containers": {}}]"#;
+        let state = import(json, Format::Docker).unwrap();
+        assert_eq!(state.pools["abc"].subnet.to_string(), "10.4.0.0/24");
+    }
+
+    #[test]
+    fn test_detect_format() {
+        assert_eq!(Format::detect("x.csv", ""), Format::Csv);
+        assert_eq!(Format::detect("x.json", " [{}]"), Format::Docker);
+        assert_eq!(Format::detect("x.json", "{}"), Format::Json);
+    }
+
+    #[test]
+    fn test_merge_reports_conflicts() {
+        let mut state = sample();
+        let mut imported = IpamState::default();
+        for (id, subnet) in [("pool-a", "10.9.0.0/24"), ("pool-c", "10.3.0.0/24")] {
+            imported.pools.insert(
+                id.into(),
+                PoolInfo {
+                    pool_id: id.into(),
+                    subnet: subnet.parse().unwrap(),
+                    gateway: None,
+                    thresholds: None,
+                },
+            );
+        }
+        for (ip, name) in [
+            ("10.1.0.2", "web"),
+            ("10.1.0.3", "cache"),
+            ("10.3.0.5", "new"),
+            ("192.168.0.1", "stray"),
+        ] {
+            imported
+                .leases
+                .push(IpLease::new(ip.parse().unwrap(), name, Utc::now()));
+        }
+
+        let report = merge(&mut state, imported, &[]);
+        assert_eq!(report.pools_added, 1);
+        assert_eq!(report.leases_added, 1);
+        assert_eq!(report.unchanged, 1);
+        assert_eq!(report.conflicts.len(), 3);
+        assert!(matches!(report.conflicts[0], Conflict::PoolSubnet { .. }));
+        assert!(matches!(report.conflicts[1], Conflict::LeaseTaken { .. }));
+        assert!(matches!(
+            report.conflicts[2],
+            Conflict::LeaseOutsidePools { .. }
+        ));
+        // Existing entries are never overwritten
+        assert_eq!(state.pools["pool-a"].subnet.to_string(), "10.1.0.0/24");
+        assert_eq!(state.leases.len(), 4);
+    }
+
+    #[test]
+    fn test_merge_checks_leases_against_their_pool() {
+        let pool = |id: &str, subnet: &str| PoolInfo {
+            pool_id: id.into(),
+            subnet: subnet.parse().unwrap(),
+            gateway: None,
+            thresholds: None,
+        };
+        let lease = |ip: &str, name: &str, pool_id: &str| IpLease {
+            pool_id: Some(pool_id.into()),
+            ..IpLease::new(ip.parse().unwrap(), name, Utc::now())
+        };
+        let mut state = sample();
+        state.leases[0].pool_id = Some("pool-a".into());
+
+        let mut imported = IpamState::default();
+        for p in [
+            // Subnet differs from the existing pool-a
+            pool("pool-a", "10.9.0.0/24"),
+            // Overlaps pool-a, which local pools may
+            pool("pool-c", "10.1.0.0/16"),
+            // Overlaps a global pool
+            pool("pool-d", "10.200.1.0/24"),
+        ] {
+            imported.pools.insert(p.pool_id.clone(), p);
+        }
+        imported.leases = vec![
+            lease("10.9.0.5", "lost", "pool-a"),
+            // The same address as web, in the overlapping pool
+            lease("10.1.0.2", "other", "pool-c"),
+            lease("10.2.0.2", "wrong", "pool-b"),
+            lease("10.200.1.5", "routed", "pool-d"),
+        ];
+
+        let global = [pool("global-pool-1", "10.200.0.0/16")];
+        let report = merge(&mut state, imported, &global);
+        assert_eq!(report.pools_added, 1);
+        assert_eq!(report.leases_added, 1);
+        let conflicts: Vec<_> = report.conflicts.iter().map(|c| c.to_string()).collect();
+        assert_eq!(
+            conflicts,
+            vec![
+                "pool pool-a exists with subnet 10.1.0.0/24, import has 10.9.0.0/24",
+                "pool pool-d has subnet 10.200.1.0/24, which overlaps 10.200.0.0/16 of global pool global-pool-1",
+                "10.9.0.5 of lost is in pool pool-a, which was not imported",
+                "10.2.0.2 of wrong is not inside pool pool-b",
+                "10.200.1.5 of routed is in pool pool-d, which was not imported",
+            ]
+        );
+        assert!(!state.pools.contains_key("pool-d"));
+        let other = state.leases.iter().find(|l| l.container_name == "other");
+        assert_eq!(other.unwrap().pool_id.as_deref(), Some("pool-c"));
+    }
+}
@@ -1,14 +1,80 @@
 use chrono::{DateTime, Utc};
+use ipnetwork::IpNetwork;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::net::IpAddr;
 
 /// Represents an IP lease assigned to a container
-#[derive(Debug, Clone, Serialize, Deserialize)]
+///
+/// Everything after `lease_time` is optional: Docker only passes what the
+/// endpoint was created with, and leases written before schema version 3
+/// have none of it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct IpLease {
     pub ip_address: IpAddr,
     pub container_name: String,
     pub lease_time: DateTime<Utc>,
+    /// Pool the address was requested from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pool_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub endpoint_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub network_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub container_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+    /// Request options not captured by the fields above
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+    /// Pinned by an operator: Docker releasing the address leaves the lease
+    /// in place, only a forced release through the admin API frees it
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub reserved: bool,
+}
+
+impl IpLease {
+    /// A lease with no endpoint metadata
+    pub fn new(
+        ip_address: IpAddr,
+        container_name: impl Into<String>,
+        lease_time: DateTime<Utc>,
+    ) -> Self {
+        Self {
+            ip_address,
+            container_name: container_name.into(),
+            lease_time,
+            pool_id: None,
+            endpoint_id: None,
+            network_id: None,
+            container_id: None,
+            hostname: None,
+            labels: BTreeMap::new(),
+            reserved: false,
+        }
+    }
+
+    /// Whether `requester` is the container this lease is for, by name or
+    /// by container ID
+    pub fn held_by(&self, requester: &IpLease) -> bool {
+        self.container_name == requester.container_name
+            || (self.container_id.is_some() && self.container_id == requester.container_id)
+    }
+}
+
+/// A holder of an IP address, past or present
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LeaseRecord {
+    pub container_name: String,
+    pub lease_time: DateTime<Utc>,
+    /// When the lease ended; `None` for the current holder
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub release_time: Option<DateTime<Utc>>,
+    /// Pool the lease was in; overlapping pools may lease the same address.
+    /// Unset for records written before schema version 7.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pool_id: Option<String>,
 }
 
 /// The IPAM state that gets persisted to YAML
@@ -16,13 +82,21 @@ pub struct IpLease {
 pub struct IpamState {
     pub pools: HashMap<String, PoolInfo>,
     pub leases: Vec<IpLease>,
+    /// Recent past holders of each address, oldest first
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub history: BTreeMap<IpAddr, Vec<LeaseRecord>>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PoolInfo {
     pub pool_id: String,
-    pub subnet: String,
-    pub gateway: Option<String>,
+    /// Always the network address, e.g. `10.0.0.0/24` rather than `10.0.0.5/24`
+    pub subnet: IpNetwork,
+    pub gateway: Option<IpAddr>,
+    /// Utilization percentages, ascending, at which crossing in either
+    /// direction is logged and published; the plugin's defaults when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thresholds: Option<Vec<f64>>,
 }
 
 // Docker IPAM Plugin API Request/Response types
@@ -42,12 +116,13 @@ pub struct RequestPoolRequest {
     #[allow(dead_code)]
     #[serde(rename = "SubPool")]
     pub sub_pool: Option<String>,
-    #[allow(dead_code)]
     #[serde(rename = "Options")]
     pub options: Option<HashMap<String, String>>,
     #[allow(dead_code)]
     #[serde(rename = "V6")]
     pub v6: Option<bool>,
+    #[serde(rename = "AddressSpace")]
+    pub address_space: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -0,0 +1,228 @@
+use crate::types::IpLease;
+use anyhow::{bail, Context, Result};
+use ipnetwork::IpNetwork;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+
+/// Utilization percentages at which pools warn unless configured otherwise
+pub const DEFAULT_THRESHOLDS: &[f64] = &[80.0, 95.0];
+
+/// Number of holders an exhaustion error lists
+const TOP_HOLDERS: usize = 5;
+
+/// How much of a pool is leased
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Utilization {
+    /// Addresses the allocator can hand out
+    pub total: u128,
+    pub used: u128,
+    pub free: u128,
+    /// Leased addresses that are pinned by an operator; part of `used`
+    pub reserved: u128,
+    /// `used` as a percentage of `total`; 0 for a pool with no usable
+    /// addresses
+    pub percent: f64,
+}
+
+impl Utilization {
+    /// Utilization of `subnet` given the leases of its pool
+    ///
+    /// Leases outside the subnet are ignored, and an address leased twice
+    /// counts once.
+    pub fn of<'a>(subnet: &IpNetwork, leases: impl IntoIterator<Item = &'a IpLease>) -> Self {
+        let mut used = HashSet::new();
+        let mut reserved = HashSet::new();
+        for lease in leases {
+            let ip = lease.ip_address;
+            if !subnet.contains(ip)
+                || ip == subnet.network()
+                || (ip.is_ipv4() && ip == subnet.broadcast())
+            {
+                continue;
+            }
+            used.insert(ip);
+            if lease.reserved {
+                reserved.insert(ip);
+            }
+        }
+        let total = usable_addresses(subnet);
+        let used = (used.len() as u128).min(total);
+        Self {
+            total,
+            used,
+            free: total - used,
+            reserved: reserved.len() as u128,
+            percent: if total == 0 {
+                0.0
+            } else {
+                used as f64 * 100.0 / total as f64
+            },
+        }
+    }
+}
+
+/// Parse comma-separated utilization percentages such as `80,95`, sorted
+/// ascending; an empty string gives no thresholds
+pub fn parse_thresholds(s: &str) -> Result<Vec<f64>> {
+    let mut thresholds = s
+        .split(',')
+        .map(str::trim)
+        .filter(|t| !t.is_empty())
+        .map(|t| {
+            let value: f64 = t
+                .trim_end_matches('%')
+                .parse()
+                .with_context(|| format!("Invalid threshold {:?}", t))?;
+            if !(value > 0.0 && value <= 100.0) {
+                bail!("Threshold {} is not between 0 and 100", t);
+            }
+            Ok(value)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    thresholds.sort_by(f64::total_cmp);
+    thresholds.dedup();
+    Ok(thresholds)
+}
+
+/// How many of the ascending `thresholds` `percent` has reached
+pub fn level(percent: f64, thresholds: &[f64]) -> usize {
+    thresholds.iter().take_while(|t| percent >= **t).count()
+}
+
+/// A pool has no address left to hand out
+#[derive(Debug)]
+pub struct ExhaustedError {
+    pub subnet: IpNetwork,
+    pub utilization: Utilization,
+    /// The containers holding the most addresses, with how many each holds
+    pub top_holders: Vec<(String, usize)>,
+}
+
+impl ExhaustedError {
+    pub fn new<'a>(subnet: &IpNetwork, leases: impl IntoIterator<Item = &'a IpLease>) -> Self {
+        let leases: Vec<&IpLease> = leases
+            .into_iter()
+            .filter(|l| subnet.contains(l.ip_address))
+            .collect();
+        let mut holders: HashMap<&str, usize> = HashMap::new();
+        for lease in &leases {
+            *holders.entry(&lease.container_name).or_default() += 1;
+        }
+        let mut top_holders: Vec<(String, usize)> = holders
+            .into_iter()
+            .map(|(name, count)| (name.to_string(), count))
+            .collect();
+        top_holders.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        top_holders.truncate(TOP_HOLDERS);
+        Self {
+            subnet: *subnet,
+            utilization: Utilization::of(subnet, leases),
+            top_holders,
+        }
+    }
+}
+
+impl std::fmt::Display for ExhaustedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let u = &self.utilization;
+        write!(
+            f,
+            "No available IP addresses in subnet {} ({} of {} used, {} reserved",
+            self.subnet, u.used, u.total, u.reserved
+        )?;
+        for (i, (name, count)) in self.top_holders.iter().enumerate() {
+            let sep = if i == 0 { "; top holders: " } else { ", " };
+            write!(f, "{}{} ({})", sep, name, count)?;
+        }
+        f.write_str(")")
+    }
+}
+
+impl std::error::Error for ExhaustedError {}
+
+/// Number of addresses allocation may use in `subnet`: everything but the
+/// network address, and for IPv4 the broadcast address
+pub fn usable_addresses(subnet: &IpNetwork) -> u128 {
+    let bits = if subnet.is_ipv4() { 32 } else { 128 };
+    let size = 1u128
+        .checked_shl(bits - subnet.prefix() as u32)
+        .unwrap_or(u128::MAX);
+    let reserved = if subnet.is_ipv4() { 2 } else { 1 };
+    size.saturating_sub(reserved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    fn lease(ip: &str) -> IpLease {
+        IpLease::new(ip.parse().unwrap(), "c", Utc::now())
+    }
+
+    #[test]
+    fn test_usable_addresses() {
+        let count = |s: &str| usable_addresses(&s.parse().unwrap());
+        assert_eq!(count("10.0.0.0/24"), 254);
+        assert_eq!(count("10.0.0.0/30"), 2);
+        assert_eq!(count("10.0.0.1/32"), 0);
+        assert_eq!(count("fd00::/64"), (1u128 << 64) - 1);
+        assert_eq!(count("::/0"), u128::MAX - 1);
+    }
+
+    #[test]
+    fn test_utilization() {
+        let subnet: IpNetwork = "10.0.0.0/30".parse().unwrap();
+        let leases = [
+            IpLease {
+                reserved: true,
+                ..lease("10.0.0.1")
+            },
+            lease("10.0.0.1"),
+            lease("10.0.0.3"),
+            lease("10.9.0.1"),
+        ];
+        let u = Utilization::of(&subnet, &leases);
+        assert_eq!((u.total, u.used, u.free, u.reserved), (2, 1, 1, 1));
+        assert_eq!(u.percent, 50.0);
+
+        let empty = Utilization::of(&"10.0.0.1/32".parse().unwrap(), &leases);
+        assert_eq!(empty.percent, 0.0);
+    }
+
+    #[test]
+    fn test_thresholds() {
+        assert_eq!(parse_thresholds("95, 80%,80").unwrap(), vec![80.0, 95.0]);
+        assert!(parse_thresholds("").unwrap().is_empty());
+        assert!(parse_thresholds("0").is_err());
+        assert!(parse_thresholds("101").is_err());
+        assert!(parse_thresholds("high").is_err());
+
+        let thresholds = [80.0, 95.0];
+        assert_eq!(level(79.9, &thresholds), 0);
+        assert_eq!(level(80.0, &thresholds), 1);
+        assert_eq!(level(100.0, &thresholds), 2);
+    }
+
+    #[test]
+    fn test_exhausted_error_lists_top_holders() {
+        let subnet: IpNetwork = "10.0.0.0/29".parse().unwrap();
+        let leases: Vec<IpLease> = ["a", "b", "b", "c", "b", "a"]
+            .iter()
+            .enumerate()
+            .map(|(i, name)| {
+                IpLease::new(
+                    format!("10.0.0.{}", i + 1).parse().unwrap(),
+                    *name,
+                    Utc::now(),
+                )
+            })
+            .collect();
+        let e = ExhaustedError::new(&subnet, &leases);
+        assert_eq!(
+            e.to_string(),
+            "No available IP addresses in subnet 10.0.0.0/29 (6 of 6 used, 0 reserved; \
+             top holders: b (3), a (2), c (1))"
+        );
+    }
+}
@@ -0,0 +1,353 @@
+use crate::types::IpamState;
+use serde::Serialize;
+use serde_yaml::{Mapping, Value};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+
+/// Key holding the content checksum in the state file
+pub const CHECKSUM_KEY: &str = "checksum";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    /// Suspicious but does not make the state unusable
+    Warning,
+    /// The state is inconsistent; strict mode refuses to load it
+    Error,
+}
+
+/// A single problem found in a state file
+#[derive(Debug, Clone, Serialize)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// Stable machine-readable identifier, e.g. `duplicate_ip`
+    pub code: &'static str,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pool_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ip_address: Option<IpAddr>,
+}
+
+impl Diagnostic {
+    fn new(severity: Severity, code: &'static str, message: String) -> Self {
+        Self {
+            severity,
+            code,
+            message,
+            pool_id: None,
+            ip_address: None,
+        }
+    }
+
+    fn pool(mut self, pool_id: &str) -> Self {
+        self.pool_id = Some(pool_id.to_string());
+        self
+    }
+
+    fn ip(mut self, ip: IpAddr) -> Self {
+        self.ip_address = Some(ip);
+        self
+    }
+
+    /// Emit this diagnostic to the log at a matching level
+    pub fn log(&self, source: &str) {
+        match self.severity {
+            Severity::Warning => tracing::warn!(code = self.code, "{}: {}", source, self.message),
+            Severity::Error => tracing::error!(code = self.code, "{}: {}", source, self.message),
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}] {}", self.code, self.message)
+    }
+}
+
+/// Returned in strict mode when a state file has error-level diagnostics
+#[derive(Debug)]
+pub struct ValidationError {
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let errors: Vec<String> = self
+            .diagnostics
+            .iter()
+            .filter(|d| d.severity == Severity::Error)
+            .map(|d| d.to_string())
+            .collect();
+        write!(f, "State failed validation: {}", errors.join("; "))
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+pub fn has_errors(diagnostics: &[Diagnostic]) -> bool {
+    diagnostics.iter().any(|d| d.severity == Severity::Error)
+}
+
+/// Check a loaded state for internal consistency
+///
+/// Malformed subnets and gateways never get this far: they fail to parse.
+pub fn validate(state: &IpamState) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+
+    let mut networks = Vec::new();
+    let mut pool_ids: Vec<_> = state.pools.keys().collect();
+    pool_ids.sort();
+    for key in pool_ids {
+        let pool = &state.pools[key];
+        if *key != pool.pool_id {
+            diagnostics.push(
+                Diagnostic::new(
+                    Severity::Error,
+                    "pool_id_mismatch",
+                    format!("Pool stored under key {} has pool_id {}", key, pool.pool_id),
+                )
+                .pool(key),
+            );
+        }
+
+        let network = pool.subnet;
+        networks.push(network);
+
+        if let Some(ip) = pool.gateway {
+            if !network.contains(ip) {
+                diagnostics.push(
+                    Diagnostic::new(
+                        Severity::Warning,
+                        "gateway_outside_subnet",
+                        format!("Pool {} gateway {} is outside {}", key, ip, network),
+                    )
+                    .pool(key)
+                    .ip(ip),
+                );
+            }
+        }
+    }
+
+    // Overlapping pools may each lease the same address, so duplicates are
+    // only looked for within a pool. A lease without a pool_id belongs to
+    // every pool containing it, like allocation counts it.
+    let mut seen: HashMap<(Option<&str>, IpAddr), &str> = HashMap::new();
+    for lease in &state.leases {
+        let ip = lease.ip_address;
+        let owners: Vec<Option<&str>> = match &lease.pool_id {
+            Some(pool_id) => vec![Some(pool_id)],
+            None => {
+                let mut owners: Vec<_> = state
+                    .pools
+                    .values()
+                    .filter(|p| p.subnet.contains(ip))
+                    .map(|p| Some(p.pool_id.as_str()))
+                    .collect();
+                owners.sort();
+                if owners.is_empty() {
+                    owners.push(None);
+                }
+                owners
+            }
+        };
+        for owner in owners {
+            if let Some(first) = seen.insert((owner, ip), &lease.container_name) {
+                let diagnostic = Diagnostic::new(
+                    Severity::Error,
+                    "duplicate_ip",
+                    match owner {
+                        Some(pool_id) => format!(
+                            "IP {} is leased to both {} and {} in pool {}",
+                            ip, first, lease.container_name, pool_id
+                        ),
+                        None => format!(
+                            "IP {} is leased to both {} and {}",
+                            ip, first, lease.container_name
+                        ),
+                    },
+                )
+                .ip(ip);
+                diagnostics.push(match owner {
+                    Some(pool_id) => diagnostic.pool(pool_id),
+                    None => diagnostic,
+                });
+                break;
+            }
+        }
+        if !networks.iter().any(|n| n.contains(ip)) {
+            diagnostics.push(
+                Diagnostic::new(
+                    Severity::Warning,
+                    "lease_outside_pools",
+                    format!(
+                        "Lease of {} to {} is not inside any pool",
+                        ip, lease.container_name
+                    ),
+                )
+                .ip(ip),
+            );
+        }
+    }
+
+    diagnostics
+}
+
+/// Checksum of a state document, ignoring any checksum key it already has
+pub fn checksum(doc: &Mapping) -> String {
+    let mut body = doc.clone();
+    body.remove(CHECKSUM_KEY);
+    // Serializing a mapping is deterministic and preserves key order, so a
+    // file we wrote re-serializes to exactly the bytes we hashed
+    let canonical = serde_yaml::to_string(&body).unwrap_or_default();
+    format!("sha256:{:x}", Sha256::digest(canonical.as_bytes()))
+}
+
+/// Add a checksum key to a serialized state document
+pub fn seal(doc: Value) -> Value {
+    match doc {
+        Value::Mapping(mut m) => {
+            let sum = checksum(&m);
+            m.insert(Value::from(CHECKSUM_KEY), Value::from(sum));
+            Value::Mapping(m)
+        }
+        other => other,
+    }
+}
+
+/// Remove and verify the checksum of a parsed state document
+///
+/// Files without a checksum (older files, or hand-edited ones where the
+/// operator removed it) are accepted as-is.
+pub fn unseal(doc: &mut Value) -> Option<Diagnostic> {
+    let Value::Mapping(m) = doc else {
+        return None;
+    };
+    let expected = m.get(CHECKSUM_KEY)?.as_str().map(str::to_string);
+    let actual = checksum(m);
+    m.remove(CHECKSUM_KEY);
+
+    if expected.as_deref() == Some(actual.as_str()) {
+        None
+    } else {
+        Some(Diagnostic::new(
+            Severity::Error,
+            "checksum_mismatch",
+            format!(
+                "State file checksum {} does not match its content ({}); it was truncated or edited by hand",
+                expected.unwrap_or_else(|| "<not a string>".to_string()),
+                actual
+            ),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{IpLease, PoolInfo};
+    use chrono::Utc;
+
+    fn codes(diagnostics: &[Diagnostic]) -> Vec<&'static str> {
+        diagnostics.iter().map(|d| d.code).collect()
+    }
+
+    #[test]
+    fn test_validate_clean_state() {
+        let mut state = IpamState::default();
+        state.pools.insert(
+            "p1".into(),
+            PoolInfo {
+                pool_id: "p1".into(),
+                subnet: "10.0.0.0/24".parse().unwrap(),
+                gateway: Some("10.0.0.1".parse().unwrap()),
+                thresholds: None,
+            },
+        );
+        state
+            .leases
+            .push(IpLease::new("10.0.0.2".parse().unwrap(), "a", Utc::now()));
+        assert!(validate(&state).is_empty());
+    }
+
+    #[test]
+    fn test_validate_reports_inconsistencies() {
+        let mut state = IpamState::default();
+        state.pools.insert(
+            "p1".into(),
+            PoolInfo {
+                pool_id: "other".into(),
+                subnet: "10.0.0.0/24".parse().unwrap(),
+                gateway: Some("10.9.9.9".parse().unwrap()),
+                thresholds: None,
+            },
+        );
+        for name in ["a", "b"] {
+            state
+                .leases
+                .push(IpLease::new("10.0.0.5".parse().unwrap(), name, Utc::now()));
+        }
+        state.leases.push(IpLease::new(
+            "192.168.0.5".parse().unwrap(),
+            "stray",
+            Utc::now(),
+        ));
+
+        let diagnostics = validate(&state);
+        let codes = codes(&diagnostics);
+        assert!(codes.contains(&"pool_id_mismatch"));
+        assert!(codes.contains(&"gateway_outside_subnet"));
+        assert!(codes.contains(&"duplicate_ip"));
+        assert!(codes.contains(&"lease_outside_pools"));
+        assert!(has_errors(&diagnostics));
+    }
+
+    #[test]
+    fn test_overlapping_pools_may_lease_the_same_ip() {
+        let mut state = IpamState::default();
+        for (id, subnet) in [("outer", "10.0.0.0/16"), ("inner", "10.0.1.0/24")] {
+            state.pools.insert(
+                id.into(),
+                PoolInfo {
+                    pool_id: id.into(),
+                    subnet: subnet.parse().unwrap(),
+                    gateway: None,
+                    thresholds: None,
+                },
+            );
+        }
+        for (pool_id, name) in [("outer", "a"), ("inner", "b")] {
+            state.leases.push(IpLease {
+                pool_id: Some(pool_id.into()),
+                ..IpLease::new("10.0.1.5".parse().unwrap(), name, Utc::now())
+            });
+        }
+        assert!(validate(&state).is_empty());
+
+        // The same address twice in one pool is still a duplicate
+        state.leases.push(IpLease {
+            pool_id: Some("inner".into()),
+            ..IpLease::new("10.0.1.5".parse().unwrap(), "c", Utc::now())
+        });
+        let diagnostics = validate(&state);
+        assert_eq!(codes(&diagnostics), vec!["duplicate_ip"]);
+        assert_eq!(diagnostics[0].pool_id.as_deref(), Some("inner"));
+    }
+
+    #[test]
+    fn test_checksum_round_trip() {
+        let doc: Value = serde_yaml::from_str("version: 1\npools: {}\nleases: []\n").unwrap();
+        let sealed = seal(doc);
+        let yaml = serde_yaml::to_string(&sealed).unwrap();
+
+        let mut parsed: Value = serde_yaml::from_str(&yaml).unwrap();
+        assert!(unseal(&mut parsed).is_none());
+        assert!(parsed.get(CHECKSUM_KEY).is_none());
+
+        let tampered = yaml.replace("leases: []", "leases: []\nextra: 1");
+        let mut parsed: Value = serde_yaml::from_str(&tampered).unwrap();
+        assert_eq!(unseal(&mut parsed).unwrap().code, "checksum_mismatch");
+    }
+}
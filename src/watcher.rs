@@ -0,0 +1,155 @@
+use crate::storage::Storage;
+use anyhow::{anyhow, Context, Result};
+use notify::{EventKind, RecursiveMode, Watcher};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+/// How long to wait for an editor to finish writing before reloading
+const DEBOUNCE: Duration = Duration::from_millis(200);
+
+/// Watches the state file and reloads it when an operator edits it
+///
+/// The parent directory is watched rather than the file itself, because both
+/// `Storage::save` and most editors replace the file by renaming over it.
+/// Writes made by the plugin itself are recognised and skipped. Stops when
+/// dropped.
+pub struct StateWatcher {
+    _watcher: notify::RecommendedWatcher,
+    task: JoinHandle<()>,
+}
+
+impl StateWatcher {
+    pub fn spawn(storage: Arc<Storage>) -> Result<Self> {
+        let file_path = storage.file_path().to_path_buf();
+        let file_name = file_path
+            .file_name()
+            .ok_or_else(|| anyhow!("State file path has no file name: {:?}", file_path))?
+            .to_owned();
+        let dir = match file_path.parent() {
+            Some(p) if !p.as_os_str().is_empty() => p.to_path_buf(),
+            _ => std::path::PathBuf::from("."),
+        };
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut watcher =
+            notify::recommended_watcher(move |res: notify::Result<notify::Event>| match res {
+                Ok(event) => {
+                    let relevant =
+                        matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_))
+                            && event
+                                .paths
+                                .iter()
+                                .any(|p| p.file_name() == Some(file_name.as_os_str()));
+                    if relevant {
+                        let _ = tx.send(());
+                    }
+                }
+                Err(e) => tracing::warn!("State file watcher error: {}", e),
+            })
+            .context("Failed to create state file watcher")?;
+        watcher
+            .watch(&dir, RecursiveMode::NonRecursive)
+            .with_context(|| format!("Failed to watch {:?}", dir))?;
+
+        let task = tokio::spawn(async move {
+            while rx.recv().await.is_some() {
+                // Let the writer finish, then coalesce the burst into one reload
+                tokio::time::sleep(DEBOUNCE).await;
+                while rx.try_recv().is_ok() {}
+
+                match storage.reload_if_changed().await {
+                    Ok(Some(diff)) if diff.is_empty() => {
+                        tracing::info!(
+                            "State file {:?} changed on disk; no effective changes",
+                            file_path
+                        );
+                    }
+                    Ok(Some(diff)) => {
+                        tracing::info!("State file {:?} reloaded: {}", file_path, diff);
+                    }
+                    Ok(None) => {}
+                    Err(e) => {
+                        tracing::error!(
+                            "Rejected edit to state file {:?}, keeping in-memory state: {:#}",
+                            file_path,
+                            e
+                        );
+                    }
+                }
+            }
+        });
+
+        tracing::info!("Watching {:?} for external edits", dir);
+        Ok(Self {
+            _watcher: watcher,
+            task,
+        })
+    }
+}
+
+impl Drop for StateWatcher {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    /// Poll until the number of leases reaches `expected` or give up
+    async fn wait_for_leases(storage: &Storage, expected: usize) -> bool {
+        for _ in 0..50 {
+            if storage.read().await.leases.len() == expected {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        false
+    }
+
+    #[tokio::test]
+    async fn test_watcher_picks_up_external_edit() {
+        let temp_dir = TempDir::new().unwrap();
+        let state_file = temp_dir.path().join("state.yaml");
+        let storage = Arc::new(Storage::new(&state_file).await.unwrap());
+        storage.save().await.unwrap();
+        let _watcher = StateWatcher::spawn(storage.clone()).unwrap();
+
+        let edited = std::fs::read_to_string(&state_file).unwrap().replace(
+            "leases: []",
+            "leases:\n- ip_address: 10.0.0.50\n  container_name: reserved\n  lease_time: 2025-01-09T10:30:00Z",
+        );
+        std::fs::write(&state_file, edited).unwrap();
+
+        assert!(wait_for_leases(&storage, 1).await);
+        assert_eq!(storage.read().await.leases[0].container_name, "reserved");
+    }
+
+    #[tokio::test]
+    async fn test_watcher_keeps_state_on_invalid_edit() {
+        let temp_dir = TempDir::new().unwrap();
+        let state_file = temp_dir.path().join("state.yaml");
+        let storage = Arc::new(Storage::new(&state_file).await.unwrap());
+        let _watcher = StateWatcher::spawn(storage.clone()).unwrap();
+
+        storage
+            .write()
+            .await
+            .leases
+            .push(crate::types::IpLease::new(
+                "10.0.0.60".parse().unwrap(),
+                "keep".to_string(),
+                chrono::Utc::now(),
+            ));
+        storage.save().await.unwrap();
+
+        std::fs::write(&state_file, "leases: [not yaml").unwrap();
+        tokio::time::sleep(DEBOUNCE * 3).await;
+        assert_eq!(storage.read().await.leases.len(), 1);
+        assert_eq!(storage.read().await.leases[0].container_name, "keep");
+    }
+}
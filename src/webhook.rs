@@ -0,0 +1,535 @@
+use crate::events::EventBus;
+use anyhow::{bail, Context, Result};
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Method, Request, Uri};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use sha2::{Digest, Sha256};
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+
+/// Tunables for webhook delivery
+#[derive(Debug, Clone)]
+pub struct WebhookOptions {
+    /// Directory holding one queue per URL
+    pub queue_dir: PathBuf,
+    /// Events kept per URL while its receiver is unreachable; the oldest are
+    /// dropped beyond that
+    pub max_queued: usize,
+    /// Delay before the first retry, doubled after every further failure
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// How long a receiver may take to answer before the attempt fails
+    pub timeout: Duration,
+}
+
+impl WebhookOptions {
+    pub fn new(queue_dir: impl Into<PathBuf>) -> Self {
+        Self {
+            queue_dir: queue_dir.into(),
+            max_queued: 10_000,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(300),
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// POSTs every event to each configured URL
+///
+/// Events are queued on disk per URL and delivered in order by a task per
+/// URL, retrying with exponential backoff until the receiver answers with a
+/// 2xx status. Requests never wait for delivery, and events still queued
+/// when the plugin stops are sent after it starts again. Delivery is at
+/// least once: an event whose answer is lost is sent again. Stops when
+/// dropped.
+pub struct Webhooks {
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Webhooks {
+    pub fn spawn(events: &EventBus, urls: &[String], options: WebhookOptions) -> Result<Self> {
+        let uris = urls
+            .iter()
+            .map(|url| {
+                let uri: Uri = url
+                    .parse()
+                    .with_context(|| format!("Invalid webhook URL {:?}", url))?;
+                if !matches!(uri.scheme_str(), Some("http" | "https")) {
+                    bail!("Webhook URL {} is not http:// or https://", url);
+                }
+                Ok(uri)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        // https receivers are verified against the bundled Mozilla roots, so
+        // delivery does not depend on the host's CA store
+        let connector = HttpsConnectorBuilder::new()
+            .with_webpki_roots()
+            .https_or_http()
+            .enable_http1()
+            .build();
+        let client = Client::builder().build(connector);
+        let mut queues = Vec::new();
+        let mut tasks = Vec::new();
+        for (url, uri) in urls.iter().zip(uris) {
+            let queue = Arc::new(DiskQueue::open(
+                options.queue_dir.join(queue_name(url)),
+                options.max_queued,
+            )?);
+            if !queue.is_empty() {
+                tracing::info!("{} queued events for webhook {}", queue.len(), url);
+            }
+            queues.push(queue.clone());
+            tasks.push(tokio::spawn(deliver_queue(
+                client.clone(),
+                uri,
+                queue,
+                options.clone(),
+            )));
+        }
+
+        let mut rx = events.subscribe();
+        tasks.push(tokio::spawn(async move {
+            loop {
+                match rx.recv().await {
+                    Ok(event) => {
+                        let body = match serde_json::to_vec(&event) {
+                            Ok(body) => body,
+                            Err(e) => {
+                                tracing::error!("Cannot serialize event for webhooks: {}", e);
+                                continue;
+                            }
+                        };
+                        let body = Arc::new(body);
+                        for queue in &queues {
+                            let body = body.clone();
+                            if let Err(e) = off_thread(queue, move |q| q.push(&body)).await {
+                                tracing::error!("Cannot queue webhook event: {:#}", e);
+                            }
+                        }
+                    }
+                    Err(RecvError::Lagged(missed)) => {
+                        tracing::warn!("Webhooks fell behind, {} events not sent", missed);
+                    }
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        }));
+
+        tracing::info!("Sending events to {} webhooks", urls.len());
+        Ok(Self { tasks })
+    }
+}
+
+impl Drop for Webhooks {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+/// Queue directory name for `url`
+fn queue_name(url: &str) -> String {
+    Sha256::digest(url.as_bytes())
+        .iter()
+        .take(8)
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+async fn deliver_queue(
+    client: Client<HttpsConnector<HttpConnector>>,
+    uri: Uri,
+    queue: Arc<DiskQueue>,
+    options: WebhookOptions,
+) {
+    let mut backoff = options.initial_backoff;
+    loop {
+        let (seq, body) = match off_thread(&queue, DiskQueue::front).await {
+            Ok(Some(front)) => front,
+            Ok(None) => {
+                queue.notify.notified().await;
+                continue;
+            }
+            Err(e) => {
+                tracing::error!("Cannot read webhook queue for {}: {:#}", uri, e);
+                tokio::time::sleep(backoff).await;
+                continue;
+            }
+        };
+        match post(&client, &uri, body, options.timeout).await {
+            Ok(()) => {
+                if let Err(e) = off_thread(&queue, move |q| q.remove(seq)).await {
+                    tracing::error!("Cannot remove delivered webhook event: {:#}", e);
+                }
+                backoff = options.initial_backoff;
+            }
+            Err(e) => {
+                tracing::warn!(
+                    "Webhook delivery to {} failed, retrying in {:?}: {:#}",
+                    uri,
+                    backoff,
+                    e
+                );
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(options.max_backoff);
+            }
+        }
+    }
+}
+
+async fn post(
+    client: &Client<HttpsConnector<HttpConnector>>,
+    uri: &Uri,
+    body: Vec<u8>,
+    timeout: Duration,
+) -> Result<()> {
+    let req = Request::builder()
+        .method(Method::POST)
+        .uri(uri.clone())
+        .header("Content-Type", "application/json")
+        .body(Body::from(body))?;
+    let resp = tokio::time::timeout(timeout, client.request(req))
+        .await
+        .context("Timed out")??;
+    if !resp.status().is_success() {
+        bail!("Receiver answered {}", resp.status());
+    }
+    Ok(())
+}
+
+/// Run `f` on `queue` on the blocking thread pool, since it does file I/O
+async fn off_thread<T, F>(queue: &Arc<DiskQueue>, f: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&DiskQueue) -> Result<T> + Send + 'static,
+{
+    let queue = queue.clone();
+    tokio::task::spawn_blocking(move || f(&queue)).await?
+}
+
+/// Bounded FIFO of event bodies, one file per entry named by its sequence
+/// number
+struct DiskQueue {
+    dir: PathBuf,
+    max: usize,
+    /// Sequence numbers of the queued files, oldest first, and the next one
+    entries: Mutex<(VecDeque<u64>, u64)>,
+    notify: Notify,
+}
+
+impl DiskQueue {
+    fn open(dir: PathBuf, max: usize) -> Result<Self> {
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create webhook queue {:?}", dir))?;
+        let mut seqs = Vec::new();
+        for entry in std::fs::read_dir(&dir)? {
+            let name = entry?.file_name();
+            let name = name.to_string_lossy();
+            match name.strip_suffix(".json").and_then(|n| n.parse().ok()) {
+                Some(seq) => seqs.push(seq),
+                // Leftover of a write that did not finish
+                None if name.ends_with(".tmp") => {
+                    let _ = std::fs::remove_file(dir.join(&*name));
+                }
+                None => {}
+            }
+        }
+        seqs.sort_unstable();
+        let next = seqs.last().map_or(0, |s| s + 1);
+        Ok(Self {
+            dir,
+            max,
+            entries: Mutex::new((seqs.into(), next)),
+            notify: Notify::new(),
+        })
+    }
+
+    fn path(&self, seq: u64) -> PathBuf {
+        self.dir.join(format!("{:020}.json", seq))
+    }
+
+    fn len(&self) -> usize {
+        self.entries.lock().unwrap().0.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn push(&self, body: &[u8]) -> Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        let (queued, next) = &mut *entries;
+        let seq = *next;
+        let path = self.path(seq);
+        let temp_path = path.with_extension("tmp");
+        std::fs::write(&temp_path, body)
+            .and_then(|_| std::fs::rename(&temp_path, &path))
+            .with_context(|| format!("Failed to write {:?}", path))?;
+        *next += 1;
+        queued.push_back(seq);
+
+        while queued.len() > self.max {
+            let oldest = queued.pop_front().unwrap();
+            tracing::warn!(
+                "Webhook queue {:?} is full, dropping oldest event",
+                self.dir
+            );
+            remove_entry(&self.path(oldest))?;
+        }
+        drop(entries);
+        self.notify.notify_one();
+        Ok(())
+    }
+
+    /// The oldest entry
+    fn front(&self) -> Result<Option<(u64, Vec<u8>)>> {
+        loop {
+            let Some(seq) = self.entries.lock().unwrap().0.front().copied() else {
+                return Ok(None);
+            };
+            match std::fs::read(self.path(seq)) {
+                Ok(body) => return Ok(Some((seq, body))),
+                // Dropped by `push` since we looked, or deleted by hand
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    let mut entries = self.entries.lock().unwrap();
+                    if entries.0.front() == Some(&seq) {
+                        entries.0.pop_front();
+                    }
+                }
+                Err(e) => {
+                    return Err(e).with_context(|| format!("Failed to read {:?}", self.path(seq)))
+                }
+            }
+        }
+    }
+
+    fn remove(&self, seq: u64) -> Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(i) = entries.0.iter().position(|s| *s == seq) {
+            entries.0.remove(i);
+            remove_entry(&self.path(seq))?;
+        }
+        Ok(())
+    }
+}
+
+fn remove_entry(path: &Path) -> Result<()> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            Err(e).with_context(|| format!("Failed to remove {:?}", path))
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::{Event, EventKind};
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Response, Server, StatusCode};
+    use std::convert::Infallible;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tempfile::TempDir;
+
+    /// Receiver that fails the first `failures` requests with a 500 and
+    /// records the bodies of the rest
+    async fn spawn_receiver(failures: usize) -> (String, Arc<Mutex<Vec<Vec<u8>>>>) {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let bodies = received.clone();
+        let make_svc = make_service_fn(move |_| {
+            let bodies = bodies.clone();
+            let attempts = attempts.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let bodies = bodies.clone();
+                    let attempts = attempts.clone();
+                    async move {
+                        let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                        let status = if attempts.fetch_add(1, Ordering::SeqCst) < failures {
+                            StatusCode::INTERNAL_SERVER_ERROR
+                        } else {
+                            bodies.lock().unwrap().push(body.to_vec());
+                            StatusCode::NO_CONTENT
+                        };
+                        let mut resp = Response::new(Body::empty());
+                        *resp.status_mut() = status;
+                        Ok::<_, Infallible>(resp)
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_svc);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        (format!("http://{}/hook", addr), received)
+    }
+
+    fn options(dir: &Path) -> WebhookOptions {
+        WebhookOptions {
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(40),
+            ..WebhookOptions::new(dir)
+        }
+    }
+
+    fn released(pool_id: &str) -> EventKind {
+        EventKind::PoolReleased {
+            pool_id: pool_id.to_string(),
+        }
+    }
+
+    /// Poll until `received` holds `expected` bodies or give up
+    async fn wait_for(received: &Mutex<Vec<Vec<u8>>>, expected: usize) -> Vec<Event> {
+        for _ in 0..200 {
+            if received.lock().unwrap().len() >= expected {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        received
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|b| serde_json::from_slice(b).unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_events_are_retried_in_order() {
+        let temp_dir = TempDir::new().unwrap();
+        let (url, received) = spawn_receiver(3).await;
+        let bus = EventBus::default();
+        let _webhooks = Webhooks::spawn(&bus, &[url], options(temp_dir.path())).unwrap();
+
+        bus.publish(released("pool-1"));
+        bus.publish(released("pool-2"));
+        let events = wait_for(&received, 2).await;
+        let kinds: Vec<_> = events.into_iter().map(|e| e.kind).collect();
+        assert_eq!(kinds, vec![released("pool-1"), released("pool-2")]);
+    }
+
+    #[tokio::test]
+    async fn test_events_queued_before_a_restart_are_sent() {
+        let temp_dir = TempDir::new().unwrap();
+        let (url, received) = spawn_receiver(0).await;
+        {
+            // Left behind by a previous run that could not deliver it
+            let queue = DiskQueue::open(temp_dir.path().join(queue_name(&url)), 10).unwrap();
+            let event = Event {
+                seq: 1,
+                timestamp: chrono::Utc::now(),
+                kind: released("pool-1"),
+            };
+            queue.push(&serde_json::to_vec(&event).unwrap()).unwrap();
+        }
+
+        let bus = EventBus::default();
+        let _webhooks = Webhooks::spawn(&bus, &[url], options(temp_dir.path())).unwrap();
+        bus.publish(released("pool-2"));
+        let events = wait_for(&received, 2).await;
+        let kinds: Vec<_> = events.into_iter().map(|e| e.kind).collect();
+        assert_eq!(kinds, vec![released("pool-1"), released("pool-2")]);
+    }
+
+    #[tokio::test]
+    async fn test_plugin_changes_reach_webhooks() {
+        use crate::ipam::IpamPlugin;
+        use crate::storage::Storage;
+        use crate::types::{RequestAddressRequest, RequestPoolRequest};
+
+        let temp_dir = TempDir::new().unwrap();
+        let storage = Arc::new(
+            Storage::new(&temp_dir.path().join("state.yaml"))
+                .await
+                .unwrap(),
+        );
+        let plugin = IpamPlugin::new(storage, "10.0.0.0/24".to_string());
+        let (url, received) = spawn_receiver(0).await;
+        let _webhooks = Webhooks::spawn(
+            plugin.events(),
+            &[url],
+            options(&temp_dir.path().join("webhooks")),
+        )
+        .unwrap();
+
+        let pool_id = plugin
+            .request_pool(RequestPoolRequest {
+                pool: Some("10.72.0.0/24".to_string()),
+                sub_pool: None,
+                options: None,
+                v6: None,
+                address_space: None,
+            })
+            .await
+            .unwrap()
+            .pool_id;
+        plugin
+            .request_address(RequestAddressRequest {
+                pool_id: pool_id.clone(),
+                address: None,
+                options: None,
+            })
+            .await
+            .unwrap();
+
+        let events = wait_for(&received, 2).await;
+        assert_eq!(events.len(), 2);
+        assert_eq!(
+            events[0].kind,
+            EventKind::PoolCreated {
+                pool_id: pool_id.clone(),
+                subnet: "10.72.0.0/24".parse().unwrap(),
+                address_space: "local".to_string(),
+            }
+        );
+        match &events[1].kind {
+            EventKind::LeaseCreated { lease } => {
+                assert_eq!(lease.ip_address.to_string(), "10.72.0.1");
+                assert_eq!(lease.pool_id.as_ref(), Some(&pool_id));
+            }
+            other => panic!("unexpected event {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_queue_drops_oldest_when_full() {
+        let temp_dir = TempDir::new().unwrap();
+        let queue = DiskQueue::open(temp_dir.path().join("q"), 2).unwrap();
+        for body in [b"1", b"2", b"3"] {
+            queue.push(body).unwrap();
+        }
+        assert_eq!(queue.len(), 2);
+        let (seq, body) = queue.front().unwrap().unwrap();
+        assert_eq!((seq, body.as_slice()), (1, &b"2"[..]));
+        queue.remove(seq).unwrap();
+
+        let reopened = DiskQueue::open(temp_dir.path().join("q"), 2).unwrap();
+        assert_eq!(reopened.front().unwrap(), Some((2, b"3".to_vec())));
+        reopened.push(b"4").unwrap();
+        assert!(reopened.path(3).exists());
+
+        // A file deleted by hand is skipped
+        std::fs::remove_file(reopened.path(2)).unwrap();
+        assert_eq!(reopened.front().unwrap(), Some((3, b"4".to_vec())));
+    }
+
+    #[tokio::test]
+    async fn test_only_http_and_https_urls_are_accepted() {
+        let temp_dir = TempDir::new().unwrap();
+        let bus = EventBus::default();
+        for url in ["ftp://example.com/hook", "not a url"] {
+            assert!(Webhooks::spawn(&bus, &[url.to_string()], options(temp_dir.path())).is_err());
+        }
+        let urls = ["https://hooks.example.com/alert".to_string()];
+        assert!(Webhooks::spawn(&bus, &urls, options(temp_dir.path())).is_ok());
+    }
+}
@@ -20,6 +20,7 @@ async fn test_full_ipam_workflow() {
         sub_pool: None,
         options: None,
         v6: None,
+        address_space: None,
     };
     let pool_resp = plugin.request_pool(pool_req).await.unwrap();
     assert!(pool_resp.pool_id.starts_with("pool-"));
@@ -105,6 +106,7 @@ async fn test_persistence_across_restarts() {
             sub_pool: None,
             options: None,
             v6: None,
+            address_space: None,
         };
         let pool_resp = plugin.request_pool(pool_req).await.unwrap();
         pool_id = pool_resp.pool_id.clone();
@@ -237,6 +239,7 @@ async fn test_concurrent_address_allocation() {
         sub_pool: None,
         options: None,
         v6: None,
+        address_space: None,
     };
     let pool_resp = plugin.request_pool(pool_req).await.unwrap();
 
@@ -295,6 +298,7 @@ async fn test_ip_exhaustion() {
         sub_pool: None,
         options: None,
         v6: None,
+        address_space: None,
     };
     let pool_resp = plugin.request_pool(pool_req).await.unwrap();
 
@@ -354,6 +358,7 @@ async fn test_specific_ip_allocation() {
         sub_pool: None,
         options: None,
         v6: None,
+        address_space: None,
     };
     let pool_resp = plugin.request_pool(pool_req).await.unwrap();
 
@@ -411,6 +416,7 @@ async fn test_invalid_ip_address() {
         sub_pool: None,
         options: None,
         v6: None,
+        address_space: None,
     };
     let pool_resp = plugin.request_pool(pool_req).await.unwrap();
 
@@ -449,6 +455,7 @@ async fn test_release_nonexistent_address() {
         sub_pool: None,
         options: None,
         v6: None,
+        address_space: None,
     };
     let pool_resp = plugin.request_pool(pool_req).await.unwrap();
 
@@ -476,6 +483,7 @@ async fn test_multiple_pools() {
         sub_pool: None,
         options: None,
         v6: None,
+        address_space: None,
     };
     let pool1_resp = plugin.request_pool(pool1_req).await.unwrap();
 
@@ -485,6 +493,7 @@ async fn test_multiple_pools() {
         sub_pool: None,
         options: None,
         v6: None,
+        address_space: None,
     };
     let pool2_resp = plugin.request_pool(pool2_req).await.unwrap();
 
@@ -528,6 +537,7 @@ async fn test_default_subnet_usage() {
         sub_pool: None,
         options: None,
         v6: None,
+        address_space: None,
     };
     let pool_resp = plugin.request_pool(pool_req).await.unwrap();
     assert_eq!(pool_resp.pool, "10.99.0.0/16");